@@ -0,0 +1,81 @@
+//! Simulated instructions per second with and without the decode cache, on
+//! the part1 listings that spend their time in loops. Run with
+//! `cargo bench --bench decode_cache`.
+
+use std::hint::black_box;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use sim86_shared::simulator::Simulator;
+
+// The other listings finish in a few dozen instructions, which measures
+// setting up the simulator more than running it
+const LISTINGS: [&str; 2] = [
+    "listing_0054_draw_rectangle",
+    "listing_0055_challenge_rectangle",
+];
+
+// The worst case for the cache, a loop that rewrites its own code each time
+// round:
+//
+//     mov cx, 0xffff
+//     top: mov [8], cl   ; the immediate of the next mov
+//     mov ax, 0
+//     loop top
+const SELF_MODIFYING_LOOP: [u8; 12] = [
+    0xB9, 0xFF, 0xFF, 0x88, 0x0E, 0x08, 0x00, 0xB8, 0x00, 0x00, 0xE2, 0xF7,
+];
+
+// Long enough for the timing to settle
+const MIN_DURATION: Duration = Duration::from_millis(500);
+
+/// Runs `program` from address 0 until it leaves its own bytes, returning
+/// how many instructions that took.
+fn run(program: &[u8], cached: bool) -> u64 {
+    let mut simulator = Simulator::new();
+    simulator.cache_decoding(cached);
+    simulator.load(0, 0, program).unwrap();
+    let mut steps = 0;
+    while simulator.instruction_address() < program.len() {
+        let Ok(inst) = simulator.fetch_instruction() else {
+            break;
+        };
+        if simulator.execute_instruction(&inst).is_err() {
+            break;
+        }
+        steps += 1;
+    }
+    steps
+}
+
+fn instructions_per_second(program: &[u8], cached: bool) -> f64 {
+    let start = Instant::now();
+    let mut steps = 0;
+    while start.elapsed() < MIN_DURATION {
+        steps += black_box(run(black_box(program), cached));
+    }
+    steps as f64 / start.elapsed().as_secs_f64()
+}
+
+fn compare(name: &str, program: &[u8]) {
+    let uncached = instructions_per_second(program, false);
+    let cached = instructions_per_second(program, true);
+    println!(
+        "{}: {:.1}M instructions/s uncached, {:.1}M cached ({:.1}x)",
+        name,
+        uncached / 1e6,
+        cached / 1e6,
+        cached / uncached
+    );
+}
+
+fn main() {
+    let part1 = Path::new(env!("CARGO_MANIFEST_DIR")).join("../../../part1");
+    for name in LISTINGS {
+        match std::fs::read(part1.join(name)) {
+            Ok(program) => compare(name, &program),
+            Err(_) => println!("{}: not found, skipped", name),
+        }
+    }
+    compare("self-modifying loop", &SELF_MODIFYING_LOOP);
+}
@@ -22,6 +22,20 @@ fn main() {
         .file(lib_path.join("../sim86_lib.cpp"))
         .compile("sim86_shared");
 
+    // The reference executor isn't exported by the library, so a shim
+    // exposes it for lockstep runs against the Rust simulator. g++ only
+    // accepts register_state_8086's u8 and u16 members with -fpermissive,
+    // and the warnings are all in the reference code.
+    println!("cargo:rerun-if-changed=shim/sim86_reference.cpp");
+    cc::Build::new()
+        .flag("-std=c++17")
+        .flag_if_supported("-fpermissive")
+        .flag_if_supported("-w")
+        .cpp(true)
+        .include(lib_path.join(".."))
+        .file("shim/sim86_reference.cpp")
+        .compile("sim86_reference");
+
     let bindings = bindgen::Builder::default()
         .clang_arg("--std=c++17")
         .clang_arg("-x")
@@ -0,0 +1,18 @@
+//! Decodes arbitrary bytes at every offset. The decoder must never crash or,
+//! under a sanitizer, read outside the input.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use sim86_shared::*;
+
+fuzz_target!(|data: &[u8]| {
+    // A copy of exactly the input's length, so any overread is past the end
+    // of its own allocation
+    let data = data.to_vec();
+    for offset in 0..data.len() {
+        if let Ok(inst) = decode_8086_instruction(&data[offset..]) {
+            assert!(inst.Size > 0 && inst.Size <= 15, "size {}", inst.Size);
+        }
+    }
+});
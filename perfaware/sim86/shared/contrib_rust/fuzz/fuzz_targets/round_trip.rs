@@ -0,0 +1,68 @@
+//! Decodes an instruction from arbitrary bytes, encodes it both ways and
+//! decodes it again. The result must describe the same instruction.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use sim86_shared::encode::{encode_with, EncodingChoice};
+use sim86_shared::text::instruction_text;
+use sim86_shared::*;
+
+/// The instruction's text with immediates cut to the operation's width, so
+/// that 0xFFFF and a sign-extended -1 read the same.
+fn normalized_text(inst: &instruction) -> String {
+    let mut inst = *inst;
+    let mask = if (inst.Flags & instruction_flag_Inst_Wide) != 0 {
+        0xFFFF
+    } else {
+        0xFF
+    };
+    for operand in &mut inst.Operands {
+        if operand.Type == operand_type_Operand_Immediate {
+            let immediate = unsafe { &mut operand.__bindgen_anon_1.Immediate };
+            if (immediate.Flags & immediate_flag_Immediate_RelativeJumpDisplacement) == 0 {
+                immediate.Value &= mask;
+            }
+        }
+    }
+    instruction_text(&inst)
+}
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(inst) = decode_8086_instruction(data) else {
+        return;
+    };
+    let text = normalized_text(&inst);
+
+    for choice in [EncodingChoice::TableOrder, EncodingChoice::Shortest] {
+        let bytes = encode_with(&inst, choice)
+            .unwrap_or_else(|| panic!("{:?} can't encode {}", choice, text));
+        let mut again = decode_8086_instruction(&bytes)
+            .unwrap_or_else(|err| panic!("{} encodes as {:02x?}: {}", text, bytes, err));
+        assert_eq!(
+            bytes.len(),
+            again.Size as usize,
+            "{} as {:02x?}",
+            text,
+            bytes
+        );
+
+        // A jump's text counts from its start, and repeated prefixes or a
+        // different encoding can change its size
+        again.Size = inst.Size;
+        if [operation_type_Op_xchg, operation_type_Op_test].contains(&inst.Op)
+            && normalized_text(&again) != text
+        {
+            // Either order means the same, and the short forms only take
+            // the accumulator one way round
+            again.Operands.swap(0, 1);
+        }
+        assert_eq!(
+            text,
+            normalized_text(&again),
+            "{:?} as {:02x?}",
+            choice,
+            bytes
+        );
+    }
+});
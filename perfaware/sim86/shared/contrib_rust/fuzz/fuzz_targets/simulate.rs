@@ -0,0 +1,31 @@
+//! Runs arbitrary bytes as a program. The simulator must stop cleanly on
+//! anything it can't run rather than panic.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use sim86_shared::simulator::{Simulator, MEM_LEN};
+use sim86_shared::*;
+
+// Enough for tight loops to go round many times without the run dragging
+const MAX_STEPS: usize = 4096;
+
+fuzz_target!(|data: &[u8]| {
+    let mut simulator = Simulator::new();
+    let data = &data[..data.len().min(MEM_LEN)];
+    simulator.memory_mut()[..data.len()].copy_from_slice(data);
+
+    for _ in 0..MAX_STEPS {
+        let address = simulator.instruction_address();
+        if address >= data.len() {
+            break;
+        }
+        let Ok(inst) = simulator.fetch_instruction() else {
+            break;
+        };
+        // Unimplemented operations and hlt end the run
+        if simulator.execute_instruction(&inst).is_err() {
+            break;
+        }
+    }
+});
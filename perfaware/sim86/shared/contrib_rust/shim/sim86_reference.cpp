@@ -0,0 +1,99 @@
+/* Exposes the reference simulator's ExecInstruction, which the shared library
+   leaves out, so the Rust simulator can be checked against it in lockstep.
+   Everything included here is static, so this builds alongside
+   sim86_lib.cpp without clashing. */
+
+#include <setjmp.h>
+#include <stdio.h>
+#include <stdlib.h>
+
+/* NOTE: The reference's asserts check its own consistency, and lockstep
+   trusts it as the oracle, so they can't be compiled out the way
+   sim86_lib.cpp does. A failed one abandons the instruction and
+   Sim86Reference_ExecInstruction returns the assertion instead. */
+static thread_local jmp_buf AssertJump;
+static thread_local char AssertText[256];
+
+static void AssertFailed(char const *Expression, char const *File, int Line)
+{
+    snprintf(AssertText, sizeof(AssertText), "%s(%d): %s", File, Line, Expression);
+    longjmp(AssertJump, 1);
+}
+
+#define assert(Expression) ((Expression) ? (void)0 : AssertFailed(#Expression, __FILE__, __LINE__))
+
+#include "sim86.h"
+
+#include "sim86_instruction.h"
+#include "sim86_instruction_table.h"
+#include "sim86_memory.h"
+#include "sim86_decode.h"
+#include "sim86_execute.h"
+
+#include "sim86_instruction.cpp"
+#include "sim86_instruction_table.cpp"
+#include "sim86_memory.cpp"
+#include "sim86_execute.cpp"
+
+#define SIM86_REFERENCE_MEMORY_POW2 20
+
+struct sim86_reference
+{
+    register_state_8086 Registers;
+    u8 Memory[1 << SIM86_REFERENCE_MEMORY_POW2];
+};
+
+extern "C" sim86_reference *Sim86Reference_Create(void)
+{
+    sim86_reference *Result = (sim86_reference *)calloc(1, sizeof(sim86_reference));
+    return Result;
+}
+
+extern "C" void Sim86Reference_Destroy(sim86_reference *Reference)
+{
+    free(Reference);
+}
+
+extern "C" u16 *Sim86Reference_Registers(sim86_reference *Reference)
+{
+    return Reference->Registers.u16;
+}
+
+extern "C" u8 *Sim86Reference_Memory(sim86_reference *Reference)
+{
+    return Reference->Memory;
+}
+
+extern "C" u32 Sim86Reference_MemorySize(void)
+{
+    return 1 << SIM86_REFERENCE_MEMORY_POW2;
+}
+
+/* Returns null, or the assertion that failed, which stays valid until the
+   next call on the same thread. */
+extern "C" char const *Sim86Reference_ExecInstruction(sim86_reference *Reference, instruction *Instruction, exec_result *Dest)
+{
+    *Dest = {};
+    if(setjmp(AssertJump))
+    {
+        return AssertText;
+    }
+    
+    // NOTE: ExecInstruction reads far address operands through a pointer it
+    // never points at memory, so those can't be run at all.
+    for(u32 OpIndex = 0; OpIndex < ArrayCount(Instruction->Operands); ++OpIndex)
+    {
+        instruction_operand Operand = Instruction->Operands[OpIndex];
+        if((Operand.Type == Operand_Memory) && (Operand.Address.Flags & Address_ExplicitSegment))
+        {
+            Dest->Unimplemented = true;
+            return 0;
+        }
+    }
+    
+    // NOTE: The reference's main loop moves ip past the instruction before executing it.
+    Reference->Registers.ip += Instruction->Size;
+    segmented_access Memory = FixedMemoryPow2(SIM86_REFERENCE_MEMORY_POW2, Reference->Memory);
+    *Dest = ExecInstruction(Memory, &Reference->Registers, *Instruction);
+    return 0;
+}
@@ -0,0 +1,971 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::mem::MaybeUninit;
+
+use crate::debugger::parse_number;
+use crate::encode::{
+    candidates, choose, encoding_bits, encoding_table, is_segment, rm_code, segment_prefix_byte,
+    Candidate, EncodingChoice,
+};
+use crate::*;
+
+// Assembles the subset of NASM syntax the course listings are written in:
+//
+//     bits 16
+//     mov bp, 64*4
+//     y_loop_start:
+//         mov word [bp + 0], cx   ; comment
+//         jnz y_loop_start
+//
+// Lines hold an optional `label:` and an instruction or a `db`/`dw` list.
+// Labels starting with a dot are local to the label before them. Operands
+// are registers, memory as `[bx + si + DISP]` with an optional `es:` style
+// segment and `byte`/`word` qualifier, immediates, and `SEG:OFF` far
+// addresses. Numbers are read by `debugger::parse_number`, so they mean the
+// same as in the debugger: decimal, hex as 0x10, 10h or $10, or binary as 0b10.
+// Expressions take + - * / % << >> & | ^ ~ with `$` as the line's address.
+//
+// Each instruction gets the shortest encoding the `encode` module finds for
+// it, breaking ties by table order. That is also how NASM chooses, so the
+// checked-in binaries come out byte for byte.
+
+// Jumps only ever grow from short to near, so sizes settle within a few passes.
+const MAX_PASSES: usize = 16;
+
+const ALIASES: [(&str, &str); 18] = [
+    ("jz", "je"),
+    ("jnz", "jne"),
+    ("jnge", "jl"),
+    ("jge", "jnl"),
+    ("jng", "jle"),
+    ("jnle", "jg"),
+    ("jc", "jb"),
+    ("jnae", "jb"),
+    ("jnc", "jnb"),
+    ("jae", "jnb"),
+    ("jna", "jbe"),
+    ("jnbe", "ja"),
+    ("jpe", "jp"),
+    ("jpo", "jnp"),
+    ("loope", "loopz"),
+    ("loopne", "loopnz"),
+    ("sal", "shl"),
+    ("retn", "ret"),
+];
+
+const STRING_OPERATIONS: [&str; 5] = ["movs", "cmps", "scas", "lods", "stos"];
+
+// Longer symbols first, so "<<" isn't read as "<"
+const SYMBOLS: [&str; 17] = [
+    "<<", ">>", "+", "-", "*", "/", "%", "&", "|", "^", "~", "(", ")", "[", "]", ":", ",",
+];
+
+// Binary operators from loosest to tightest binding, as in NASM.
+const PRECEDENCE: [&[&str]; 6] = [
+    &["|"],
+    &["^"],
+    &["&"],
+    &["<<", ">>"],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssembleError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for AssembleError {}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(i64),
+    Name(String),
+    Symbol(&'static str),
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Number(i64),
+    Label(String),
+    /// `$`, the address of the line being assembled.
+    Here,
+    Unary(&'static str, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone)]
+enum Operand {
+    Register(register_access),
+    Memory {
+        segment: Option<register_index>,
+        terms: Vec<register_index>,
+        displacement: Expr,
+    },
+    Immediate(Expr),
+    Far {
+        segment: Expr,
+        offset: Expr,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum JumpSize {
+    Short,
+    Near,
+}
+
+#[derive(Debug, Clone)]
+struct InstructionSyntax {
+    /// Lock and repeat prefix bytes, in source order.
+    prefixes: Vec<u8>,
+    op: operation_type,
+    /// Set by a `byte` or `word` qualifier, or a string mnemonic like movsb.
+    wide: Option<bool>,
+    far: bool,
+    jump: Option<JumpSize>,
+    operands: Vec<Operand>,
+}
+
+#[derive(Debug, Clone)]
+enum Statement {
+    Instruction(InstructionSyntax),
+    Data { wide: bool, values: Vec<Expr> },
+}
+
+struct Line {
+    number: usize,
+    labels: Vec<String>,
+    statement: Option<Statement>,
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "_.$?@".contains(c)
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = text.trim_start();
+    while let Some(first) = rest.chars().next() {
+        let length = if is_word_char(first) {
+            let length = rest.find(|c| !is_word_char(c)).unwrap_or(rest.len());
+            let word = &rest[..length];
+            if first.is_ascii_digit()
+                || (first == '$' && word[1..].starts_with(|c: char| c.is_ascii_digit()))
+            {
+                let value = parse_number(word).ok_or_else(|| format!("bad number `{}`", word))?;
+                tokens.push(Token::Number(value));
+            } else {
+                tokens.push(Token::Name(word.to_string()));
+            }
+            length
+        } else {
+            let symbol = SYMBOLS
+                .iter()
+                .find(|symbol| rest.starts_with(*symbol))
+                .ok_or_else(|| format!("unexpected `{}`", first))?;
+            tokens.push(Token::Symbol(symbol));
+            symbol.len()
+        };
+        rest = rest[length..].trim_start();
+    }
+
+    Ok(tokens)
+}
+
+/// A general or segment register by its (case-insensitive) name.
+fn register_from_name(name: &str) -> Option<register_access> {
+    let name = name.to_ascii_lowercase();
+    let wide = (Register_a..=Register_ds).map(|index| register_access {
+        Index: index,
+        Offset: 0,
+        Count: 2,
+    });
+    let bytes = (Register_a..=Register_d).flat_map(|index| {
+        [0, 1].map(|offset| register_access {
+            Index: index,
+            Offset: offset,
+            Count: 1,
+        })
+    });
+    wide.chain(bytes)
+        .find(|access| register_name_from_operand(access) == name)
+}
+
+fn operation_from_mnemonic(name: &str) -> Option<(operation_type, Option<bool>)> {
+    let mut wide = None;
+    let mut name = ALIASES
+        .iter()
+        .find(|(alias, _)| *alias == name)
+        .map_or(name, |(_, canonical)| *canonical);
+    if let Some((base, suffix)) = name.split_at_checked(name.len().saturating_sub(1)) {
+        if STRING_OPERATIONS.contains(&base) {
+            wide = Some(suffix == "w");
+            name = if suffix == "b" || suffix == "w" {
+                base
+            } else {
+                return None;
+            };
+        }
+    }
+
+    // Prefixes are only accepted in front of an instruction
+    (1..operation_type_Op_Count)
+        .filter(|op| {
+            ![
+                operation_type_Op_lock,
+                operation_type_Op_rep,
+                operation_type_Op_segment,
+            ]
+            .contains(op)
+        })
+        .find(|op| mnemonic_from_operation_type(*op) == name)
+        .map(|op| (op, wide))
+}
+
+fn prefix_byte(name: &str) -> Option<u8> {
+    match name {
+        "lock" => Some(0xF0),
+        "rep" | "repe" | "repz" => Some(0xF3),
+        "repne" | "repnz" => Some(0xF2),
+        _ => None,
+    }
+}
+
+struct Parser<'a> {
+    tokens: &'a [Token],
+    at: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.at)
+    }
+
+    fn eat(&mut self, symbol: &str) -> bool {
+        let found = matches!(self.peek(), Some(Token::Symbol(s)) if *s == symbol);
+        if found {
+            self.at += 1;
+        }
+        found
+    }
+
+    fn expression(tokens: &[Token]) -> Result<Expr, String> {
+        let mut parser = Parser { tokens, at: 0 };
+        let expr = parser.binary(0)?;
+        match parser.peek() {
+            None => Ok(expr),
+            Some(token) => Err(format!("unexpected {:?} in expression", token)),
+        }
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Expr, String> {
+        let Some(operators) = PRECEDENCE.get(level) else {
+            return self.unary();
+        };
+
+        let mut left = self.binary(level + 1)?;
+        while let Some(Token::Symbol(symbol)) = self.peek() {
+            let Some(operator) = operators.iter().find(|operator| *operator == symbol) else {
+                break;
+            };
+            self.at += 1;
+            let right = self.binary(level + 1)?;
+            left = Expr::Binary(operator, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        if self.eat("+") {
+            return self.unary();
+        }
+        for operator in ["-", "~"] {
+            if self.eat(operator) {
+                return Ok(Expr::Unary(operator, Box::new(self.unary()?)));
+            }
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        let token = self.peek().cloned().ok_or("expression expected")?;
+        self.at += 1;
+        match token {
+            Token::Number(value) => Ok(Expr::Number(value)),
+            Token::Symbol("(") => {
+                let inner = self.binary(0)?;
+                if !self.eat(")") {
+                    return Err("expected `)`".to_string());
+                }
+                Ok(inner)
+            }
+            Token::Name(name) if name == "$" => Ok(Expr::Here),
+            // Without an org, the start of the section is address 0
+            Token::Name(name) if name == "$$" => Ok(Expr::Number(0)),
+            Token::Name(name) if register_from_name(&name).is_some() => {
+                Err(format!("register {} in an expression", name))
+            }
+            Token::Name(name) => Ok(Expr::Label(name)),
+            Token::Symbol(symbol) => Err(format!("unexpected `{}`", symbol)),
+        }
+    }
+}
+
+/// What `[...]` holds: the base and index registers, with the rest of the
+/// sum as the displacement.
+fn parse_memory(inner: &[Token]) -> Result<(Vec<register_index>, Expr), String> {
+    let mut terms = Vec::new();
+    let mut displacement = inner.to_vec();
+    for (index, token) in inner.iter().enumerate() {
+        let Token::Name(name) = token else {
+            continue;
+        };
+        let Some(register) = register_from_name(name) else {
+            continue;
+        };
+
+        let before = index.checked_sub(1).map(|before| &inner[before]);
+        let after = inner.get(index + 1);
+        let added = matches!(before, None | Some(Token::Symbol("+")))
+            && matches!(after, None | Some(Token::Symbol("+" | "-")));
+        if !added || register.Count != 2 {
+            return Err(format!("invalid effective address at {}", name));
+        }
+        terms.push(register.Index);
+        displacement[index] = Token::Number(0);
+    }
+
+    if !terms.is_empty() && rm_code(&terms).is_none() {
+        return Err("invalid effective address".to_string());
+    }
+    Ok((terms, Parser::expression(&displacement)?))
+}
+
+fn parse_operand(tokens: &[Token], syntax: &mut InstructionSyntax) -> Result<Operand, String> {
+    let mut tokens = tokens;
+    while let Some(Token::Name(name)) = tokens.first() {
+        match name.to_ascii_lowercase().as_str() {
+            "byte" => syntax.wide = Some(false),
+            "word" => syntax.wide = Some(true),
+            "far" => syntax.far = true,
+            "short" => syntax.jump = Some(JumpSize::Short),
+            "near" => syntax.jump = Some(JumpSize::Near),
+            _ => break,
+        }
+        tokens = &tokens[1..];
+    }
+
+    if let [Token::Name(name)] = tokens {
+        if let Some(register) = register_from_name(name) {
+            return Ok(Operand::Register(register));
+        }
+    }
+
+    if let Some(open) = tokens.iter().position(|token| *token == Token::Symbol("[")) {
+        let segment_of = |tokens: &[Token]| match tokens {
+            [Token::Name(name), Token::Symbol(":")] => register_from_name(name)
+                .filter(is_segment)
+                .map(|register| register.Index)
+                .ok_or_else(|| format!("{} is not a segment register", name)),
+            _ => Err("expected a memory operand".to_string()),
+        };
+
+        let mut segment = None;
+        if open > 0 {
+            segment = Some(segment_of(&tokens[..open])?);
+        }
+        let Some((Token::Symbol("]"), inner)) = tokens[open + 1..].split_last() else {
+            return Err("expected `]`".to_string());
+        };
+        let mut inner = inner;
+        if inner.get(1) == Some(&Token::Symbol(":")) {
+            if segment.is_some() {
+                return Err("two segment overrides".to_string());
+            }
+            segment = Some(segment_of(&inner[..2])?);
+            inner = &inner[2..];
+        }
+
+        let (terms, displacement) = parse_memory(inner)?;
+        return Ok(Operand::Memory {
+            segment,
+            terms,
+            displacement,
+        });
+    }
+
+    if let Some(colon) = tokens.iter().position(|token| *token == Token::Symbol(":")) {
+        return Ok(Operand::Far {
+            segment: Parser::expression(&tokens[..colon])?,
+            offset: Parser::expression(&tokens[colon + 1..])?,
+        });
+    }
+
+    Ok(Operand::Immediate(Parser::expression(tokens)?))
+}
+
+fn parse_statement(tokens: &[Token]) -> Result<Option<Statement>, String> {
+    let mut prefixes = Vec::new();
+    let mut at = 0;
+    let mnemonic = loop {
+        let Some(Token::Name(name)) = tokens.get(at) else {
+            return Err("instruction expected".to_string());
+        };
+        at += 1;
+        let name = name.to_ascii_lowercase();
+        match prefix_byte(&name) {
+            Some(prefix) if at < tokens.len() => prefixes.push(prefix),
+            _ => break name,
+        }
+    };
+
+    let mut operand_tokens: Vec<&[Token]> = tokens[at..]
+        .split(|token| *token == Token::Symbol(","))
+        .collect();
+    if operand_tokens == [&[] as &[Token]] {
+        operand_tokens.clear();
+    }
+
+    match mnemonic.as_str() {
+        "bits" => {
+            return match operand_tokens[..] {
+                [[Token::Number(16)]] => Ok(None),
+                _ => Err("only bits 16 is supported".to_string()),
+            }
+        }
+        "db" | "dw" => {
+            let values = operand_tokens
+                .iter()
+                .map(|tokens| Parser::expression(tokens))
+                .collect::<Result<Vec<Expr>, String>>()?;
+            return Ok(Some(Statement::Data {
+                wide: mnemonic == "dw",
+                values,
+            }));
+        }
+        _ => {}
+    }
+
+    // The 8086 has no separate nop, it is the one-byte xchg ax, ax
+    if mnemonic == "nop" && operand_tokens.is_empty() {
+        let ax = Token::Name("ax".to_string());
+        return parse_statement(&[
+            Token::Name("xchg".to_string()),
+            ax.clone(),
+            Token::Symbol(","),
+            ax,
+        ]);
+    }
+
+    let Some((op, wide)) = operation_from_mnemonic(&mnemonic) else {
+        return Err(format!("unknown instruction {}", mnemonic));
+    };
+    let mut syntax = InstructionSyntax {
+        prefixes,
+        op,
+        wide,
+        far: op == operation_type_Op_retf,
+        jump: None,
+        operands: Vec::new(),
+    };
+    for tokens in operand_tokens {
+        if tokens.is_empty() {
+            return Err("operand expected".to_string());
+        }
+        let operand = parse_operand(tokens, &mut syntax)?;
+        syntax.far |= matches!(operand, Operand::Far { .. });
+        syntax.operands.push(operand);
+    }
+    if syntax.operands.len() > 2 {
+        return Err("too many operands".to_string());
+    }
+
+    Ok(Some(Statement::Instruction(syntax)))
+}
+
+fn parse_source(source: &str) -> Result<Vec<Line>, AssembleError> {
+    let mut lines = Vec::new();
+    let mut scope = String::new();
+    for (index, text) in source.lines().enumerate() {
+        let number = index + 1;
+        let error = |message| AssembleError {
+            line: number,
+            message,
+        };
+
+        let code = text.split(';').next().unwrap_or_default();
+        let mut tokens = tokenize(code).map_err(error)?;
+        let mut labels = Vec::new();
+        while let [Token::Name(name), Token::Symbol(":"), ..] = &tokens[..] {
+            if register_from_name(name).is_some() {
+                break;
+            }
+            // Local labels belong to the last ordinary label
+            if name.starts_with('.') {
+                labels.push(format!("{}{}", scope, name));
+            } else {
+                scope = name.clone();
+                labels.push(name.clone());
+            }
+            tokens.drain(..2);
+        }
+        for token in &mut tokens {
+            if let Token::Name(name) = token {
+                if name.starts_with('.') {
+                    *name = format!("{}{}", scope, name);
+                }
+            }
+        }
+
+        let statement = if tokens.is_empty() {
+            None
+        } else {
+            parse_statement(&tokens).map_err(error)?
+        };
+        lines.push(Line {
+            number,
+            labels,
+            statement,
+        });
+    }
+
+    Ok(lines)
+}
+
+struct Scope<'a> {
+    labels: &'a HashMap<String, i64>,
+    here: i64,
+    /// Whether a missing label is an error, or just not known yet.
+    strict: bool,
+}
+
+impl Scope<'_> {
+    fn evaluate(&self, expr: &Expr) -> Result<i64, String> {
+        Ok(match expr {
+            Expr::Number(value) => *value,
+            Expr::Here => self.here,
+            Expr::Label(name) => match self.labels.get(name) {
+                Some(value) => *value,
+                None if self.strict => return Err(format!("undefined symbol {}", name)),
+                None => 0,
+            },
+            Expr::Unary(operator, inner) => {
+                let inner = self.evaluate(inner)?;
+                if *operator == "-" {
+                    inner.wrapping_neg()
+                } else {
+                    !inner
+                }
+            }
+            Expr::Binary(operator, left, right) => {
+                let (left, right) = (self.evaluate(left)?, self.evaluate(right)?);
+                match *operator {
+                    "|" => left | right,
+                    "^" => left ^ right,
+                    "&" => left & right,
+                    "<<" => left.wrapping_shl(right as u32),
+                    ">>" => left.wrapping_shr(right as u32),
+                    "+" => left.wrapping_add(right),
+                    "-" => left.wrapping_sub(right),
+                    "*" => left.wrapping_mul(right),
+                    _ if right == 0 => return Err("division by zero".to_string()),
+                    "/" => left.wrapping_div(right),
+                    _ => left.wrapping_rem(right),
+                }
+            }
+        })
+    }
+}
+
+fn empty_instruction() -> instruction {
+    // Every field is plain data, for which all zeroes is the empty value
+    unsafe { MaybeUninit::zeroed().assume_init() }
+}
+
+fn register_operand(access: register_access) -> instruction_operand {
+    instruction_operand {
+        Type: operand_type_Operand_Register,
+        __bindgen_anon_1: instruction_operand__bindgen_ty_1 { Register: access },
+    }
+}
+
+fn immediate_operand(value: i64, flags: u32) -> instruction_operand {
+    instruction_operand {
+        Type: operand_type_Operand_Immediate,
+        __bindgen_anon_1: instruction_operand__bindgen_ty_1 {
+            Immediate: immediate {
+                Value: value as i32,
+                Flags: flags,
+            },
+        },
+    }
+}
+
+fn memory_operand(address: effective_address_expression) -> instruction_operand {
+    instruction_operand {
+        Type: operand_type_Operand_Memory,
+        __bindgen_anon_1: instruction_operand__bindgen_ty_1 { Address: address },
+    }
+}
+
+/// Whether the operation has a form that takes a relative displacement, in
+/// which case a plain number or label is where it goes rather than its value.
+fn is_relative(op: operation_type) -> bool {
+    encoding_table().iter().any(|encoding| {
+        encoding.Op == op
+            && encoding_bits(encoding)
+                .any(|bits| bits.Usage == instruction_bits_usage_Bits_RelJMPDisp)
+    })
+}
+
+impl InstructionSyntax {
+    /// The instruction as the decoder would describe it, with `relative` as
+    /// the displacement of a jump.
+    fn instruction(&self, scope: &Scope, relative: Option<i64>) -> Result<instruction, String> {
+        let mut inst = empty_instruction();
+        inst.Op = self.op;
+        if self.wide == Some(true) {
+            inst.Flags |= instruction_flag_Inst_Wide;
+        }
+        if self.far {
+            inst.Flags |= instruction_flag_Inst_Far;
+        }
+
+        for (slot, operand) in self.operands.iter().enumerate() {
+            inst.Operands[slot] = match operand {
+                Operand::Register(access) => register_operand(*access),
+                Operand::Immediate(expr) => match relative {
+                    Some(displacement) => immediate_operand(
+                        displacement,
+                        immediate_flag_Immediate_RelativeJumpDisplacement,
+                    ),
+                    None => immediate_operand(scope.evaluate(expr)?, 0),
+                },
+                Operand::Memory {
+                    terms,
+                    displacement,
+                    ..
+                } => {
+                    let mut address: effective_address_expression =
+                        unsafe { MaybeUninit::zeroed().assume_init() };
+                    for (term, index) in address.Terms.iter_mut().zip(terms) {
+                        term.Register = register_access {
+                            Index: *index,
+                            Offset: 0,
+                            Count: 2,
+                        };
+                        term.Scale = 1;
+                    }
+                    address.Displacement = scope.evaluate(displacement)? as i32;
+                    memory_operand(address)
+                }
+                Operand::Far { segment, offset } => {
+                    let mut address: effective_address_expression =
+                        unsafe { MaybeUninit::zeroed().assume_init() };
+                    address.ExplicitSegment = scope.evaluate(segment)? as u32;
+                    address.Displacement = scope.evaluate(offset)? as i32;
+                    address.Flags = effective_address_flag_Address_ExplicitSegment;
+                    memory_operand(address)
+                }
+            };
+        }
+
+        Ok(inst)
+    }
+
+    fn prefix_bytes(&self) -> Vec<u8> {
+        let mut prefixes = self.prefixes.clone();
+        for operand in &self.operands {
+            if let Operand::Memory {
+                segment: Some(segment),
+                ..
+            } = operand
+            {
+                prefixes.push(segment_prefix_byte(*segment));
+            }
+        }
+        prefixes
+    }
+
+    /// Machine code for the instruction at `address`. A jump is made at least
+    /// `min_size` bytes long, so sizes only grow from one pass to the next.
+    fn assemble(&self, scope: &Scope, min_size: usize) -> Result<Vec<u8>, String> {
+        let mut bytes = self.prefix_bytes();
+        let target = match self.operands[..] {
+            [Operand::Immediate(ref expr)] if is_relative(self.op) => Some(scope.evaluate(expr)?),
+            _ => None,
+        };
+
+        let Some(target) = target else {
+            let inst = self.instruction(scope, None)?;
+            let encoding = best_encoding(&inst, self.wide.is_some(), |_| true)?
+                .ok_or_else(|| self.invalid())?;
+            bytes.extend(encoding);
+            return Ok(bytes);
+        };
+
+        let sizes: &[usize] = match self.jump {
+            Some(JumpSize::Short) => &[2],
+            Some(JumpSize::Near) => &[3],
+            None => &[2, 3],
+        };
+        let prefix_size = bytes.len();
+        for size in sizes.iter().filter(|size| prefix_size + *size >= min_size) {
+            let end = scope.here + (prefix_size + size) as i64;
+            let inst = self.instruction(scope, Some(target - end))?;
+            if let Some(encoding) = best_encoding(&inst, true, |bytes| bytes.len() == *size)? {
+                bytes.extend(encoding);
+                return Ok(bytes);
+            }
+        }
+        let encodable = best_encoding(&self.instruction(scope, Some(0))?, true, |_| true)?;
+        if encodable.is_some() {
+            Err("jump out of range".to_string())
+        } else {
+            Err(self.invalid())
+        }
+    }
+
+    fn invalid(&self) -> String {
+        format!(
+            "invalid combination of operands for {}",
+            mnemonic_from_operation_type(self.op)
+        )
+    }
+}
+
+impl Statement {
+    fn assemble(&self, scope: &Scope, min_size: usize) -> Result<Vec<u8>, String> {
+        match self {
+            Statement::Instruction(syntax) => syntax.assemble(scope, min_size),
+            Statement::Data { wide, values } => {
+                let mut bytes = Vec::new();
+                for value in values {
+                    let value = scope.evaluate(value)?;
+                    if *wide {
+                        bytes.extend((value as u16).to_le_bytes());
+                    } else {
+                        bytes.push(value as u8);
+                    }
+                }
+                Ok(bytes)
+            }
+        }
+    }
+}
+
+/// The shortest encoding of `target` that `accept` allows, preferring operands
+/// in the order given and then the first in table order. Without
+/// `check_wide`, the width is left to the operands, and it is an error for
+/// both widths to fit.
+fn best_encoding(
+    target: &instruction,
+    check_wide: bool,
+    accept: impl Fn(&[u8]) -> bool,
+) -> Result<Option<Vec<u8>>, String> {
+    let candidates: Vec<Candidate> = candidates(target, check_wide)
+        .into_iter()
+        .filter(|candidate| accept(&candidate.bytes))
+        .collect();
+    let mut widths = [false; 2];
+    for candidate in &candidates {
+        widths[candidate.wide as usize] = true;
+    }
+
+    if !check_wide && widths == [true, true] {
+        return Err("operation size not specified".to_string());
+    }
+    Ok(choose(candidates, EncodingChoice::Shortest).map(|candidate| candidate.bytes))
+}
+
+/// Encodes each statement at the addresses the previous pass's sizes give,
+/// updating the sizes. Errors are only reported when `strict`, since until
+/// the sizes settle a label may just not be placed yet.
+fn run_pass(
+    lines: &[Line],
+    sizes: &mut [usize],
+    grow_only: bool,
+    strict: bool,
+) -> Result<(Vec<u8>, bool), AssembleError> {
+    let mut labels = HashMap::new();
+    let mut address = 0;
+    for (line, size) in lines.iter().zip(sizes.iter()) {
+        for label in &line.labels {
+            labels.insert(label.clone(), address);
+        }
+        address += *size as i64;
+    }
+
+    let mut output = Vec::new();
+    let mut changed = false;
+    for (line, size) in lines.iter().zip(sizes.iter_mut()) {
+        let Some(statement) = &line.statement else {
+            continue;
+        };
+        let scope = Scope {
+            labels: &labels,
+            here: output.len() as i64,
+            strict,
+        };
+        let min_size = if grow_only { *size } else { 0 };
+        match statement.assemble(&scope, min_size) {
+            Ok(bytes) => {
+                changed |= bytes.len() != *size;
+                *size = bytes.len();
+                output.extend(bytes);
+            }
+            Err(message) if strict => {
+                return Err(AssembleError {
+                    line: line.number,
+                    message,
+                })
+            }
+            Err(_) => output.resize(output.len() + *size, 0),
+        }
+    }
+
+    Ok((output, changed))
+}
+
+/// Assembles NASM source for a flat 16-bit binary starting at address 0.
+pub fn assemble(source: &str) -> Result<Vec<u8>, AssembleError> {
+    let lines = parse_source(source)?;
+    let mut defined = HashMap::new();
+    for line in &lines {
+        for label in &line.labels {
+            if defined.insert(label.clone(), line.number).is_some() {
+                return Err(AssembleError {
+                    line: line.number,
+                    message: format!("label {} redefined", label),
+                });
+            }
+        }
+    }
+
+    let mut sizes = vec![0; lines.len()];
+    for pass in 0..MAX_PASSES {
+        let (_, changed) = run_pass(&lines, &mut sizes, pass > 0, false)?;
+        if !changed {
+            break;
+        }
+    }
+
+    let (output, changed) = run_pass(&lines, &mut sizes, true, true)?;
+    if changed {
+        return Err(AssembleError {
+            line: 0,
+            message: "instruction sizes never settled".to_string(),
+        });
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disasm::Disassembly;
+
+    #[test]
+    fn resolves_labels_and_grows_jumps() {
+        let source = "
+            bits 16
+            start:
+                mov cx, 3        ; counter
+            .again:
+                dec cx
+                jnz .again
+                jmp done
+                dw 0x1234
+                db 1, 2, 250 dup_free_space
+            done:
+                mov ax, [table + 2*2]
+            table:
+        ";
+        let error = assemble(source).unwrap_err();
+        assert_eq!(10, error.line);
+
+        let source = source.replace("dup_free_space", "");
+        let bytes = assemble(&source).unwrap();
+        assert_eq!(
+            vec![
+                0xB9, 0x03, 0x00, 0x49, 0x75, 0xFD, 0xEB, 0x05, 0x34, 0x12, 0x01, 0x02, 0xFA, 0xA1,
+                0x14, 0x00,
+            ],
+            bytes
+        );
+
+        // Too far for a short jump
+        let far = format!("jmp over\n{}over: hlt\n", "db 0\n".repeat(200));
+        let bytes = assemble(&far).unwrap();
+        assert_eq!([0xE9, 0xC8, 0x00], bytes[..3]);
+        assert_eq!(Some(&0xF4), bytes.last());
+    }
+
+    #[test]
+    fn reports_errors_with_lines() {
+        let error = |source: &str| assemble(source).unwrap_err().to_string();
+        assert_eq!(
+            "line 2: operation size not specified",
+            error("bits 16\ninc [bx]")
+        );
+        assert_eq!("line 1: undefined symbol nowhere", error("jmp nowhere"));
+        assert_eq!("line 1: too many operands", error("mov cs, [bx], 1"));
+        assert_eq!(
+            "line 1: invalid combination of operands for mov",
+            error("mov 1, ax")
+        );
+        assert_eq!(
+            "line 1: invalid combination of operands for shl",
+            error("shl ax, 2")
+        );
+        assert_eq!(
+            "line 1: invalid effective address",
+            error("mov ax, [bx + bp]")
+        );
+        assert_eq!("line 2: label a redefined", error("a: nop\na: nop"));
+    }
+
+    #[test]
+    fn reads_numbers_like_the_debugger() {
+        let expected = assemble("mov ax, 0x10").unwrap();
+        for source in [
+            "mov ax, 10h",
+            "mov ax, $10",
+            "mov ax, 16",
+            "mov ax, 0b10000",
+        ] {
+            assert_eq!(expected, assemble(source).unwrap(), "{}", source);
+        }
+        assert_eq!(vec![0xB4, 0x01], assemble("mov ah, 1").unwrap());
+    }
+
+    #[test]
+    fn reassembles_labelled_disassembly() {
+        let program = assemble(
+            "
+            mov cx, 4
+            top: lock xchg [bp + di - 3], al
+            rep movsw
+            mov dx, es:[bx]
+            loop top
+            call 2000
+            jmp 0:1234
+            db 0xff, 0xfe
+            ",
+        )
+        .unwrap();
+        let mut disassembly = Disassembly::new(&program, 0, &[0]);
+        disassembly.synthesize_labels();
+        let text = disassembly.text();
+        assert!(text.contains("label_0003:\nlock xchg"));
+        assert_eq!(program, assemble(&text).unwrap());
+    }
+}
@@ -0,0 +1,419 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+use crate::cycles::{
+    estimate_instruction_clocks, expected_clocks_from, ClockInterval, TimingState,
+};
+use crate::text::instruction_text;
+use crate::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EdgeKind {
+    /// Execution runs on into the next block, including after a call returns
+    /// and when a conditional branch isn't taken.
+    Fallthrough,
+    /// A jump, loop or taken conditional branch.
+    Branch,
+    Call,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Edge {
+    pub target: usize,
+    pub kind: EdgeKind,
+}
+
+/// A straight run of instructions that is only ever entered at the top.
+#[derive(Clone)]
+pub struct BasicBlock {
+    pub start: usize,
+    /// Address just past the last instruction.
+    pub end: usize,
+    pub instructions: Vec<(usize, instruction)>,
+    pub successors: Vec<Edge>,
+}
+
+/// A loop found from a back edge: a branch to a block that dominates it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NaturalLoop {
+    pub header: usize,
+    /// Blocks that branch back to the header.
+    pub latches: BTreeSet<usize>,
+    /// Start addresses of every block in the loop, header included.
+    pub blocks: BTreeSet<usize>,
+}
+
+/// The basic blocks reachable from a program's entry points, found by
+/// decoding along every jump, loop and call with a known target. Indirect
+/// jumps and calls can't be followed statically, so code only they reach is
+/// left out.
+#[derive(Clone)]
+pub struct ControlFlowGraph {
+    entries: Vec<usize>,
+    blocks: BTreeMap<usize, BasicBlock>,
+}
+
+enum Flow {
+    /// Anything that carries on to the next instruction.
+    Next,
+    Conditional(usize),
+    Jump(Option<usize>),
+    Call(Option<usize>),
+    Stop,
+}
+
+fn flow(address: usize, inst: &instruction) -> Flow {
+    let target = relative_jump_target(address, inst);
+    match inst.Op {
+        operation_type_Op_jmp => Flow::Jump(target),
+        operation_type_Op_call => Flow::Call(target),
+        operation_type_Op_ret
+        | operation_type_Op_retf
+        | operation_type_Op_iret
+        | operation_type_Op_hlt => Flow::Stop,
+        _ => match target {
+            Some(target) => Flow::Conditional(target),
+            None => Flow::Next,
+        },
+    }
+}
+
+impl ControlFlowGraph {
+    /// Analyses `program` as loaded at absolute address `base`, entering at
+    /// its first byte.
+    pub fn build(program: &[u8], base: usize) -> Self {
+        Self::build_with_entries(program, base, &[base])
+    }
+
+    /// Like `build`, but starting from each of `entries`, which are absolute
+    /// addresses.
+    pub fn build_with_entries(program: &[u8], base: usize, entries: &[usize]) -> Self {
+        let decode = |address: usize| {
+            let offset = address.checked_sub(base)?;
+            decode_8086_instruction(program.get(offset..)?).ok()
+        };
+
+        // Decode everything reachable, noting where blocks have to start
+        let mut instructions = BTreeMap::new();
+        let mut leaders: BTreeSet<usize> = entries.iter().copied().collect();
+        let mut pending = entries.to_vec();
+        while let Some(mut address) = pending.pop() {
+            while !instructions.contains_key(&address) {
+                let Some(inst) = decode(address) else {
+                    break;
+                };
+                instructions.insert(address, inst);
+                let next = address + inst.Size as usize;
+
+                let (target, falls_through) = match flow(address, &inst) {
+                    Flow::Next => {
+                        address = next;
+                        continue;
+                    }
+                    Flow::Conditional(target) => (Some(target), true),
+                    Flow::Call(target) => (target, true),
+                    Flow::Jump(target) => (target, false),
+                    Flow::Stop => (None, false),
+                };
+                if let Some(target) = target {
+                    leaders.insert(target);
+                    pending.push(target);
+                }
+                if !falls_through {
+                    break;
+                }
+                leaders.insert(next);
+                address = next;
+            }
+        }
+
+        let mut blocks = BTreeMap::new();
+        for start in leaders.iter().copied() {
+            if !instructions.contains_key(&start) {
+                continue;
+            }
+
+            let mut block = BasicBlock {
+                start,
+                end: start,
+                instructions: Vec::new(),
+                successors: Vec::new(),
+            };
+            while let Some(inst) = instructions.get(&block.end) {
+                let address = block.end;
+                block.instructions.push((address, *inst));
+                block.end += inst.Size as usize;
+                if !matches!(flow(address, inst), Flow::Next) || leaders.contains(&block.end) {
+                    break;
+                }
+            }
+
+            let (last_address, last) = *block.instructions.last().unwrap();
+            let mut add = |target: Option<usize>, kind| {
+                if let Some(target) = target.filter(|target| instructions.contains_key(target)) {
+                    block.successors.push(Edge { target, kind });
+                }
+            };
+            match flow(last_address, &last) {
+                Flow::Next => add(Some(block.end), EdgeKind::Fallthrough),
+                Flow::Conditional(target) => {
+                    add(Some(target), EdgeKind::Branch);
+                    add(Some(block.end), EdgeKind::Fallthrough);
+                }
+                Flow::Jump(target) => add(target, EdgeKind::Branch),
+                Flow::Call(target) => {
+                    add(target, EdgeKind::Call);
+                    add(Some(block.end), EdgeKind::Fallthrough);
+                }
+                Flow::Stop => {}
+            }
+            blocks.insert(start, block);
+        }
+
+        Self {
+            entries: entries.to_vec(),
+            blocks,
+        }
+    }
+
+    pub fn entries(&self) -> &[usize] {
+        &self.entries
+    }
+
+    pub fn blocks(&self) -> &BTreeMap<usize, BasicBlock> {
+        &self.blocks
+    }
+
+    /// Estimated clocks for running a block once through, under `timing`.
+    pub fn block_clocks(&self, block: &BasicBlock, timing: &TimingState) -> ClockInterval {
+        let mut total = ClockInterval::default();
+        for (_, inst) in &block.instructions {
+            let estimate = estimate_instruction_clocks(timing, inst);
+            let clocks = expected_clocks_from(timing, inst, &estimate);
+            total.min += clocks.min;
+            total.max += clocks.max;
+        }
+        total
+    }
+
+    /// The blocks that dominate each block. The entries and every call target
+    /// count as roots, since a procedure is only entered through its call.
+    pub fn dominators(&self) -> BTreeMap<usize, BTreeSet<usize>> {
+        let mut predecessors: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+        let mut roots: BTreeSet<usize> = self.entries.iter().copied().collect();
+        for block in self.blocks.values() {
+            for edge in &block.successors {
+                if edge.kind == EdgeKind::Call {
+                    roots.insert(edge.target);
+                } else {
+                    predecessors
+                        .entry(edge.target)
+                        .or_default()
+                        .push(block.start);
+                }
+            }
+        }
+
+        let all: BTreeSet<usize> = self.blocks.keys().copied().collect();
+        let mut dominators: BTreeMap<usize, BTreeSet<usize>> = self
+            .blocks
+            .keys()
+            .map(|start| {
+                if roots.contains(start) {
+                    (*start, BTreeSet::from([*start]))
+                } else {
+                    (*start, all.clone())
+                }
+            })
+            .collect();
+
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for start in self.blocks.keys().filter(|start| !roots.contains(*start)) {
+                let mut incoming = predecessors
+                    .get(start)
+                    .into_iter()
+                    .flatten()
+                    .map(|predecessor| &dominators[predecessor]);
+                let mut dominated = incoming.next().cloned().unwrap_or_default();
+                for other in incoming {
+                    dominated.retain(|block| other.contains(block));
+                }
+                dominated.insert(*start);
+
+                if dominated != dominators[start] {
+                    dominators.insert(*start, dominated);
+                    changed = true;
+                }
+            }
+        }
+
+        dominators
+    }
+
+    /// Natural loops, one per header, in address order. Back edges to the
+    /// same header are merged into one loop.
+    pub fn natural_loops(&self) -> Vec<NaturalLoop> {
+        let dominators = self.dominators();
+        let mut loops: BTreeMap<usize, NaturalLoop> = BTreeMap::new();
+        for block in self.blocks.values() {
+            for edge in &block.successors {
+                if edge.kind == EdgeKind::Call || !dominators[&block.start].contains(&edge.target) {
+                    continue;
+                }
+
+                let found = loops.entry(edge.target).or_insert_with(|| NaturalLoop {
+                    header: edge.target,
+                    latches: BTreeSet::new(),
+                    blocks: BTreeSet::from([edge.target]),
+                });
+                found.latches.insert(block.start);
+
+                // Everything that reaches the latch without passing the header
+                let mut pending = vec![block.start];
+                while let Some(start) = pending.pop() {
+                    if found.blocks.insert(start) {
+                        pending.extend(self.predecessors(start));
+                    }
+                }
+            }
+        }
+
+        loops.into_values().collect()
+    }
+
+    fn predecessors(&self, target: usize) -> Vec<usize> {
+        self.blocks
+            .values()
+            .filter(|block| {
+                block
+                    .successors
+                    .iter()
+                    .any(|edge| edge.target == target && edge.kind != EdgeKind::Call)
+            })
+            .map(|block| block.start)
+            .collect()
+    }
+
+    /// Graphviz DOT with each block's disassembly, and its estimated clocks
+    /// when `timing` is given. Loop headers get a double border and back
+    /// edges are drawn bold.
+    pub fn dot(&self, timing: Option<&TimingState>) -> String {
+        let loops = self.natural_loops();
+        let mut text = String::from("digraph cfg {\n");
+        text.push_str("    node [shape=box, fontname=\"monospace\"];\n");
+
+        for block in self.blocks.values() {
+            let mut label = String::new();
+            for (address, inst) in &block.instructions {
+                let _ = write!(
+                    label,
+                    "0x{:05x}: {}\\l",
+                    address,
+                    instruction_text(inst).trim_end()
+                );
+            }
+            if let Some(timing) = timing {
+                let clocks = self.block_clocks(block, timing);
+                let _ = write!(label, "clocks: {}\\l", text::clock_interval_text(&clocks));
+            }
+
+            let header = loops.iter().any(|found| found.header == block.start);
+            let _ = writeln!(
+                text,
+                "    b{:05x} [label=\"{}\"{}];",
+                block.start,
+                label.replace('"', "\\\""),
+                if header { ", peripheries=2" } else { "" }
+            );
+        }
+
+        for block in self.blocks.values() {
+            for edge in &block.successors {
+                let back = loops.iter().any(|found| {
+                    found.header == edge.target && found.latches.contains(&block.start)
+                });
+                let style = match edge.kind {
+                    _ if back && edge.kind != EdgeKind::Call => " [style=bold, label=\"loop\"]",
+                    EdgeKind::Call => " [style=dashed, label=\"call\"]",
+                    EdgeKind::Fallthrough => "",
+                    EdgeKind::Branch => " [label=\"jump\"]",
+                };
+                let _ = writeln!(
+                    text,
+                    "    b{:05x} -> b{:05x}{};",
+                    block.start, edge.target, style
+                );
+            }
+        }
+
+        text.push_str("}\n");
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn starts(graph: &ControlFlowGraph) -> Vec<usize> {
+        graph.blocks().keys().copied().collect()
+    }
+
+    #[test]
+    fn splits_blocks_and_finds_loops() {
+        // mov cx, 3 / dec cx / jne $-1 / hlt
+        let graph = ControlFlowGraph::build(&[0xB9, 0x03, 0x00, 0x49, 0x75, 0xFD, 0xF4], 0);
+        assert_eq!(vec![0, 3, 6], starts(&graph));
+        assert_eq!(
+            vec![
+                Edge {
+                    target: 3,
+                    kind: EdgeKind::Branch
+                },
+                Edge {
+                    target: 6,
+                    kind: EdgeKind::Fallthrough
+                }
+            ],
+            graph.blocks()[&3].successors
+        );
+
+        let loops = graph.natural_loops();
+        assert_eq!(1, loops.len());
+        assert_eq!(3, loops[0].header);
+        assert_eq!(BTreeSet::from([3]), loops[0].blocks);
+
+        let timing = TimingState {
+            assume_branch_taken: true,
+            ..TimingState::default()
+        };
+        let dot = graph.dot(Some(&timing));
+        assert!(dot.contains(
+            "b00003 [label=\"0x00003: dec cx\\l0x00004: jne $-1\\lclocks: 18\\l\", peripheries=2];"
+        ));
+        assert!(dot.contains("b00003 -> b00003 [style=bold, label=\"loop\"];"));
+    }
+
+    #[test]
+    fn follows_calls_and_nested_loops() {
+        // 0: call 9 / hlt / (unreachable) nop
+        // 4: ...
+        // 9: mov bx, 2 / 12: mov cx, 2 / 15: loop 15 / 17: dec bx / 18: jne 12 / 20: ret
+        let program = [
+            0xE8, 0x06, 0x00, 0xF4, 0x90, 0x90, 0x90, 0x90, 0x90, 0xBB, 0x02, 0x00, 0xB9, 0x02,
+            0x00, 0xE2, 0xFE, 0x4B, 0x75, 0xF8, 0xC3,
+        ];
+        let graph = ControlFlowGraph::build(&program, 0);
+        assert_eq!(vec![0, 3, 9, 12, 15, 17, 20], starts(&graph));
+        assert_eq!(EdgeKind::Call, graph.blocks()[&0].successors[0].kind);
+        assert!(graph.blocks()[&20].successors.is_empty());
+
+        let loops = graph.natural_loops();
+        let headers: Vec<usize> = loops.iter().map(|found| found.header).collect();
+        assert_eq!(vec![12, 15], headers);
+        assert_eq!(BTreeSet::from([12, 15, 17]), loops[0].blocks);
+        assert_eq!(BTreeSet::from([15]), loops[1].blocks);
+    }
+}
@@ -0,0 +1,33 @@
+use sim86_shared::cfg::ControlFlowGraph;
+use sim86_shared::cycles::TimingState;
+
+use super::{read_program, usage_error, Outcome};
+
+/// Prints the control-flow graph of a program as Graphviz DOT.
+pub fn run(args: &[String]) -> Outcome {
+    let mut clocks = false;
+    let mut timing = TimingState {
+        assume_branch_taken: true,
+        ..TimingState::default()
+    };
+    let mut file = None;
+    for arg in args {
+        match arg.as_str() {
+            "--clocks" => clocks = true,
+            "-8088" => timing.assume_8088 = true,
+            _ if !arg.starts_with('-') && file.is_none() => file = Some(arg),
+            _ => return usage_error(),
+        }
+    }
+
+    let Some(file) = file else {
+        return usage_error();
+    };
+    let Some(buf) = read_program(file) else {
+        return Outcome::BadInput;
+    };
+
+    let graph = ControlFlowGraph::build(&buf, 0);
+    print!("{}", graph.dot(clocks.then_some(&timing)));
+    Outcome::Finished
+}
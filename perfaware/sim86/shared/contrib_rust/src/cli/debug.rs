@@ -0,0 +1,400 @@
+use std::ffi::c_int;
+use std::io::{self, BufRead, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+
+use sim86_shared::debugger::*;
+use sim86_shared::snapshot::SnapshotError;
+use sim86_shared::text::*;
+use sim86_shared::*;
+
+use super::{parse_session, report, Outcome, Session};
+
+const DEBUG_HELP: &str = "Locations are labels, SEG:OFF in hex, or absolute addresses. Numbers are
+decimal, or hex as 0x10, 10h or $10.
+    step [N]            (s) execute N instructions, default 1
+    next                (n) step, running calls through to their return
+    continue            (c) run until a breakpoint or the program stops, or
+                        Ctrl-C is pressed
+    back [N]            undo the last N instructions, default 1
+    reverse-continue    (rc) run backwards to the previous breakpoint
+    writer LOC          show the last instruction that wrote the byte at LOC
+    break LOC [if EXPR] (b) set a breakpoint, stopping only when EXPR holds
+    ignore LOC N        pass over the next N hits of a breakpoint
+    delete LOC          (d) remove a breakpoint
+    watch LOC [LEN]     stop after an instruction writes LEN bytes at LOC
+    rwatch LOC [LEN]    stop after an instruction reads them
+    awatch LOC [LEN]    stop after an instruction reads or writes them
+    unwatch LOC         remove the watchpoints starting at LOC
+    print EXPR          (p) evaluate an expression such as \"cx == 0 && [bp+2] > 10\"
+    info                (i) list breakpoints, watchpoints and labels
+    label NAME [LOC]    name a location, default the current instruction
+    list [N]            (l) disassemble N instructions either side of ip
+    regs                (r) show all registers
+    set REG VALUE       change a 16-bit register
+    x LOC [COUNT]       dump COUNT bytes of memory, default 64
+    poke LOC BYTE...    write bytes to memory
+    save FILE           write a snapshot of the machine state
+    load FILE           restore a snapshot (recorded history starts over)
+    history [N]         show the recorded history, or keep only the last N
+                        instructions (0 stops recording)
+    quit                (q) leave the debugger";
+
+fn location_text(debugger: &Debugger, address: usize) -> String {
+    match debugger.label_at(address) {
+        Some(label) => format!("0x{:05x} <{}>", address, label),
+        None => format!("0x{:05x}", address),
+    }
+}
+
+fn print_current(debugger: &Debugger) {
+    let address = debugger.simulator().instruction_address();
+    match debugger.instruction_at(address) {
+        Some(inst) => println!(
+            "=> {}: {}",
+            location_text(debugger, address),
+            instruction_text(&inst)
+        ),
+        None => println!("=> {}", location_text(debugger, address)),
+    }
+}
+
+fn print_stop(debugger: &Debugger, reason: StopReason) {
+    match reason {
+        StopReason::Step => {}
+        StopReason::Breakpoint(address) => {
+            println!("Breakpoint at {}.", location_text(debugger, address))
+        }
+        StopReason::Halted => println!("Program halted."),
+        StopReason::ProgramEnd => println!("Execution left the program."),
+        StopReason::DecodeError(address) => eprintln!(
+            "ERROR: Unrecognized binary at {}.",
+            location_text(debugger, address)
+        ),
+        StopReason::Unimplemented(op) => eprintln!(
+            "ERROR: Unimplemented instruction ({}).",
+            mnemonic_from_operation_type(op)
+        ),
+        StopReason::MemoryFault(address) => eprintln!(
+            "ERROR: Code runs past the end of its segment at 0x{:x}.",
+            address
+        ),
+        StopReason::StepLimit(steps) => println!("Step limit of {} reached.", steps),
+        StopReason::Interrupted => println!("Interrupted."),
+        StopReason::HistoryStart => println!("Reached the start of the recorded history."),
+        StopReason::Watchpoint(hit) => {
+            if hit.write {
+                println!(
+                    "Watchpoint: wrote 0x{:05x}, 0x{:02x} -> 0x{:02x}.",
+                    hit.address, hit.old, hit.new
+                );
+            } else {
+                println!(
+                    "Watchpoint: read 0x{:05x} = 0x{:02x}.",
+                    hit.address, hit.new
+                );
+            }
+        }
+    }
+    print_current(debugger);
+}
+
+fn print_registers(debugger: &Debugger) {
+    let registers = debugger.simulator().registers();
+    let mut line = String::new();
+    for index in Register_a..Register_flags {
+        let access = register_access {
+            Index: index,
+            Offset: 0,
+            Count: 2,
+        };
+        line.push_str(&format!(
+            "{:>2}: 0x{:04x}  ",
+            register_name_from_operand(&access),
+            registers.get(index)
+        ));
+        if (index - Register_a) % 4 == 3 || index == Register_ip {
+            println!("{}", line.trim_end());
+            line.clear();
+        }
+    }
+    println!("flags: {}", flags_text(registers.flags()));
+}
+
+fn print_memory(debugger: &Debugger, address: usize, count: usize) {
+    let memory = debugger.simulator().memory();
+    let end = (address + count).min(memory.len());
+    for (row, bytes) in memory[address..end].chunks(16).enumerate() {
+        let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+        println!("0x{:05x}: {}", address + row * 16, hex.join(" "));
+    }
+}
+
+fn print_listing(debugger: &Debugger, count: usize) {
+    let ip = debugger.simulator().instruction_address();
+    for (address, inst) in debugger.disassemble_around(ip, count, count) {
+        if let Some(label) = debugger.label_at(address) {
+            println!("{}:", label);
+        }
+        let marker = if address == ip { "=>" } else { "  " };
+        let breakpoint = if debugger.breakpoints().contains_key(&address) {
+            '*'
+        } else {
+            ' '
+        };
+        println!(
+            "{}{} 0x{:05x}: {}",
+            marker,
+            breakpoint,
+            address,
+            instruction_text(&inst)
+        );
+    }
+}
+
+/// Runs one debugger command. Returns false when the session should end.
+fn debug_command(debugger: &mut Debugger, command: Command) -> bool {
+    match command {
+        Command::Step(steps) => {
+            for _ in 0..steps {
+                let address = debugger.simulator().instruction_address();
+                let inst = debugger.instruction_at(address);
+                let before = *debugger.simulator().registers();
+                let reason = debugger.step();
+
+                if let (
+                    Some(inst),
+                    StopReason::Step
+                    | StopReason::Halted
+                    | StopReason::ProgramEnd
+                    | StopReason::Watchpoint(_),
+                ) = (inst, reason)
+                {
+                    println!(
+                        "0x{:05x}: {} ; {}",
+                        address,
+                        instruction_text(&inst),
+                        register_difference_text(&before, debugger.simulator().registers())
+                    );
+                }
+                if reason != StopReason::Step {
+                    print_stop(debugger, reason);
+                    return true;
+                }
+            }
+            print_current(debugger);
+        }
+        Command::Next => {
+            let reason = debugger.step_over();
+            print_stop(debugger, reason);
+        }
+        Command::Continue => {
+            let reason = debugger.continue_execution();
+            print_stop(debugger, reason);
+        }
+        Command::Back(steps) => {
+            for _ in 0..steps {
+                let reason = debugger.step_back();
+                if reason != StopReason::Step {
+                    print_stop(debugger, reason);
+                    return true;
+                }
+            }
+            print_current(debugger);
+        }
+        Command::ReverseContinue => {
+            let reason = debugger.reverse_continue();
+            print_stop(debugger, reason);
+        }
+        Command::Writer(address) => match debugger.simulator().last_writer(address) {
+            Some(write) => {
+                let text = debugger
+                    .instruction_at(write.instruction_address)
+                    .map(|inst| instruction_text(&inst))
+                    .unwrap_or_default();
+                println!(
+                    "Step {}: {}: {} (was 0x{:02x})",
+                    write.step,
+                    location_text(debugger, write.instruction_address),
+                    text,
+                    write.old
+                );
+            }
+            None => println!("No recorded write to 0x{:05x}.", address),
+        },
+        Command::Break { address, condition } => {
+            debugger.add_breakpoint(address);
+            if let Some(breakpoint) = debugger.breakpoint_mut(address) {
+                breakpoint.condition = condition;
+            }
+            println!("Breakpoint at {}.", location_text(debugger, address));
+        }
+        Command::Ignore { address, count } => match debugger.breakpoint_mut(address) {
+            Some(breakpoint) => breakpoint.ignore_count = count,
+            None => println!("No breakpoint at {}.", location_text(debugger, address)),
+        },
+        Command::Delete(address) => {
+            if !debugger.remove_breakpoint(address) {
+                println!("No breakpoint at {}.", location_text(debugger, address));
+            }
+        }
+        Command::Watch { range, kind } => debugger.add_watchpoint(range, kind),
+        Command::Unwatch(address) => {
+            if !debugger.remove_watchpoint(address) {
+                println!("No watchpoint at {}.", location_text(debugger, address));
+            }
+        }
+        Command::Print(expression) => {
+            let value = expression.evaluate(debugger.simulator());
+            println!("{} = {} (0x{:x})", expression, value, value);
+        }
+        Command::Info => {
+            for (address, breakpoint) in debugger.breakpoints() {
+                let mut line = format!("breakpoint {}", location_text(debugger, *address));
+                if let Some(condition) = &breakpoint.condition {
+                    line.push_str(&format!(" if {}", condition));
+                }
+                line.push_str(&format!(", hit {} times", breakpoint.hits));
+                if breakpoint.ignore_count > 0 {
+                    line.push_str(&format!(", ignoring {} more", breakpoint.ignore_count));
+                }
+                println!("{}", line);
+            }
+            for watch in debugger.watchpoints() {
+                let kind = match watch.kind {
+                    WatchKind::Read => "rwatch",
+                    WatchKind::Write => "watch",
+                    WatchKind::Access => "awatch",
+                };
+                println!(
+                    "{} 0x{:05x}, {} bytes",
+                    kind,
+                    watch.range.start,
+                    watch.range.len()
+                );
+            }
+            for (name, address) in debugger.labels() {
+                println!("label {} = 0x{:05x}", name, address);
+            }
+        }
+        Command::Label { name, address } => debugger.define_label(&name, address),
+        Command::List(count) => print_listing(debugger, count as usize),
+        Command::Registers => print_registers(debugger),
+        Command::Set { index, value } => debugger.simulator_mut().registers_mut().set(index, value),
+        Command::Examine { address, count } => print_memory(debugger, address, count),
+        Command::Poke { address, bytes } => {
+            let memory = debugger.simulator_mut().memory_mut();
+            for (offset, byte) in bytes.iter().enumerate() {
+                if let Some(slot) = memory.get_mut(address + offset) {
+                    *slot = *byte;
+                }
+            }
+        }
+        Command::Save(path) => {
+            let result = std::fs::File::create(&path)
+                .and_then(|mut file| debugger.simulator().save_snapshot(&mut file));
+            if let Err(err) = result {
+                eprintln!("ERROR: Unable to save {}: {}", path, err);
+            }
+        }
+        Command::Load(path) => {
+            let result = std::fs::File::open(&path)
+                .map_err(SnapshotError::from)
+                .and_then(|mut file| debugger.simulator_mut().restore_snapshot(&mut file));
+            match result {
+                Ok(()) => print_current(debugger),
+                Err(err) => eprintln!("ERROR: Unable to load {}: {}", path, err),
+            }
+        }
+        Command::History(limit) => {
+            let simulator = debugger.simulator_mut();
+            if let Some(limit) = limit {
+                simulator.limit_history(limit);
+            }
+            println!(
+                "{} instructions recorded, keeping at most {}.",
+                simulator.history_len(),
+                simulator.history_limit()
+            );
+        }
+        Command::Help => println!("{}", DEBUG_HELP),
+        Command::Quit => return false,
+    }
+
+    true
+}
+
+/// Makes Ctrl-C set `flag` instead of ending the process, so that it stops a
+/// running program and returns to the debugger's prompt.
+fn catch_interrupts(flag: Arc<AtomicBool>) {
+    const SIGINT: c_int = 2;
+    static FLAG: OnceLock<Arc<AtomicBool>> = OnceLock::new();
+
+    extern "C" {
+        fn signal(signum: c_int, handler: extern "C" fn(c_int)) -> usize;
+    }
+
+    // Some C runtimes reset the handler once it runs, so it puts itself back
+    extern "C" fn on_interrupt(_: c_int) {
+        if let Some(flag) = FLAG.get() {
+            flag.store(true, Ordering::Relaxed);
+        }
+        unsafe { signal(SIGINT, on_interrupt) };
+    }
+
+    if FLAG.set(flag).is_ok() {
+        unsafe { signal(SIGINT, on_interrupt) };
+    }
+}
+
+/// Runs the interactive debugger on one program.
+pub fn run(args: &[String]) -> Outcome {
+    let Session {
+        file,
+        buf,
+        load_at,
+        max_steps,
+        ..
+    } = match parse_session(args, false) {
+        Ok(session) => session,
+        Err(outcome) => return outcome,
+    };
+
+    let mut debugger = match Debugger::new(&buf, load_at.0, load_at.1) {
+        Ok(debugger) => debugger,
+        Err(err) => return report(err),
+    };
+    debugger.limit_steps(max_steps);
+    catch_interrupts(debugger.interrupt_flag());
+    println!(
+        "Debugging {} ({} bytes at {:04x}:{:04x}). Type \"help\" for commands.",
+        file,
+        buf.len(),
+        load_at.0,
+        load_at.1
+    );
+    print_current(&debugger);
+
+    let mut lines = io::stdin().lock().lines();
+    loop {
+        print!("(sim86) ");
+        let _ = io::stdout().flush();
+        let Some(Ok(line)) = lines.next() else {
+            break;
+        };
+
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if let Some((command, args)) = words.split_first() {
+            match debugger.parse_command(command, args) {
+                Ok(command) => {
+                    if !debug_command(&mut debugger, command) {
+                        break;
+                    }
+                }
+                Err(err) => eprintln!("ERROR: {}.", err),
+            }
+        }
+    }
+
+    Outcome::Finished
+}
@@ -0,0 +1,40 @@
+use std::net::TcpListener;
+
+use sim86_shared::debugger::Debugger;
+use sim86_shared::gdb::GdbServer;
+
+use super::{parse_session, report, Outcome};
+
+/// Serves one gdb session on one program.
+pub fn run(args: &[String]) -> Outcome {
+    let session = match parse_session(args, true) {
+        Ok(session) => session,
+        Err(outcome) => return outcome,
+    };
+
+    let (segment, offset) = session.load_at;
+    let debugger = match Debugger::new(&session.buf, segment, offset) {
+        Ok(debugger) => debugger,
+        Err(err) => return report(err),
+    };
+    let listener = match TcpListener::bind(("127.0.0.1", session.port)) {
+        Ok(listener) => listener,
+        Err(err) => {
+            eprintln!("ERROR: Unable to listen on port {}: {}", session.port, err);
+            return Outcome::BadInput;
+        }
+    };
+
+    println!(
+        "Waiting for gdb on 127.0.0.1:{} to debug {}.",
+        session.port, session.file
+    );
+    let result = listener
+        .accept()
+        .and_then(|(stream, _)| GdbServer::new(debugger).serve(stream));
+    if let Err(err) = result {
+        eprintln!("ERROR: gdb connection failed: {}", err);
+    }
+
+    Outcome::Finished
+}
@@ -0,0 +1,214 @@
+//! The subcommands of sim86_shared_example, each with a `run` function
+//! taking the arguments after its name, and what they share.
+
+use sim86_shared::asm::assemble;
+use sim86_shared::debugger::DEFAULT_STEP_LIMIT;
+use sim86_shared::simulator::SimError;
+
+pub mod cfg;
+pub mod debug;
+pub mod gdb;
+pub mod simulate;
+pub mod singlestep;
+pub mod trace_diff;
+
+const USAGE: &str = "USAGE: sim86_shared_example [options] [8086 machine code file] ...
+       sim86_shared_example debug [--load-at SEG:OFF] [--max-steps N] <8086 machine code file>
+       sim86_shared_example gdb [--port N] [--load-at SEG:OFF] <8086 machine code file>
+       sim86_shared_example trace-diff [--context N] <a.trace> <b.trace>
+       sim86_shared_example cfg [--clocks] [-8088] <8086 machine code file>
+       sim86_shared_example singlestep [--metadata FILE] [--failures N] <dir or .json file> ...
+
+Options apply to every file that comes after them:
+    -exec              simulate the following files
+    -disasm            disassemble the following files (default)
+    -showclocks        print estimated clocks for each instruction
+    -explainclocks     as -showclocks, with the EA and penalty breakdown
+    -8088              estimate clocks for the 8088's 8-bit bus
+    -dump              write memory to sim86_memory_N.data after each simulation
+    -stoponret         stop simulating at the first ret
+    --recursive        disassemble by following control flow from the first
+                       byte, listing bytes it never reaches as db data
+    --entry OFFSET     also follow control flow from OFFSET into the file
+                       (decimal, or hex as 0x10, 10h or $10); implies
+                       --recursive
+    --labels           name branch targets label_XXXX and jump to them by
+                       name; implies --recursive
+    --symbols FILE     name locations from FILE, one \"NAME OFFSET\" per line;
+                       implies --labels
+    --load-at SEG:OFF  start execution at SEG:OFF (hex), default 0000:0000
+    --max-steps N      give up after N instructions
+    --trace FILE       record a binary trace of each simulation to FILE
+    --profile          after each simulation, print clocks per instruction,
+                       the hottest instructions and the loops
+    --coverage         after each simulation, print the program annotated with
+                       execution counts and branch outcomes
+    --lcov FILE        write the coverage of each simulation to FILE as lcov
+    --lockstep         run the reference simulator's executor alongside, and
+                       stop at the first instruction after which registers or
+                       memory differ
+    --quiet            only print final registers and errors
+
+Files ending in .asm are assembled first, from the NASM subset the course
+listings use. With no files the built-in example is used. Exits non-zero if
+any file could not be read, failed to decode, hit an unimplemented
+instruction, ran out of steps or diverged from the reference.
+
+trace-diff compares two traces from --trace, printing where they first differ,
+and exits with 5 if they do.
+
+cfg prints the control-flow graph of a file as Graphviz DOT, with loop headers
+double-bordered and back edges in bold. --clocks adds estimated clocks for each
+block, assuming branches are taken.
+
+singlestep runs single-step CPU test suites: JSON files named for their opcode
+(\"00.json\", \"F6.4.json\"), each an array of cases giving the registers and
+memory before and after one instruction. Directories are searched for such
+files, which must be gunzipped first. Results are reported per opcode, with
+the mismatching fields of the first N failures (default 3). Flags the 8086
+leaves undefined are ignored, or those outside the \"flags-mask\" of each opcode
+in the suite's metadata file. Exits with 5 if any case fails.
+
+The debug command starts an interactive debugger; type \"help\" at its prompt
+for the list of commands. continue and next give up after --max-steps
+instructions (default 10000000), or when Ctrl-C is pressed. The gdb command
+waits on 127.0.0.1 (port 1234 by default) for gdb to attach with
+\"set architecture i8086\" and \"target remote :1234\".";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Finished,
+    BadInput,
+    DecodeError,
+    Unimplemented,
+    StepLimit,
+    Diverged,
+}
+
+impl From<&SimError> for Outcome {
+    fn from(err: &SimError) -> Self {
+        match err {
+            SimError::DecodeError { .. } => Outcome::DecodeError,
+            SimError::StepLimit(_) => Outcome::StepLimit,
+            SimError::MemoryFault(_) => Outcome::BadInput,
+            SimError::Unimplemented(_) => Outcome::Unimplemented,
+            // Running on from a hlt does nothing, which isn't a failure
+            SimError::Halted => Outcome::Finished,
+        }
+    }
+}
+
+/// Prints why a simulation stopped early.
+fn report(err: SimError) -> Outcome {
+    eprintln!("ERROR: {}.", err);
+    Outcome::from(&err)
+}
+
+/// Prints the usage for a command line that can't be parsed.
+fn usage_error() -> Outcome {
+    eprintln!("{}", USAGE);
+    Outcome::BadInput
+}
+
+impl Outcome {
+    pub fn exit_code(self) -> u8 {
+        match self {
+            Outcome::Finished => 0,
+            Outcome::BadInput => 1,
+            Outcome::DecodeError => 2,
+            Outcome::Unimplemented => 3,
+            Outcome::StepLimit => 4,
+            Outcome::Diverged => 5,
+        }
+    }
+}
+
+fn parse_load_at(value: &str) -> Option<(u16, u16)> {
+    let parse = |part: &str| {
+        let part = part.trim_start_matches("0x").trim_start_matches("0X");
+        u16::from_str_radix(part, 16).ok()
+    };
+    let (segment, offset) = value.split_once(':')?;
+    Some((parse(segment)?, parse(offset)?))
+}
+
+/// The machine code in `path`, assembling it first if it is a `.asm` source.
+fn read_program(path: &str) -> Option<Vec<u8>> {
+    if !path.ends_with(".asm") {
+        let buf = std::fs::read(path).ok();
+        if buf.is_none() {
+            eprintln!("ERROR: Unable to open {}.", path);
+        }
+        return buf;
+    }
+
+    let Ok(source) = std::fs::read_to_string(path) else {
+        eprintln!("ERROR: Unable to open {}.", path);
+        return None;
+    };
+    match assemble(&source) {
+        Ok(buf) => Some(buf),
+        Err(err) => {
+            eprintln!("ERROR: {}: {}", path, err);
+            None
+        }
+    }
+}
+
+/// What the debug and gdb commands were asked to load.
+struct Session {
+    file: String,
+    buf: Vec<u8>,
+    load_at: (u16, u16),
+    port: u16,
+    max_steps: u64,
+}
+
+const DEFAULT_GDB_PORT: u16 = 1234;
+
+fn parse_session(args: &[String], accepts_port: bool) -> Result<Session, Outcome> {
+    let mut load_at = (0u16, 0u16);
+    let mut port = DEFAULT_GDB_PORT;
+    let mut max_steps = DEFAULT_STEP_LIMIT;
+    let mut file = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let (flag, inline_value) = match arg.split_once('=') {
+            Some((flag, value)) => (flag, Some(value.to_string())),
+            None => (arg.as_str(), None),
+        };
+        let mut value = || inline_value.clone().or_else(|| args.next().cloned());
+
+        match flag {
+            "--load-at" => match value().as_deref().and_then(parse_load_at) {
+                Some(at) => load_at = at,
+                None => return Err(usage_error()),
+            },
+            "--max-steps" if !accepts_port => match value().map(|steps| steps.parse()) {
+                Some(Ok(steps)) => max_steps = steps,
+                _ => return Err(usage_error()),
+            },
+            "--port" if accepts_port => match value().map(|port| port.parse()) {
+                Some(Ok(value)) => port = value,
+                _ => return Err(usage_error()),
+            },
+            _ if file.is_none() && !arg.starts_with('-') => file = Some(arg.clone()),
+            _ => return Err(usage_error()),
+        }
+    }
+
+    let Some(file) = file else {
+        return Err(usage_error());
+    };
+    let Some(buf) = read_program(&file) else {
+        return Err(Outcome::BadInput);
+    };
+
+    Ok(Session {
+        file,
+        buf,
+        load_at,
+        port,
+        max_steps,
+    })
+}
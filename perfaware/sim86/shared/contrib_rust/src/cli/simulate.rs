@@ -0,0 +1,489 @@
+use std::fs::File;
+use std::io::BufWriter;
+
+use sim86_shared::coverage::Coverage;
+use sim86_shared::cycles::*;
+use sim86_shared::debugger::parse_number;
+use sim86_shared::disasm::{parse_symbols, Disassembly, DisassemblyLine};
+use sim86_shared::lockstep::{compare, compare_all, ReferenceError, ReferenceSimulator};
+use sim86_shared::profile::Profile;
+use sim86_shared::simulator::{SimError, Simulator};
+use sim86_shared::text::*;
+use sim86_shared::trace::*;
+use sim86_shared::*;
+
+use super::{parse_load_at, read_program, report, usage_error, Outcome, USAGE};
+
+const EXAMPLE_DISASSEMBLY: [u8; 247] = [
+    0x03, 0x18, 0x03, 0x5E, 0x00, 0x83, 0xC6, 0x02, 0x83, 0xC5, 0x02, 0x83, 0xC1, 0x08, 0x03, 0x5E,
+    0x00, 0x03, 0x4F, 0x02, 0x02, 0x7A, 0x04, 0x03, 0x7B, 0x06, 0x01, 0x18, 0x01, 0x5E, 0x00, 0x01,
+    0x5E, 0x00, 0x01, 0x4F, 0x02, 0x00, 0x7A, 0x04, 0x01, 0x7B, 0x06, 0x80, 0x07, 0x22, 0x83, 0x82,
+    0xE8, 0x03, 0x1D, 0x03, 0x46, 0x00, 0x02, 0x00, 0x01, 0xD8, 0x00, 0xE0, 0x05, 0xE8, 0x03, 0x04,
+    0xE2, 0x04, 0x09, 0x2B, 0x18, 0x2B, 0x5E, 0x00, 0x83, 0xEE, 0x02, 0x83, 0xED, 0x02, 0x83, 0xE9,
+    0x08, 0x2B, 0x5E, 0x00, 0x2B, 0x4F, 0x02, 0x2A, 0x7A, 0x04, 0x2B, 0x7B, 0x06, 0x29, 0x18, 0x29,
+    0x5E, 0x00, 0x29, 0x5E, 0x00, 0x29, 0x4F, 0x02, 0x28, 0x7A, 0x04, 0x29, 0x7B, 0x06, 0x80, 0x2F,
+    0x22, 0x83, 0x29, 0x1D, 0x2B, 0x46, 0x00, 0x2A, 0x00, 0x29, 0xD8, 0x28, 0xE0, 0x2D, 0xE8, 0x03,
+    0x2C, 0xE2, 0x2C, 0x09, 0x3B, 0x18, 0x3B, 0x5E, 0x00, 0x83, 0xFE, 0x02, 0x83, 0xFD, 0x02, 0x83,
+    0xF9, 0x08, 0x3B, 0x5E, 0x00, 0x3B, 0x4F, 0x02, 0x3A, 0x7A, 0x04, 0x3B, 0x7B, 0x06, 0x39, 0x18,
+    0x39, 0x5E, 0x00, 0x39, 0x5E, 0x00, 0x39, 0x4F, 0x02, 0x38, 0x7A, 0x04, 0x39, 0x7B, 0x06, 0x80,
+    0x3F, 0x22, 0x83, 0x3E, 0xE2, 0x12, 0x1D, 0x3B, 0x46, 0x00, 0x3A, 0x00, 0x39, 0xD8, 0x38, 0xE0,
+    0x3D, 0xE8, 0x03, 0x3C, 0xE2, 0x3C, 0x09, 0x75, 0x02, 0x75, 0xFC, 0x75, 0xFA, 0x75, 0xFC, 0x74,
+    0xFE, 0x7C, 0xFC, 0x7E, 0xFA, 0x72, 0xF8, 0x76, 0xF6, 0x7A, 0xF4, 0x70, 0xF2, 0x78, 0xF0, 0x75,
+    0xEE, 0x7D, 0xEC, 0x7F, 0xEA, 0x73, 0xE8, 0x77, 0xE6, 0x7B, 0xE4, 0x71, 0xE2, 0x79, 0xE0, 0xE2,
+    0xDE, 0xE1, 0xDC, 0xE0, 0xDA, 0xE3, 0xD8,
+];
+
+// How many of the most expensive instructions --profile lists.
+const PROFILE_HOT_SPOTS: usize = 10;
+
+const CLOCKS_WARNING: &str = "
+WARNING: Clocks reported by this utility are strictly from the 8086 manual.
+They will be inaccurate, both because the manual clocks are estimates, and because
+some of the entries in the manual look highly suspicious and are probably typos.
+";
+
+#[derive(Debug, Default, Clone)]
+struct Options {
+    execute: bool,
+    show_clocks: bool,
+    explain_clocks: bool,
+    dump: bool,
+    stop_on_ret: bool,
+    quiet: bool,
+    profile: bool,
+    coverage: bool,
+    lockstep: bool,
+    recursive: bool,
+    entries: Vec<usize>,
+    labels: bool,
+    symbols: Vec<(String, usize)>,
+    load_segment: u16,
+    load_offset: u16,
+    max_steps: Option<u64>,
+    trace: Option<String>,
+    lcov: Option<String>,
+    timing: TimingState,
+}
+
+fn is_ret(op: operation_type) -> bool {
+    op == operation_type_Op_ret || op == operation_type_Op_retf
+}
+
+fn disassemble(buf: &[u8], options: &Options) -> Outcome {
+    // Without a simulation to say otherwise, assume branches are taken, since
+    // that is what loop conditionals usually do.
+    let mut timing = options.timing;
+    timing.assume_branch_taken = true;
+    let mut total = ClockInterval::default();
+    let mut print_instruction = |mut line: String, decoded: &instruction| {
+        if options.show_clocks {
+            let estimate = estimate_instruction_clocks(&timing, decoded);
+            let clocks = expected_clocks_from(&timing, decoded, &estimate);
+            total.min += clocks.min;
+            total.max += clocks.max;
+            line.push_str(" ; ");
+            line.push_str(&clocks_text(&clocks, &total));
+            if options.explain_clocks {
+                line.push_str(&explain_timing_text(&estimate, &clocks));
+            }
+        }
+        println!("{}", line);
+    };
+
+    if options.recursive {
+        let mut entries = vec![0];
+        entries.extend(&options.entries);
+        let mut disassembly = Disassembly::new(buf, 0, &entries);
+        for (name, offset) in &options.symbols {
+            disassembly.define_label(*offset, name);
+        }
+        if options.labels {
+            disassembly.synthesize_labels();
+        }
+        if !options.quiet {
+            for line in disassembly.lines() {
+                if let Some(label) = disassembly.label_at(line.address()) {
+                    println!("{}:", label);
+                }
+                let text = disassembly.line_text(line);
+                match line {
+                    DisassemblyLine::Instruction { inst, .. } => print_instruction(text, inst),
+                    DisassemblyLine::Data { .. } => println!("{}", text),
+                }
+            }
+        }
+        return Outcome::Finished;
+    }
+
+    for decoded in decode_stream(buf, 0) {
+        let decoded = match decoded {
+            Ok(decoded) => decoded.instruction,
+            Err(err) => {
+                eprintln!("ERROR: {}.", err);
+                return Outcome::DecodeError;
+            }
+        };
+        if !options.quiet {
+            print_instruction(instruction_text(&decoded), &decoded);
+        }
+    }
+
+    Outcome::Finished
+}
+
+type FileTrace = TraceWriter<BufWriter<File>>;
+
+fn execute(
+    name: &str,
+    buf: &[u8],
+    options: &Options,
+    simulator: &mut Simulator<WriteLog>,
+    mut trace: Option<&mut FileTrace>,
+) -> Outcome {
+    let (segment, offset) = (options.load_segment, options.load_offset);
+    let base = simulator.instruction_address();
+    if let Err(err) = simulator.load(segment, offset, buf) {
+        return report(err);
+    }
+
+    let mut timing = options.timing;
+    let mut total = ClockInterval::default();
+    let mut steps = 0u64;
+    let mut outcome = Outcome::Finished;
+    let mut profile = options.profile.then(Profile::new);
+    let mut coverage = (options.coverage || options.lcov.is_some()).then(Coverage::new);
+    let mut reference = options
+        .lockstep
+        .then(|| ReferenceSimulator::matching(simulator));
+    let mut skipped = 0u64;
+
+    // The program runs from simulated memory, so it sees its own writes, and
+    // stops once CS:IP leaves the bytes it was loaded into
+    loop {
+        let address = simulator.instruction_address();
+        let registers = simulator.registers();
+        let into_program = registers.ip().wrapping_sub(offset) as usize;
+        if registers.cs() != segment || into_program >= buf.len() {
+            break;
+        }
+
+        let decoded = match simulator.fetch_instruction() {
+            Ok(decoded) => decoded,
+            Err(err) => {
+                outcome = report(err);
+                break;
+            }
+        };
+        // Taken before running, in case the instruction overwrites itself
+        let bytes = trace
+            .is_some()
+            .then(|| simulator.code_bytes(decoded.Size as usize));
+
+        if options.stop_on_ret && is_ret(decoded.Op) {
+            println!("STOPONRET: Return encountered at address {}.", address);
+            break;
+        }
+
+        if options.max_steps.is_some_and(|max| steps >= max) {
+            outcome = report(SimError::StepLimit(steps));
+            break;
+        }
+
+        let prev = *simulator.registers();
+        let exec = match simulator.execute_instruction(&decoded) {
+            Ok(exec) => exec,
+            Err(err) => {
+                outcome = report(err);
+                break;
+            }
+        };
+        steps += 1;
+
+        timing.update_for_exec(&exec);
+        let estimate = estimate_instruction_clocks(&timing, &decoded);
+        let clocks = expected_clocks_from(&timing, &decoded, &estimate);
+        total.min += clocks.min;
+        total.max += clocks.max;
+        if let Some(profile) = &mut profile {
+            profile.record(address, &decoded, &exec, clocks);
+        }
+        if let Some(coverage) = &mut coverage {
+            coverage.record(address, &decoded, &exec);
+        }
+
+        if let (Some(writer), Some(bytes)) = (trace.as_deref_mut(), &bytes) {
+            let writes = simulator.hooks().writes();
+            if let Err(err) = writer.record(address, bytes, simulator.registers(), writes, clocks) {
+                eprintln!("ERROR: Unable to write trace: {}", err);
+                trace = None;
+            }
+        }
+
+        if !options.quiet {
+            let mut line = instruction_text(&decoded);
+            line.push_str(" ; ");
+            if options.show_clocks {
+                line.push_str(&clocks_text(&clocks, &total));
+                if options.explain_clocks {
+                    line.push_str(&explain_timing_text(&estimate, &clocks));
+                }
+                line.push_str(" | ");
+            }
+            line.push_str(&register_difference_text(&prev, simulator.registers()));
+            println!("{}", line);
+        }
+
+        if let Some(reference) = &mut reference {
+            let text = instruction_text(&decoded);
+            match reference.execute_instruction(&decoded) {
+                Ok(_) => {
+                    let writes = simulator.hooks().writes();
+                    if let Some(divergence) = compare(simulator, reference, writes) {
+                        eprintln!(
+                            "ERROR: Diverged from the reference simulator at 0x{:05x} ({}):",
+                            address,
+                            text.trim_end()
+                        );
+                        eprint!("{}", divergence);
+                        outcome = Outcome::Diverged;
+                        break;
+                    }
+                }
+                Err(ReferenceError::Unimplemented(_)) => {
+                    skipped += 1;
+                    reference.sync(simulator);
+                }
+                Err(err) => {
+                    eprintln!(
+                        "ERROR: At 0x{:05x} ({}): {}.",
+                        address,
+                        text.trim_end(),
+                        err
+                    );
+                    outcome = Outcome::Diverged;
+                    break;
+                }
+            }
+        }
+
+        if simulator.halted() {
+            break;
+        }
+    }
+
+    // Each step only checked the memory the Rust side wrote, so look over all
+    // of it once for anything the reference wrote somewhere else
+    if let Some(reference) = reference.filter(|_| outcome != Outcome::Diverged) {
+        if let Some(divergence) = compare_all(simulator, &reference) {
+            eprintln!("ERROR: Diverged from the reference simulator by the end of the run:");
+            eprint!("{}", divergence);
+            outcome = Outcome::Diverged;
+        }
+    }
+
+    println!();
+    println!("Final registers:");
+    print!("{}", registers_text(simulator.registers()));
+    println!();
+    if options.lockstep {
+        println!(
+            "Lockstep: {} instructions checked against the reference simulator, {} it can't run skipped.",
+            steps - skipped,
+            skipped
+        );
+        println!();
+    }
+    if let Some(profile) = profile {
+        println!("{}", profile.report(PROFILE_HOT_SPOTS));
+    }
+    if let Some(coverage) = coverage {
+        if options.coverage {
+            println!("{}", coverage.annotated_listing(buf, base));
+        }
+        if let Some(path) = &options.lcov {
+            if let Err(err) = std::fs::write(path, coverage.lcov(name, buf, base)) {
+                eprintln!("ERROR: Unable to write {}: {}", path, err);
+            }
+        }
+    }
+
+    outcome
+}
+
+fn run_file(name: &str, buf: &[u8], options: &Options, dump_index: &mut u32) -> Outcome {
+    if options.show_clocks && !options.quiet {
+        println!("{}", CLOCKS_WARNING);
+    }
+
+    if !options.execute {
+        if !options.quiet {
+            println!("; {} disassembly:", name);
+            println!("bits 16");
+        }
+        return disassemble(buf, options);
+    }
+
+    if !options.quiet {
+        println!("--- {} execution ---", name);
+    }
+    // CS:IP is set before the trace starts, so that its initial registers
+    // match what the first instruction sees
+    let mut simulator = Simulator::with_hooks(WriteLog::default());
+    let registers = simulator.registers_mut();
+    registers.set(Register_cs, options.load_segment);
+    registers.set(Register_ip, options.load_offset);
+
+    let mut trace = None;
+    if let Some(path) = &options.trace {
+        let writer = File::create(path)
+            .and_then(|file| TraceWriter::new(BufWriter::new(file), simulator.registers()));
+        match writer {
+            Ok(writer) => trace = Some(writer),
+            Err(err) => eprintln!("ERROR: Unable to write {}: {}", path, err),
+        }
+    }
+
+    let outcome = execute(name, buf, options, &mut simulator, trace.as_mut());
+    if let Some(Err(err)) = trace.map(TraceWriter::finish) {
+        eprintln!("ERROR: Unable to write trace: {}", err);
+    }
+
+    if options.dump {
+        let dump_name = format!("sim86_memory_{}.data", dump_index);
+        if let Err(err) = std::fs::write(&dump_name, simulator.memory()) {
+            eprintln!("ERROR: Unable to write {}: {}", dump_name, err);
+        }
+        *dump_index += 1;
+    }
+
+    outcome
+}
+
+/// Disassembles or simulates each file with the options that come before it.
+pub fn run(args: &[String]) -> Outcome {
+    let mut options = Options::default();
+    let mut dump_index = 0u32;
+    let mut ran_any = false;
+    let mut outcome = Outcome::Finished;
+    let mut record = |result: Outcome| {
+        if outcome == Outcome::Finished {
+            outcome = result;
+        }
+    };
+
+    let mut args = args.iter().cloned();
+    while let Some(arg) = args.next() {
+        let (flag, inline_value) = match arg.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => {
+                (flag.to_string(), Some(value.to_string()))
+            }
+            _ => (arg.clone(), None),
+        };
+        let mut value = |name: &str| {
+            let value = inline_value.clone().or_else(|| args.next());
+            if value.is_none() {
+                eprintln!("ERROR: {} expects a value.", name);
+            }
+            value
+        };
+
+        match flag.as_str() {
+            "-exec" => options.execute = true,
+            "-disasm" => options.execute = false,
+            "-showclocks" => options.show_clocks = true,
+            "-explainclocks" => {
+                options.show_clocks = true;
+                options.explain_clocks = true;
+            }
+            "-8088" => options.timing.assume_8088 = true,
+            "-dump" => options.dump = true,
+            "-stoponret" => options.stop_on_ret = true,
+            "--quiet" => options.quiet = true,
+            "--profile" => options.profile = true,
+            "--coverage" => options.coverage = true,
+            "--lockstep" => options.lockstep = true,
+            "--recursive" => options.recursive = true,
+            "--labels" => {
+                options.recursive = true;
+                options.labels = true;
+            }
+            "--symbols" => {
+                let symbols = value("--symbols").map(|path| {
+                    std::fs::read_to_string(&path)
+                        .map_err(|err| err.to_string())
+                        .and_then(|text| parse_symbols(&text))
+                        .map_err(|err| format!("{}: {}", path, err))
+                });
+                match symbols {
+                    Some(Ok(symbols)) => {
+                        options.recursive = true;
+                        options.labels = true;
+                        options.symbols = symbols;
+                    }
+                    Some(Err(err)) => {
+                        eprintln!("ERROR: Unable to read symbols from {}", err);
+                        return Outcome::BadInput;
+                    }
+                    None => return usage_error(),
+                }
+            }
+            "--entry" => match value("--entry").as_deref().and_then(parse_number) {
+                Some(offset) => {
+                    options.recursive = true;
+                    options.entries.push(offset);
+                }
+                None => return usage_error(),
+            },
+            "--load-at" => match value("--load-at").as_deref().map(parse_load_at) {
+                Some(Some((segment, offset))) => {
+                    options.load_segment = segment;
+                    options.load_offset = offset;
+                }
+                _ => return usage_error(),
+            },
+            "--trace" => match value("--trace") {
+                Some(path) => options.trace = Some(path),
+                None => return usage_error(),
+            },
+            "--lcov" => match value("--lcov") {
+                Some(path) => options.lcov = Some(path),
+                None => return usage_error(),
+            },
+            "--max-steps" => match value("--max-steps").map(|steps| steps.parse::<u64>()) {
+                Some(Ok(steps)) => options.max_steps = Some(steps),
+                _ => return usage_error(),
+            },
+            "-h" | "-help" | "--help" => {
+                println!("{}", USAGE);
+                return Outcome::Finished;
+            }
+            _ if flag.starts_with('-') => {
+                eprintln!("ERROR: Unknown option {}.", arg);
+                return usage_error();
+            }
+            _ => {
+                ran_any = true;
+                match read_program(&arg) {
+                    Some(buf) => record(run_file(&arg, &buf, &options, &mut dump_index)),
+                    None => record(Outcome::BadInput),
+                }
+            }
+        }
+    }
+
+    if !ran_any {
+        let table = get_8086_instruction_table();
+        println!(
+            "8086 Instruction Instruction Encoding Count: {}",
+            table.EncodingCount
+        );
+        record(run_file(
+            "example",
+            &EXAMPLE_DISASSEMBLY,
+            &options,
+            &mut dump_index,
+        ));
+    }
+
+    outcome
+}
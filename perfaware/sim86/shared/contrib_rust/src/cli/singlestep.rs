@@ -0,0 +1,177 @@
+use std::path::{Path, PathBuf};
+
+use sim86_shared::singlestep::*;
+use sim86_shared::*;
+
+use super::{usage_error, Outcome};
+
+// How many failing cases of each opcode singlestep shows by default.
+const SINGLESTEP_FAILURES_SHOWN: usize = 3;
+
+/// Whether `path` is named like a suite file, "F6.json" or "F6.4.json".
+fn is_singlestep_file(path: &Path) -> bool {
+    let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+        return false;
+    };
+    let Some(stem) = name.strip_suffix(".json") else {
+        return false;
+    };
+    let (opcode, reg) = stem.split_once('.').unwrap_or((stem, "0"));
+    opcode.len() == 2
+        && u8::from_str_radix(opcode, 16).is_ok()
+        && reg.len() == 1
+        && reg.parse::<u8>().is_ok_and(|reg| reg < 8)
+}
+
+/// Runs single-step test suites and reports the results per opcode.
+pub fn run(args: &[String]) -> Outcome {
+    let mut masks = FlagMasks::default();
+    let mut failures_shown = SINGLESTEP_FAILURES_SHOWN;
+    let mut files = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--metadata" => {
+                let Some(path) = args.next() else {
+                    return usage_error();
+                };
+                let Ok(text) = std::fs::read_to_string(path) else {
+                    eprintln!("ERROR: Unable to open {}.", path);
+                    return Outcome::BadInput;
+                };
+                match FlagMasks::from_metadata(&text) {
+                    Ok(metadata) => masks = metadata,
+                    Err(err) => {
+                        eprintln!("ERROR: {}: {}", path, err);
+                        return Outcome::BadInput;
+                    }
+                }
+            }
+            "--failures" => match args.next().and_then(|count| count.parse().ok()) {
+                Some(count) => failures_shown = count,
+                None => return usage_error(),
+            },
+            _ if !arg.starts_with('-') => {
+                let path = Path::new(arg);
+                if !path.is_dir() {
+                    files.push(path.to_path_buf());
+                    continue;
+                }
+                let Ok(entries) = std::fs::read_dir(path) else {
+                    eprintln!("ERROR: Unable to open {}.", arg);
+                    return Outcome::BadInput;
+                };
+                let mut found: Vec<PathBuf> = entries
+                    .filter_map(|entry| Some(entry.ok()?.path()))
+                    .filter(|path| is_singlestep_file(path))
+                    .collect();
+                if found.is_empty() {
+                    eprintln!(
+                        "ERROR: No test files such as 00.json in {}; gunzip the suite first.",
+                        arg
+                    );
+                    return Outcome::BadInput;
+                }
+                found.sort();
+                files.extend(found);
+            }
+            _ => return usage_error(),
+        }
+    }
+    if files.is_empty() {
+        return usage_error();
+    }
+
+    let (mut passed, mut failed, mut unimplemented, mut undecodable) = (0, 0, 0, 0);
+    let mut failed_opcodes = 0;
+    for file in &files {
+        let name = file.display();
+        let Ok(text) = std::fs::read_to_string(file) else {
+            eprintln!("ERROR: Unable to open {}.", name);
+            return Outcome::BadInput;
+        };
+        let cases = match parse_tests(&text) {
+            Ok(cases) => cases,
+            Err(err) => {
+                eprintln!("ERROR: {}: {}", name, err);
+                return Outcome::BadInput;
+            }
+        };
+
+        let mnemonic = cases
+            .first()
+            .and_then(|case| decode_8086_instruction(&case.bytes).ok())
+            .map_or("?".into(), |inst| mnemonic_from_operation_type(inst.Op));
+        let (mut file_passed, mut file_unimplemented, mut file_undecodable) = (0, 0, 0);
+        let mut file_failures: Vec<(&TestCase, Vec<String>)> = Vec::new();
+        for case in &cases {
+            match run_case(case, masks.compared_flags(&case.bytes)) {
+                CaseResult::Passed => file_passed += 1,
+                CaseResult::Failed(mismatches) => {
+                    let lines = mismatches.iter().map(ToString::to_string).collect();
+                    file_failures.push((case, lines));
+                }
+                CaseResult::Error(err) => file_failures.push((case, vec![err.to_string()])),
+                CaseResult::Unimplemented => file_unimplemented += 1,
+                CaseResult::DecodeError => file_undecodable += 1,
+            }
+        }
+
+        let status = if !file_failures.is_empty() || file_undecodable > 0 {
+            "FAIL"
+        } else if file_passed == 0 {
+            "SKIP"
+        } else {
+            "ok"
+        };
+        print!(
+            "{:<4} {} ({}): {} passed, {} failed",
+            status,
+            file.file_name().unwrap_or_default().to_string_lossy(),
+            mnemonic,
+            file_passed,
+            file_failures.len()
+        );
+        if file_unimplemented > 0 {
+            print!(", {} unimplemented", file_unimplemented);
+        }
+        if file_undecodable > 0 {
+            print!(", {} don't decode", file_undecodable);
+        }
+        println!();
+        for (case, lines) in file_failures.iter().take(failures_shown) {
+            let bytes: Vec<String> = case
+                .bytes
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect();
+            println!("    {} [{}]", case.name, bytes.join(" "));
+            for line in lines {
+                println!("        {}", line);
+            }
+        }
+
+        if status == "FAIL" {
+            failed_opcodes += 1;
+        }
+        passed += file_passed;
+        failed += file_failures.len();
+        unimplemented += file_unimplemented;
+        undecodable += file_undecodable;
+    }
+
+    println!(
+        "\n{} passed, {} failed, {} unimplemented, {} don't decode; {} of {} opcodes failed.",
+        passed,
+        failed,
+        unimplemented,
+        undecodable,
+        failed_opcodes,
+        files.len()
+    );
+    if failed_opcodes == 0 {
+        Outcome::Finished
+    } else {
+        Outcome::Diverged
+    }
+}
@@ -0,0 +1,55 @@
+use sim86_shared::trace::*;
+
+use super::{usage_error, Outcome};
+
+fn read_trace_file(path: &str) -> Option<Vec<TraceRecord>> {
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(_) => {
+            eprintln!("ERROR: Unable to open {}.", path);
+            return None;
+        }
+    };
+    match read_trace(&bytes) {
+        Ok(records) => Some(records),
+        Err(err) => {
+            eprintln!("ERROR: {}: {}", path, err);
+            None
+        }
+    }
+}
+
+/// Compares two traces recorded with --trace.
+pub fn run(args: &[String]) -> Outcome {
+    let mut context = 5usize;
+    let mut paths = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--context" => match args.next().and_then(|count| count.parse().ok()) {
+                Some(count) => context = count,
+                None => return usage_error(),
+            },
+            _ if !arg.starts_with('-') => paths.push(arg.as_str()),
+            _ => return usage_error(),
+        }
+    }
+
+    let [a, b] = paths[..] else {
+        return usage_error();
+    };
+    let (Some(a), Some(b)) = (read_trace_file(a), read_trace_file(b)) else {
+        return Outcome::BadInput;
+    };
+
+    match first_divergence(&a, &b) {
+        Some(divergence) => {
+            print!("{}", divergence_text(&a, &b, &divergence, context));
+            Outcome::Diverged
+        }
+        None => {
+            println!("Traces match ({} instructions).", a.len());
+            Outcome::Finished
+        }
+    }
+}
@@ -0,0 +1,299 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::disasm::data_text;
+use crate::simulator::{ExecResult, MAX_INSTRUCTION_SIZE};
+use crate::text::instruction_text;
+use crate::*;
+
+/// How often one instruction address ran, and which ways it branched.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CoverageLine {
+    pub count: u64,
+    pub size: usize,
+    /// Conditional jumps, loops and jcxz can go either way.
+    pub conditional: bool,
+    pub taken: u64,
+    pub not_taken: u64,
+}
+
+impl CoverageLine {
+    pub fn went_both_ways(&self) -> bool {
+        self.taken > 0 && self.not_taken > 0
+    }
+}
+
+/// One line of the program listing that coverage is reported against.
+enum ListingLine {
+    Instruction {
+        address: usize,
+        inst: instruction,
+        line: Option<CoverageLine>,
+    },
+    /// Bytes that don't decode, or that lead into an executed instruction
+    /// from the middle of another one.
+    Data { address: usize, bytes: Vec<u8> },
+}
+
+/// Which instructions of a program ran and which branches went both ways,
+/// over one or more runs.
+#[derive(Debug, Default)]
+pub struct Coverage {
+    lines: BTreeMap<usize, CoverageLine>,
+}
+
+fn is_conditional_branch(address: usize, inst: &instruction) -> bool {
+    relative_jump_target(address, inst).is_some()
+        && !matches!(inst.Op, operation_type_Op_jmp | operation_type_Op_call)
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, address: usize, inst: &instruction, result: &ExecResult) {
+        let line = self.lines.entry(address).or_default();
+        line.count += 1;
+        line.size = inst.Size as usize;
+        line.conditional = is_conditional_branch(address, inst);
+        if line.conditional {
+            if result.branch_taken {
+                line.taken += 1;
+            } else {
+                line.not_taken += 1;
+            }
+        }
+    }
+
+    pub fn lines(&self) -> &BTreeMap<usize, CoverageLine> {
+        &self.lines
+    }
+
+    pub fn is_executed(&self, address: usize) -> bool {
+        // Only an instruction starting within the longest one's reach, prefixes
+        // and all, can cover it
+        self.lines
+            .range(address.saturating_sub(MAX_INSTRUCTION_SIZE - 1)..=address)
+            .any(|(start, line)| address < start + line.size)
+    }
+
+    /// Walks the program from `base`, preferring instruction boundaries that
+    /// were actually executed over the ones a linear decode would find.
+    fn listing(&self, program: &[u8], base: usize) -> Vec<ListingLine> {
+        let mut listing = Vec::new();
+        let mut at = 0usize;
+        while at < program.len() {
+            let address = base + at;
+            let decoded = decode_8086_instruction(&program[at..]).ok();
+            let next_executed = self
+                .lines
+                .range(address + 1..)
+                .next()
+                .map(|(start, _)| *start);
+
+            match decoded {
+                Some(inst)
+                    if self.lines.contains_key(&address)
+                        || next_executed
+                            .is_none_or(|next| next >= address + inst.Size as usize) =>
+                {
+                    listing.push(ListingLine::Instruction {
+                        address,
+                        inst,
+                        line: self.lines.get(&address).copied(),
+                    });
+                    at += inst.Size as usize;
+                }
+                _ => {
+                    let end = next_executed
+                        .map_or(program.len(), |next| next - base)
+                        .clamp(at + 1, program.len());
+                    listing.push(ListingLine::Data {
+                        address,
+                        bytes: program[at..end].to_vec(),
+                    });
+                    at = end;
+                }
+            }
+        }
+
+        listing
+    }
+
+    /// The program's disassembly with an execution count in front of each
+    /// instruction, "#####" for ones that never ran, and branch outcomes.
+    pub fn annotated_listing(&self, program: &[u8], base: usize) -> String {
+        let listing = self.listing(program, base);
+        let (mut instructions, mut hit) = (0, 0);
+        let (mut branches, mut both_ways) = (0, 0);
+        let mut body = String::new();
+        for entry in &listing {
+            match entry {
+                ListingLine::Instruction {
+                    address,
+                    inst,
+                    line,
+                } => {
+                    instructions += 1;
+                    let count = match line {
+                        Some(line) => {
+                            hit += 1;
+                            line.count.to_string()
+                        }
+                        None => "#####".to_string(),
+                    };
+                    let _ = write!(
+                        body,
+                        "{:>9}  0x{:05x}: {}",
+                        count,
+                        address,
+                        instruction_text(inst).trim_end()
+                    );
+                    branches += is_conditional_branch(*address, inst) as usize;
+                    if let Some(line) = line.filter(|line| line.conditional) {
+                        both_ways += line.went_both_ways() as usize;
+                        let _ = write!(
+                            body,
+                            " ; taken {}, not taken {}",
+                            line.taken, line.not_taken
+                        );
+                        if !line.went_both_ways() {
+                            body.push_str(" (one way only)");
+                        }
+                    }
+                    body.push('\n');
+                }
+                ListingLine::Data { address, bytes } => {
+                    let _ = writeln!(body, "{:>9}  0x{:05x}: {}", "", address, data_text(bytes));
+                }
+            }
+        }
+
+        let executed_bytes = (base..base + program.len())
+            .filter(|address| self.is_executed(*address))
+            .count();
+        format!(
+            "Coverage: {}/{} instructions, {}/{} bytes, {}/{} branches went both ways\n{}",
+            hit,
+            instructions,
+            executed_bytes,
+            program.len(),
+            both_ways,
+            branches,
+            body
+        )
+    }
+
+    /// An lcov tracefile for the program, named `source`. Line numbers count
+    /// the lines of `annotated_listing` after its summary line, so the two
+    /// can be read side by side.
+    pub fn lcov(&self, source: &str, program: &[u8], base: usize) -> String {
+        let mut text = format!("TN:\nSF:{}\n", source);
+        let (mut found, mut hit) = (0, 0);
+        let (mut branches, mut branches_hit) = (0, 0);
+        for (index, entry) in self.listing(program, base).iter().enumerate() {
+            let ListingLine::Instruction {
+                address,
+                inst,
+                line,
+            } = entry
+            else {
+                continue;
+            };
+            let number = index + 1;
+            found += 1;
+            hit += line.is_some() as usize;
+            let _ = writeln!(text, "DA:{},{}", number, line.map_or(0, |line| line.count));
+
+            if is_conditional_branch(*address, inst) {
+                let outcomes = line.map(|line| [line.taken, line.not_taken]);
+                for (branch, count) in [0, 1].iter().zip(outcomes.unwrap_or_default()) {
+                    branches += 1;
+                    branches_hit += (count > 0) as usize;
+                    let count = match outcomes {
+                        Some(_) => count.to_string(),
+                        None => "-".to_string(),
+                    };
+                    let _ = writeln!(text, "BRDA:{},0,{},{}", number, branch, count);
+                }
+            }
+        }
+
+        let _ = writeln!(text, "BRF:{}\nBRH:{}", branches, branches_hit);
+        let _ = writeln!(text, "LF:{}\nLH:{}", found, hit);
+        text.push_str("end_of_record\n");
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::Simulator;
+
+    // mov cx, 3 / dec cx / jne $-1 / hlt / jmp $-2 / db 0xff
+    const PROGRAM: [u8; 10] = [0xB9, 0x03, 0x00, 0x49, 0x75, 0xFD, 0xF4, 0xEB, 0xFE, 0xFF];
+
+    fn coverage_of(code: &[u8]) -> Coverage {
+        let mut simulator = Simulator::new();
+        let mut coverage = Coverage::new();
+        while !simulator.halted() {
+            let offset = simulator.registers().ip() as usize;
+            let inst = decode_8086_instruction(&code[offset..]).unwrap();
+            let result = simulator.execute_instruction(&inst).unwrap();
+            coverage.record(offset, &inst, &result);
+        }
+        coverage
+    }
+
+    #[test]
+    fn annotates_counts_and_branches() {
+        let coverage = coverage_of(&PROGRAM);
+        assert_eq!(3, coverage.lines()[&3].count);
+        assert!(coverage.lines()[&4].went_both_ways());
+        assert!(coverage.is_executed(5));
+        assert!(!coverage.is_executed(7));
+
+        let listing = coverage.annotated_listing(&PROGRAM, 0);
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!(
+            vec![
+                "Coverage: 4/5 instructions, 7/10 bytes, 1/1 branches went both ways",
+                "        1  0x00000: mov cx, 3",
+                "        3  0x00003: dec cx",
+                "        3  0x00004: jne $-1 ; taken 2, not taken 1",
+                "        1  0x00006: hlt",
+                "    #####  0x00007: jmp $+0",
+                "           0x00009: db 0xff",
+            ],
+            lines
+        );
+    }
+
+    #[test]
+    fn long_prefixed_instructions_cover_all_their_bytes() {
+        let mut coverage = Coverage::new();
+        coverage.lines.insert(
+            0x10,
+            CoverageLine {
+                count: 1,
+                size: MAX_INSTRUCTION_SIZE,
+                ..Default::default()
+            },
+        );
+        assert!(!coverage.is_executed(0x0F));
+        assert!(coverage.is_executed(0x10 + MAX_INSTRUCTION_SIZE - 1));
+        assert!(!coverage.is_executed(0x10 + MAX_INSTRUCTION_SIZE));
+    }
+
+    #[test]
+    fn writes_lcov() {
+        let coverage = coverage_of(&PROGRAM);
+        let lcov = coverage.lcov("countdown", &PROGRAM, 0);
+        assert!(lcov.starts_with("TN:\nSF:countdown\nDA:1,1\nDA:2,3\nDA:3,3\n"));
+        assert!(lcov.contains("BRDA:3,0,0,2\nBRDA:3,0,1,1\n"));
+        assert!(lcov.contains("DA:5,0\n"));
+        assert!(lcov.ends_with("BRF:2\nBRH:2\nLF:5\nLH:4\nend_of_record\n"));
+    }
+}
@@ -0,0 +1,551 @@
+use crate::simulator::ExecResult;
+use crate::*;
+
+// These are the numbers from the cycles table in the 8086 user's manual, the
+// same as the reference simulator reports. Some of the entries in that table
+// are very likely misprints, so don't treat them as ground truth.
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ClockInterval {
+    pub min: u32,
+    pub max: u32,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct InstructionTiming {
+    pub base: ClockInterval,
+    pub transfers: u32,
+    pub ea_clocks: u32,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TimingState {
+    pub assume_8088: bool,
+    pub assume_branch_taken: bool,
+    pub assume_address_unaligned: bool,
+    pub assume_rep_count: u32,
+    pub assume_shift_count: u32,
+}
+
+impl TimingState {
+    pub fn update_for_exec(&mut self, exec: &ExecResult) {
+        self.assume_branch_taken = exec.branch_taken;
+        self.assume_address_unaligned = exec.address_is_unaligned;
+        self.assume_rep_count = exec.rep_count;
+        self.assume_shift_count = exec.shift_count;
+    }
+}
+
+fn clock_range_transfers(min: u32, max: u32, transfers: u32, ea_clocks: u32) -> InstructionTiming {
+    InstructionTiming {
+        base: ClockInterval { min, max },
+        transfers,
+        ea_clocks,
+    }
+}
+
+fn clocks_transfers(clocks: u32, transfers: u32, ea_clocks: u32) -> InstructionTiming {
+    clock_range_transfers(clocks, clocks, transfers, ea_clocks)
+}
+
+fn ea_clocks_from(inst: &instruction, index: usize) -> u32 {
+    let address = unsafe { inst.Operands[index].__bindgen_anon_1.Address };
+    let t0 = address.Terms[0].Register.Index;
+    let t1 = address.Terms[1].Register.Index;
+
+    let mut clocks = match (t0, t1) {
+        (Register_none, _) => 2,
+        (_, Register_none) => 5,
+        (Register_bp, Register_di) | (Register_b, Register_si) => 7,
+        _ => 8,
+    };
+    if address.Displacement != 0 {
+        clocks += 4;
+    }
+    if inst.SegmentOverride != Register_none {
+        clocks += 2;
+    }
+
+    clocks
+}
+
+#[allow(non_upper_case_globals)]
+pub fn estimate_instruction_clocks(state: &TimingState, inst: &instruction) -> InstructionTiming {
+    let is = |index: usize, kind: operand_type| inst.Operands[index].Type == kind;
+    let register0 = is(0, operand_type_Operand_Register);
+    let register1 = is(1, operand_type_Operand_Register);
+    let memory0 = is(0, operand_type_Operand_Memory);
+    let memory1 = is(1, operand_type_Operand_Memory);
+    let immediate0 = is(0, operand_type_Operand_Immediate);
+    let immediate1 = is(1, operand_type_Operand_Immediate);
+
+    let far = (inst.Flags & instruction_flag_Inst_Far) != 0;
+    let wide = (inst.Flags & instruction_flag_Inst_Wide) != 0;
+
+    let ea = if memory0 {
+        ea_clocks_from(inst, 0)
+    } else if memory1 {
+        ea_clocks_from(inst, 1)
+    } else {
+        0
+    };
+
+    let taken = state.assume_branch_taken;
+    let rep = state.assume_rep_count;
+    let cl = state.assume_shift_count;
+
+    // The reference simulator never distinguishes the accumulator or segment
+    // register forms, so neither do we.
+    let used_accumulator = false;
+    let used_seg_reg = false;
+
+    let mut timing = InstructionTiming::default();
+    match inst.Op {
+        operation_type_Op_cbw
+        | operation_type_Op_clc
+        | operation_type_Op_cld
+        | operation_type_Op_cli
+        | operation_type_Op_cmc
+        | operation_type_Op_hlt
+        | operation_type_Op_lock
+        | operation_type_Op_rep
+        | operation_type_Op_stc
+        | operation_type_Op_std
+        | operation_type_Op_sti
+        | operation_type_Op_segment => timing = clocks_transfers(2, 0, 0),
+
+        operation_type_Op_aaa
+        | operation_type_Op_aas
+        | operation_type_Op_daa
+        | operation_type_Op_das
+        | operation_type_Op_lahf
+        | operation_type_Op_sahf => timing = clocks_transfers(4, 0, 0),
+
+        operation_type_Op_cwd => timing = clocks_transfers(5, 0, 0),
+        operation_type_Op_aad => timing = clocks_transfers(60, 0, 0),
+        operation_type_Op_aam => timing = clocks_transfers(83, 0, 0),
+
+        operation_type_Op_adc
+        | operation_type_Op_add
+        | operation_type_Op_and
+        | operation_type_Op_xor
+        | operation_type_Op_or
+        | operation_type_Op_sub
+        | operation_type_Op_sbb => {
+            if register0 && register1 {
+                timing = clocks_transfers(3, 0, 0);
+            }
+            if register0 && memory1 {
+                timing = clocks_transfers(9, 1, ea);
+            }
+            if memory0 && register1 {
+                timing = clocks_transfers(16, 2, ea);
+            }
+            if register0 && immediate1 {
+                timing = clocks_transfers(4, 0, 0);
+            }
+            if memory0 && immediate1 {
+                timing = clocks_transfers(17, 2, ea);
+            }
+        }
+
+        operation_type_Op_call => {
+            timing = if memory0 {
+                if far {
+                    clocks_transfers(37, 4, ea)
+                } else {
+                    clocks_transfers(21, 2, ea)
+                }
+            } else if register0 {
+                clocks_transfers(16, 1, 0)
+            } else if far {
+                clocks_transfers(28, 2, 0)
+            } else {
+                clocks_transfers(19, 1, 0)
+            };
+        }
+
+        operation_type_Op_cmp => {
+            if register0 && register1 {
+                timing = clocks_transfers(3, 0, 0);
+            }
+            if register0 && memory1 {
+                timing = clocks_transfers(9, 1, ea);
+            }
+            if memory0 && register1 {
+                timing = clocks_transfers(9, 1, ea);
+            }
+            if register0 && immediate1 {
+                timing = clocks_transfers(4, 0, 0);
+            }
+            if memory0 && immediate1 {
+                timing = clocks_transfers(10, 1, ea);
+            }
+        }
+
+        operation_type_Op_cmps => {
+            timing = if rep != 0 {
+                clocks_transfers(9 + 22 * rep, 2 * rep, 0)
+            } else {
+                clocks_transfers(22, 2, 0)
+            };
+        }
+
+        operation_type_Op_dec | operation_type_Op_inc => {
+            if register0 && !wide {
+                timing = clocks_transfers(3, 0, 0);
+            }
+            if register0 && wide {
+                timing = clocks_transfers(2, 0, 0);
+            }
+            if memory0 {
+                timing = clocks_transfers(15, 2, ea);
+            }
+        }
+
+        operation_type_Op_div => {
+            if register0 && !wide {
+                timing = clock_range_transfers(80, 90, 0, 0);
+            }
+            if register0 && wide {
+                timing = clock_range_transfers(144, 162, 0, 0);
+            }
+            if memory0 && !wide {
+                timing = clock_range_transfers(86, 96, 1, ea);
+            }
+            if memory0 && wide {
+                timing = clock_range_transfers(150, 168, 1, ea);
+            }
+        }
+
+        operation_type_Op_esc => {
+            if immediate0 && memory1 {
+                timing = clocks_transfers(8, 1, ea);
+            }
+            if immediate0 && register1 {
+                timing = clocks_transfers(2, 0, 0);
+            }
+        }
+
+        operation_type_Op_idiv => {
+            if register0 && !wide {
+                timing = clock_range_transfers(101, 112, 0, 0);
+            }
+            if register0 && wide {
+                timing = clock_range_transfers(165, 184, 0, 0);
+            }
+            if memory0 && !wide {
+                timing = clock_range_transfers(107, 118, 1, ea);
+            }
+            if memory0 && wide {
+                timing = clock_range_transfers(171, 190, 1, ea);
+            }
+        }
+
+        operation_type_Op_imul => {
+            if register0 && !wide {
+                timing = clock_range_transfers(80, 98, 0, 0);
+            }
+            if register0 && wide {
+                timing = clock_range_transfers(128, 154, 0, 0);
+            }
+            if memory0 && !wide {
+                timing = clock_range_transfers(86, 104, 1, ea);
+            }
+            if memory0 && wide {
+                timing = clock_range_transfers(134, 160, 1, ea);
+            }
+        }
+
+        operation_type_Op_in => {
+            if register0 && immediate1 {
+                timing = clocks_transfers(10, 1, 0);
+            }
+            if register0 && register1 {
+                timing = clocks_transfers(8, 1, 0);
+            }
+        }
+
+        operation_type_Op_int => {
+            let kind = unsafe { inst.Operands[0].__bindgen_anon_1.Immediate.Value };
+            timing = if kind == 3 {
+                clocks_transfers(52, 5, 0)
+            } else {
+                clocks_transfers(51, 5, 0)
+            };
+        }
+
+        operation_type_Op_int3 => timing = clocks_transfers(52, 5, 0),
+        operation_type_Op_into => timing = clock_range_transfers(4, 53, 5, 0),
+        operation_type_Op_iret => timing = clocks_transfers(24, 3, 0),
+
+        operation_type_Op_je
+        | operation_type_Op_jl
+        | operation_type_Op_jle
+        | operation_type_Op_jb
+        | operation_type_Op_jbe
+        | operation_type_Op_jp
+        | operation_type_Op_jo
+        | operation_type_Op_js
+        | operation_type_Op_jne
+        | operation_type_Op_jnl
+        | operation_type_Op_jg
+        | operation_type_Op_jnb
+        | operation_type_Op_ja
+        | operation_type_Op_jnp
+        | operation_type_Op_jno
+        | operation_type_Op_jns => timing = clocks_transfers(if taken { 16 } else { 4 }, 0, 0),
+
+        operation_type_Op_jcxz => timing = clocks_transfers(if taken { 18 } else { 6 }, 0, 0),
+
+        operation_type_Op_jmp => {
+            if memory0 && far {
+                timing = clocks_transfers(24, 2, ea);
+            }
+            if memory0 && !far {
+                timing = clocks_transfers(18, 1, ea);
+            }
+            if immediate0 {
+                timing = clocks_transfers(15, 0, 0);
+            }
+            if register0 {
+                timing = clocks_transfers(11, 0, 0);
+            }
+        }
+
+        operation_type_Op_lds => timing = clocks_transfers(16, 2, ea),
+        operation_type_Op_lea => timing = clocks_transfers(2, 0, ea),
+        operation_type_Op_les => timing = clocks_transfers(16, 2, ea),
+
+        operation_type_Op_lods => {
+            timing = if rep != 0 {
+                clocks_transfers(9 + 13 * rep, rep, 0)
+            } else {
+                clocks_transfers(12, 1, 0)
+            };
+        }
+
+        operation_type_Op_loop => timing = clocks_transfers(if taken { 17 } else { 5 }, 0, 0),
+        operation_type_Op_loopz => timing = clocks_transfers(if taken { 18 } else { 6 }, 0, 0),
+        operation_type_Op_loopnz => timing = clocks_transfers(if taken { 19 } else { 5 }, 0, 0),
+
+        operation_type_Op_mov => {
+            // The manual claims the accumulator forms skip the EA calculation,
+            // which is almost certainly a misprint.
+            if memory0 && register1 {
+                timing = if used_accumulator {
+                    clocks_transfers(10, 1, 0)
+                } else {
+                    clocks_transfers(9, 1, ea)
+                };
+            }
+            if register0 && memory1 {
+                timing = if used_accumulator {
+                    clocks_transfers(10, 1, 0)
+                } else {
+                    clocks_transfers(8, 1, ea)
+                };
+            }
+            if register0 && register1 {
+                timing = clocks_transfers(2, 0, 0);
+            }
+            if register0 && immediate1 {
+                timing = clocks_transfers(4, 0, 0);
+            }
+            if memory0 && immediate1 {
+                timing = clocks_transfers(10, 1, ea);
+            }
+        }
+
+        operation_type_Op_movs => {
+            timing = if rep != 0 {
+                clocks_transfers(9 + 17 * rep, 2 * rep, 0)
+            } else {
+                clocks_transfers(18, 2, 0)
+            };
+        }
+
+        operation_type_Op_mul => {
+            if register0 && !wide {
+                timing = clock_range_transfers(70, 77, 0, 0);
+            }
+            if register0 && wide {
+                timing = clock_range_transfers(118, 133, 0, 0);
+            }
+            if memory0 && !wide {
+                timing = clock_range_transfers(76, 83, 1, ea);
+            }
+            if memory0 && wide {
+                timing = clock_range_transfers(124, 139, 1, ea);
+            }
+        }
+
+        operation_type_Op_neg | operation_type_Op_not => {
+            if register0 {
+                timing = clocks_transfers(3, 0, 0);
+            }
+            if memory0 {
+                timing = clocks_transfers(16, 2, ea);
+            }
+        }
+
+        operation_type_Op_out => {
+            if immediate0 && register1 {
+                timing = clocks_transfers(10, 1, 0);
+            }
+            if register0 && register1 {
+                timing = clocks_transfers(8, 1, 0);
+            }
+        }
+
+        operation_type_Op_pop => {
+            if register0 {
+                timing = clocks_transfers(8, 1, 0);
+            }
+            if memory0 {
+                timing = clocks_transfers(17, 2, ea);
+            }
+        }
+
+        operation_type_Op_popf => timing = clocks_transfers(8, 1, 0),
+
+        operation_type_Op_push => {
+            if register0 {
+                timing = clocks_transfers(if used_seg_reg { 10 } else { 11 }, 1, 0);
+            }
+            if memory0 {
+                timing = clocks_transfers(16, 2, 0);
+            }
+        }
+
+        operation_type_Op_pushf => timing = clocks_transfers(10, 1, 0),
+
+        operation_type_Op_ret => timing = clocks_transfers(if immediate0 { 12 } else { 8 }, 1, 0),
+        operation_type_Op_retf => timing = clocks_transfers(if immediate0 { 17 } else { 18 }, 2, 0),
+
+        operation_type_Op_rcl
+        | operation_type_Op_rcr
+        | operation_type_Op_rol
+        | operation_type_Op_ror
+        | operation_type_Op_shl
+        | operation_type_Op_sar
+        | operation_type_Op_shr => {
+            if register0 && immediate1 {
+                timing = clocks_transfers(2, 0, 0);
+            }
+            if register0 && register1 {
+                timing = clocks_transfers(8 + 4 * cl, 0, 0);
+            }
+            if memory0 && immediate1 {
+                timing = clocks_transfers(15, 2, ea);
+            }
+            if memory0 && register1 {
+                timing = clocks_transfers(20 + 4 * cl, 2, ea);
+            }
+        }
+
+        operation_type_Op_scas => {
+            timing = if rep != 0 {
+                clocks_transfers(9 + 15 * rep, rep, 0)
+            } else {
+                clocks_transfers(15, 1, 0)
+            };
+        }
+
+        operation_type_Op_stos => {
+            timing = if rep != 0 {
+                clocks_transfers(9 + 10 * rep, rep, 0)
+            } else {
+                clocks_transfers(11, 1, 0)
+            };
+        }
+
+        operation_type_Op_test => {
+            if register0 && register1 {
+                timing = clocks_transfers(3, 0, 0);
+            }
+            if register0 && memory1 {
+                timing = clocks_transfers(9, 1, ea);
+            }
+            if register0 && immediate1 {
+                timing = clocks_transfers(if used_accumulator { 4 } else { 5 }, 0, 0);
+            }
+            if memory0 && immediate1 {
+                timing = clocks_transfers(11, 0, ea);
+            }
+        }
+
+        operation_type_Op_wait => timing = clocks_transfers(3 + 5 * rep, 0, 0),
+
+        operation_type_Op_xchg => {
+            if memory0 && register1 {
+                timing = clocks_transfers(17, 2, ea);
+            }
+            if register0 && register1 {
+                timing = clocks_transfers(if used_accumulator { 3 } else { 4 }, 0, 0);
+            }
+        }
+
+        operation_type_Op_xlat => timing = clocks_transfers(11, 1, 0),
+
+        _ => {}
+    }
+
+    timing
+}
+
+pub fn expected_clocks_from(
+    state: &TimingState,
+    inst: &instruction,
+    timing: &InstructionTiming,
+) -> ClockInterval {
+    let mut extra = timing.ea_clocks;
+    let wide = (inst.Flags & instruction_flag_Inst_Wide) != 0;
+    if wide && (state.assume_8088 || state.assume_address_unaligned) {
+        extra += 4 * timing.transfers;
+    }
+
+    ClockInterval {
+        min: timing.base.min + extra,
+        max: timing.base.max + extra,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clocks_for(state: &TimingState, code: &[u8]) -> ClockInterval {
+        let inst = decode_8086_instruction(code).unwrap();
+        let timing = estimate_instruction_clocks(state, &inst);
+        expected_clocks_from(state, &inst, &timing)
+    }
+
+    #[test]
+    fn effective_address_clocks() {
+        let state = TimingState::default();
+        // mov cx, [bp+di+1000]
+        assert_eq!(
+            ClockInterval { min: 19, max: 19 },
+            clocks_for(&state, &[0x8B, 0x8B, 0xE8, 0x03])
+        );
+        // mov dx, [1000]
+        assert_eq!(
+            ClockInterval { min: 14, max: 14 },
+            clocks_for(&state, &[0x8B, 0x16, 0xE8, 0x03])
+        );
+    }
+
+    #[test]
+    fn word_transfers_cost_more_on_8088() {
+        let state = TimingState {
+            assume_8088: true,
+            ..Default::default()
+        };
+        // add word [bp+si], 76
+        assert_eq!(
+            ClockInterval { min: 33, max: 33 },
+            clocks_for(&state, &[0x83, 0x02, 0x4C])
+        );
+    }
+}
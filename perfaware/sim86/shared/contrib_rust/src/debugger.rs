@@ -0,0 +1,970 @@
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::expr::Expression;
+use crate::simulator::{absolute_address, Hooks, Registers, SimError, Simulator, MEM_LEN};
+use crate::*;
+
+/// How many instructions a `Debugger` can step back over unless told
+/// otherwise, so that a long `continue` doesn't grow the undo log without
+/// bound.
+pub const DEFAULT_HISTORY_LIMIT: usize = 100_000;
+
+/// How many instructions `continue` and `next` run before giving up unless
+/// told otherwise, so that a program that never stops can't hang the session.
+pub const DEFAULT_STEP_LIMIT: u64 = 10_000_000;
+
+// How many instructions to run between checks for an interrupt.
+const INTERRUPT_POLL_INTERVAL: u64 = 4096;
+
+/// Why a `Debugger` stopped running the program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// A single step finished normally.
+    Step,
+    Breakpoint(usize),
+    Halted,
+    /// CS:IP is no longer inside the loaded program.
+    ProgramEnd,
+    DecodeError(usize),
+    Unimplemented(operation_type),
+    /// Code runs past the end of its segment at this address.
+    MemoryFault(usize),
+    StepLimit(u64),
+    /// The run was interrupted through `Debugger::interrupt_flag`.
+    Interrupted,
+    /// Running backwards reached the first recorded instruction.
+    HistoryStart,
+    /// The instruction just executed touched watched memory.
+    Watchpoint(WatchHit),
+}
+
+impl From<&SimError> for StopReason {
+    fn from(err: &SimError) -> Self {
+        match err {
+            SimError::DecodeError { address, .. } => StopReason::DecodeError(*address),
+            SimError::Unimplemented(op) => StopReason::Unimplemented(*op),
+            SimError::MemoryFault(address) => StopReason::MemoryFault(*address),
+            SimError::Halted => StopReason::Halted,
+            SimError::StepLimit(steps) => StopReason::StepLimit(*steps),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    /// Either a read or a write.
+    Access,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Watchpoint {
+    /// Absolute addresses watched.
+    pub range: Range<usize>,
+    pub kind: WatchKind,
+}
+
+/// The first access to watched memory made by an instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchHit {
+    /// Kind of the watchpoint that caught the access.
+    pub kind: WatchKind,
+    pub address: usize,
+    pub write: bool,
+    /// For a read, both are the value read.
+    pub old: u8,
+    pub new: u8,
+}
+
+/// The hooks a `Debugger` runs its simulator with, which catch accesses to
+/// watched memory.
+#[derive(Debug, Default)]
+pub struct Watchpoints {
+    watches: Vec<Watchpoint>,
+    hit: Option<WatchHit>,
+}
+
+impl Watchpoints {
+    fn check(&mut self, address: usize, write: bool, old: u8, new: u8) {
+        if self.hit.is_some() {
+            return;
+        }
+        let unwanted = if write {
+            WatchKind::Read
+        } else {
+            WatchKind::Write
+        };
+        self.hit = self
+            .watches
+            .iter()
+            .find(|watch| watch.kind != unwanted && watch.range.contains(&address))
+            .map(|watch| WatchHit {
+                kind: watch.kind,
+                address,
+                write,
+                old,
+                new,
+            });
+    }
+}
+
+impl Hooks for Watchpoints {
+    fn before_instruction(&mut self, _: &Registers, _: &instruction) {
+        self.hit = None;
+    }
+
+    fn memory_read(&mut self, address: usize, value: u8) {
+        self.check(address, false, value, value);
+    }
+
+    fn memory_write(&mut self, address: usize, old: u8, new: u8) {
+        self.check(address, true, old, new);
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Breakpoint {
+    /// Only stop when this holds.
+    pub condition: Option<Expression>,
+    /// Times execution reached the breakpoint with its condition holding.
+    pub hits: u64,
+    /// Hits still to pass over before stopping again.
+    pub ignore_count: u64,
+}
+
+/// A command typed at the debugger prompt, with its locations resolved.
+#[derive(Debug, Clone)]
+pub enum Command {
+    Step(u32),
+    Next,
+    Continue,
+    Back(u32),
+    ReverseContinue,
+    Writer(usize),
+    Break {
+        address: usize,
+        condition: Option<Expression>,
+    },
+    Ignore {
+        address: usize,
+        count: u64,
+    },
+    Delete(usize),
+    Watch {
+        range: Range<usize>,
+        kind: WatchKind,
+    },
+    Unwatch(usize),
+    Print(Expression),
+    Info,
+    Label {
+        name: String,
+        address: usize,
+    },
+    List(u32),
+    Registers,
+    Set {
+        index: register_index,
+        value: u16,
+    },
+    Examine {
+        address: usize,
+        count: usize,
+    },
+    Poke {
+        address: usize,
+        bytes: Vec<u8>,
+    },
+    Save(String),
+    Load(String),
+    /// Shows the recorded history, or limits it to this many instructions.
+    History(Option<usize>),
+    Help,
+    Quit,
+}
+
+/// Drives a `Simulator` one instruction at a time, with breakpoints,
+/// watchpoints and labels. The program is copied into simulated memory and
+/// decoded from there, so memory edits made while debugging are what
+/// actually runs. History is recorded from the start, so execution can also
+/// run backwards, as far back as `DEFAULT_HISTORY_LIMIT` instructions unless
+/// changed.
+pub struct Debugger {
+    simulator: Simulator<Watchpoints>,
+    segment: u16,
+    /// Offsets of the program within its segment.
+    program: Range<usize>,
+    breakpoints: BTreeMap<usize, Breakpoint>,
+    labels: BTreeMap<String, usize>,
+    step_limit: u64,
+    interrupt: Arc<AtomicBool>,
+}
+
+/// Parses a number the way NASM writes one, so that the debugger and the
+/// assembler agree: decimal, hex as 0x10, 10h or $10, or binary as 0b10. Hex
+/// after `$` or before `h` has to start with a digit, so a name like `ah`
+/// never reads as a number.
+pub fn parse_number<T: TryFrom<u64>>(text: &str) -> Option<T> {
+    let lower = text.to_ascii_lowercase();
+    let starts_with_digit = |digits: &str| digits.starts_with(|c: char| c.is_ascii_digit());
+    let value = if let Some(hex) = lower.strip_prefix("0x") {
+        u64::from_str_radix(hex, 16).ok()
+    } else if let Some(hex) = lower.strip_prefix('$').filter(|hex| starts_with_digit(hex)) {
+        u64::from_str_radix(hex, 16).ok()
+    } else if let Some(hex) = lower.strip_suffix('h').filter(|hex| starts_with_digit(hex)) {
+        u64::from_str_radix(hex, 16).ok()
+    } else if let Some(binary) = lower.strip_prefix("0b") {
+        u64::from_str_radix(binary, 2).ok()
+    } else {
+        lower.parse().ok()
+    };
+    value.and_then(|value| T::try_from(value).ok())
+}
+
+impl Debugger {
+    /// Loads `program` at SEGMENT:OFFSET and points CS:IP at its first byte.
+    /// Fails if the program doesn't fit in the segment.
+    pub fn new(program: &[u8], segment: u16, offset: u16) -> Result<Self, SimError> {
+        let mut simulator = Simulator::with_hooks(Watchpoints::default());
+        simulator.record_history(true);
+        simulator.limit_history(DEFAULT_HISTORY_LIMIT);
+        simulator.load(segment, offset, program)?;
+
+        let registers = simulator.registers_mut();
+        registers.set(Register_cs, segment);
+        registers.set(Register_ip, offset);
+
+        let start = offset as usize;
+        Ok(Self {
+            simulator,
+            segment,
+            program: start..start + program.len(),
+            breakpoints: BTreeMap::new(),
+            labels: BTreeMap::new(),
+            step_limit: DEFAULT_STEP_LIMIT,
+            interrupt: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    pub fn simulator(&self) -> &Simulator<Watchpoints> {
+        &self.simulator
+    }
+
+    pub fn simulator_mut(&mut self) -> &mut Simulator<Watchpoints> {
+        &mut self.simulator
+    }
+
+    /// Stops `continue` and `next` after this many instructions.
+    pub fn limit_steps(&mut self, limit: u64) {
+        self.step_limit = limit;
+    }
+
+    pub fn step_limit(&self) -> u64 {
+        self.step_limit
+    }
+
+    /// Setting this flag, from a signal handler or another thread, stops a
+    /// running `continue` or `next`. It is cleared when they start.
+    pub fn interrupt_flag(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.interrupt)
+    }
+
+    /// Absolute addresses occupied by the loaded program.
+    pub fn program(&self) -> Range<usize> {
+        let start = absolute_address(self.segment, self.program.start as u16);
+        start..start + self.program.len()
+    }
+
+    /// The offset into the program's segment of an absolute address inside
+    /// the program.
+    fn program_offset(&self, address: usize) -> Option<u16> {
+        let offset = (address + MEM_LEN - absolute_address(self.segment, 0)) % MEM_LEN;
+        self.program.contains(&offset).then_some(offset as u16)
+    }
+
+    /// Whether CS:IP is inside the loaded program.
+    fn running_program(&self) -> bool {
+        let registers = self.simulator.registers();
+        registers.cs() == self.segment && self.program.contains(&(registers.ip() as usize))
+    }
+
+    /// Decodes the instruction at an absolute address inside the program,
+    /// reading it as the simulator would fetch it from there.
+    pub fn instruction_at(&self, address: usize) -> Option<instruction> {
+        let offset = self.program_offset(address)?;
+        self.simulator.decode_at(self.segment, offset).ok()
+    }
+
+    /// Executes the instruction at CS:IP.
+    pub fn step(&mut self) -> StopReason {
+        if self.simulator.halted() {
+            return StopReason::Halted;
+        }
+        if !self.running_program() {
+            return StopReason::ProgramEnd;
+        }
+
+        let executed = self
+            .simulator
+            .fetch_instruction()
+            .and_then(|inst| self.simulator.execute_instruction(&inst));
+        if let Err(err) = executed {
+            return StopReason::from(&err);
+        }
+
+        if let Some(hit) = self.simulator.hooks_mut().hit.take() {
+            StopReason::Watchpoint(hit)
+        } else if self.simulator.halted() {
+            StopReason::Halted
+        } else if !self.running_program() {
+            StopReason::ProgramEnd
+        } else {
+            StopReason::Step
+        }
+    }
+
+    /// Like `step`, but runs a call through to its return.
+    pub fn step_over(&mut self) -> StopReason {
+        let address = self.simulator.instruction_address();
+        let Some(inst) = self
+            .instruction_at(address)
+            .filter(|inst| inst.Op == operation_type_Op_call)
+        else {
+            return self.step();
+        };
+
+        // Checking sp as well keeps a recursive call from stopping early
+        let return_address = address + inst.Size as usize;
+        let sp = self.simulator.registers().get(Register_sp);
+        self.run(|debugger, here| {
+            let sp_now = debugger.simulator.registers().get(Register_sp);
+            let unwound = sp_now.wrapping_sub(sp) < 0x8000;
+            (here == return_address && unwound).then_some(StopReason::Step)
+        })
+    }
+
+    /// Runs until a breakpoint is reached or the program stops.
+    pub fn continue_execution(&mut self) -> StopReason {
+        self.run(|_, _| None)
+    }
+
+    /// Steps until `stop` returns a reason to stop at the instruction about
+    /// to run, a step doesn't finish normally, a breakpoint is reached, the
+    /// step limit runs out or the run is interrupted.
+    fn run(&mut self, mut stop: impl FnMut(&Self, usize) -> Option<StopReason>) -> StopReason {
+        self.interrupt.store(false, Ordering::Relaxed);
+        let mut steps = 0u64;
+        loop {
+            let reason = self.step();
+            if reason != StopReason::Step {
+                return reason;
+            }
+
+            let here = self.simulator.instruction_address();
+            if let Some(reason) = stop(self, here) {
+                return reason;
+            }
+            if self.stops_at(here) {
+                return StopReason::Breakpoint(here);
+            }
+
+            steps += 1;
+            if steps >= self.step_limit {
+                return StopReason::StepLimit(steps);
+            }
+            if steps.is_multiple_of(INTERRUPT_POLL_INTERVAL)
+                && self.interrupt.swap(false, Ordering::Relaxed)
+            {
+                return StopReason::Interrupted;
+            }
+        }
+    }
+
+    /// Undoes the last executed instruction.
+    pub fn step_back(&mut self) -> StopReason {
+        if self.simulator.step_back() {
+            StopReason::Step
+        } else {
+            StopReason::HistoryStart
+        }
+    }
+
+    /// Runs backwards until a breakpoint is reached or the history runs out.
+    /// Watchpoints only apply going forwards.
+    pub fn reverse_continue(&mut self) -> StopReason {
+        loop {
+            if !self.simulator.step_back() {
+                return StopReason::HistoryStart;
+            }
+
+            let here = self.simulator.instruction_address();
+            if self.stops_at(here) {
+                return StopReason::Breakpoint(here);
+            }
+        }
+    }
+
+    /// Whether a breakpoint at `address` stops execution there now. Counts a
+    /// hit when its condition holds.
+    pub fn stops_at(&mut self, address: usize) -> bool {
+        let Some(breakpoint) = self.breakpoints.get_mut(&address) else {
+            return false;
+        };
+        if let Some(condition) = &breakpoint.condition {
+            if !condition.is_true(&self.simulator) {
+                return false;
+            }
+        }
+
+        breakpoint.hits += 1;
+        if breakpoint.ignore_count > 0 {
+            breakpoint.ignore_count -= 1;
+            return false;
+        }
+        true
+    }
+
+    /// Returns false if there was already a breakpoint there.
+    pub fn add_breakpoint(&mut self, address: usize) -> bool {
+        let added = !self.breakpoints.contains_key(&address);
+        self.breakpoints.entry(address).or_default();
+        added
+    }
+
+    pub fn remove_breakpoint(&mut self, address: usize) -> bool {
+        self.breakpoints.remove(&address).is_some()
+    }
+
+    pub fn breakpoints(&self) -> &BTreeMap<usize, Breakpoint> {
+        &self.breakpoints
+    }
+
+    /// For setting a breakpoint's condition or ignore count.
+    pub fn breakpoint_mut(&mut self, address: usize) -> Option<&mut Breakpoint> {
+        self.breakpoints.get_mut(&address)
+    }
+
+    pub fn add_watchpoint(&mut self, range: Range<usize>, kind: WatchKind) {
+        let watches = &mut self.simulator.hooks_mut().watches;
+        watches.retain(|watch| watch.range != range || watch.kind != kind);
+        watches.push(Watchpoint { range, kind });
+    }
+
+    /// Removes every watchpoint starting at `address`. Returns false if there
+    /// weren't any.
+    pub fn remove_watchpoint(&mut self, address: usize) -> bool {
+        let watches = &mut self.simulator.hooks_mut().watches;
+        let count = watches.len();
+        watches.retain(|watch| watch.range.start != address);
+        watches.len() != count
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.simulator.hooks().watches
+    }
+
+    pub fn define_label(&mut self, name: &str, address: usize) {
+        self.labels.insert(name.to_string(), address);
+    }
+
+    pub fn labels(&self) -> &BTreeMap<String, usize> {
+        &self.labels
+    }
+
+    pub fn label_at(&self, address: usize) -> Option<&str> {
+        self.labels
+            .iter()
+            .find(|(_, at)| **at == address)
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// Resolves a label, a SEGMENT:OFFSET pair in hex, or an absolute address
+    /// in any form `parse_number` takes.
+    pub fn resolve_location(&self, text: &str) -> Option<usize> {
+        if let Some(address) = self.labels.get(text) {
+            return Some(*address);
+        }
+
+        if let Some((segment, offset)) = text.split_once(':') {
+            let parse = |part: &str| {
+                let part = part.trim_start_matches("0x").trim_start_matches("0X");
+                u16::from_str_radix(part, 16).ok()
+            };
+            return Some(absolute_address(parse(segment)?, parse(offset)?));
+        }
+
+        parse_number(text).filter(|address| *address < MEM_LEN)
+    }
+
+    /// Decodes up to `before` instructions leading up to `address`, the one at
+    /// `address`, and up to `after` following it.
+    pub fn disassemble_around(
+        &self,
+        address: usize,
+        before: usize,
+        after: usize,
+    ) -> Vec<(usize, instruction)> {
+        // 8086 code can't be decoded backwards, so find the instruction
+        // boundaries by walking forward from the start of the program
+        let mut boundaries = Vec::new();
+        let mut at = self.program().start;
+        while at < address {
+            let Some(inst) = self.instruction_at(at) else {
+                break;
+            };
+            boundaries.push(at);
+            at += inst.Size as usize;
+        }
+
+        let (mut at, mut count) = if at == address {
+            let first = boundaries.len().saturating_sub(before);
+            let start = boundaries.get(first).copied().unwrap_or(address);
+            (start, boundaries.len() - first + after + 1)
+        } else {
+            (address, after + 1)
+        };
+
+        let mut listing = Vec::new();
+        while count > 0 {
+            let Some(inst) = self.instruction_at(at) else {
+                break;
+            };
+            listing.push((at, inst));
+            at += inst.Size as usize;
+            count -= 1;
+        }
+
+        listing
+    }
+
+    /// Parses a command typed at the prompt, split into words. The error is a
+    /// message for the user.
+    pub fn parse_command(&self, command: &str, args: &[&str]) -> Result<Command, String> {
+        let location = |index: usize| -> Result<usize, String> {
+            let text = args
+                .get(index)
+                .ok_or_else(|| format!("{} expects a location", command))?;
+            self.resolve_location(text)
+                .ok_or_else(|| format!("Unknown location {}", text))
+        };
+        let number = |index: usize, default: u32| -> Result<u32, String> {
+            match args.get(index) {
+                Some(arg) => parse_number(arg).ok_or_else(|| format!("Bad number {}", arg)),
+                None => Ok(default),
+            }
+        };
+
+        let parsed = match command {
+            "s" | "step" => Command::Step(number(0, 1)?.max(1)),
+            "n" | "next" => Command::Next,
+            "c" | "continue" => Command::Continue,
+            "back" => Command::Back(number(0, 1)?.max(1)),
+            "rc" | "reverse-continue" => Command::ReverseContinue,
+            "writer" => Command::Writer(location(0)?),
+            "b" | "break" => {
+                let condition = match args.get(1) {
+                    Some(&"if") => Some(
+                        Expression::parse(&args[2..].join(" "))
+                            .map_err(|err| format!("Bad condition: {}", err))?,
+                    ),
+                    Some(_) => {
+                        return Err(
+                            "break expects a location, then optionally \"if\" and a condition"
+                                .to_string(),
+                        )
+                    }
+                    None => None,
+                };
+                Command::Break {
+                    address: location(0)?,
+                    condition,
+                }
+            }
+            "ignore" => {
+                let address = location(0)?;
+                match args.get(1).and_then(|arg| parse_number(arg)) {
+                    Some(count) => Command::Ignore { address, count },
+                    None => return Err("ignore expects a location and a count".to_string()),
+                }
+            }
+            "d" | "delete" => Command::Delete(location(0)?),
+            "watch" | "rwatch" | "awatch" => {
+                let kind = match command {
+                    "watch" => WatchKind::Write,
+                    "rwatch" => WatchKind::Read,
+                    _ => WatchKind::Access,
+                };
+                let address = location(0)?;
+                let end = (address + number(1, 1)?.max(1) as usize).min(MEM_LEN);
+                Command::Watch {
+                    range: address..end,
+                    kind,
+                }
+            }
+            "unwatch" => Command::Unwatch(location(0)?),
+            "p" | "print" => Command::Print(Expression::parse(&args.join(" "))?),
+            "i" | "info" => Command::Info,
+            "label" => {
+                let name = args.first().ok_or("label expects a name")?;
+                let address = match args.get(1) {
+                    Some(_) => location(1)?,
+                    None => self.simulator.instruction_address(),
+                };
+                Command::Label {
+                    name: name.to_string(),
+                    address,
+                }
+            }
+            "l" | "list" => Command::List(number(0, 5)?),
+            "r" | "regs" => Command::Registers,
+            "set" => {
+                let index = args.first().and_then(|name| register_index_from_name(name));
+                let value = args.get(1).and_then(|arg| parse_number::<u32>(arg));
+                match (index, value) {
+                    (Some(index), Some(value)) => Command::Set {
+                        index,
+                        value: value as u16,
+                    },
+                    _ => return Err("set expects a register and a value".to_string()),
+                }
+            }
+            "x" => Command::Examine {
+                address: location(0)?,
+                count: number(1, 64)? as usize,
+            },
+            "poke" => {
+                let bytes: Option<Vec<u8>> =
+                    args.iter().skip(1).map(|arg| parse_number(arg)).collect();
+                match (location(0), bytes) {
+                    (Ok(address), Some(bytes)) if !bytes.is_empty() => {
+                        Command::Poke { address, bytes }
+                    }
+                    (Err(err), _) if !args.is_empty() => return Err(err),
+                    _ => return Err("poke expects a location and bytes".to_string()),
+                }
+            }
+            "save" => Command::Save(args.first().ok_or("save expects a file name")?.to_string()),
+            "load" => Command::Load(args.first().ok_or("load expects a file name")?.to_string()),
+            "history" => Command::History(match args.first() {
+                Some(_) => Some(number(0, 0)? as usize),
+                None => None,
+            }),
+            "h" | "help" => Command::Help,
+            "q" | "quit" => Command::Quit,
+            _ => {
+                return Err(format!(
+                    "Unknown command {}. Type \"help\" for a list",
+                    command
+                ))
+            }
+        };
+
+        Ok(parsed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // mov cx, 3 / dec cx / jne $-1 / hlt
+    const COUNTDOWN: [u8; 7] = [0xB9, 0x03, 0x00, 0x49, 0x75, 0xFD, 0xF4];
+
+    #[test]
+    fn continue_stops_at_breakpoints() {
+        let mut debugger = Debugger::new(&COUNTDOWN, 0, 0).unwrap();
+        debugger.define_label("again", 3);
+        let again = debugger.resolve_location("again").unwrap();
+        assert!(debugger.add_breakpoint(again));
+
+        assert_eq!(StopReason::Breakpoint(3), debugger.continue_execution());
+        assert_eq!(3, debugger.simulator().registers().get(Register_c));
+        assert_eq!(StopReason::Breakpoint(3), debugger.continue_execution());
+        assert_eq!(2, debugger.simulator().registers().get(Register_c));
+
+        debugger.remove_breakpoint(again);
+        assert_eq!(StopReason::Halted, debugger.continue_execution());
+        assert_eq!(0, debugger.simulator().registers().get(Register_c));
+    }
+
+    #[test]
+    fn reverse_continue_stops_at_breakpoints() {
+        let mut debugger = Debugger::new(&COUNTDOWN, 0, 0).unwrap();
+        assert_eq!(StopReason::Halted, debugger.continue_execution());
+
+        debugger.add_breakpoint(3);
+        assert_eq!(StopReason::Breakpoint(3), debugger.reverse_continue());
+        assert_eq!(1, debugger.simulator().registers().get(Register_c));
+        assert_eq!(StopReason::Step, debugger.step_back());
+        assert_eq!(StopReason::Breakpoint(3), debugger.reverse_continue());
+        assert_eq!(2, debugger.simulator().registers().get(Register_c));
+
+        debugger.remove_breakpoint(3);
+        assert_eq!(StopReason::HistoryStart, debugger.reverse_continue());
+        assert_eq!(0, debugger.simulator().registers().get(Register_c));
+        assert!(!debugger.simulator().halted());
+    }
+
+    #[test]
+    fn step_over_runs_calls_to_completion() {
+        // call $+4 / hlt / inc ax / ret
+        let mut debugger = Debugger::new(&[0xE8, 0x01, 0x00, 0xF4, 0x40, 0xC3], 0, 0).unwrap();
+        assert_eq!(StopReason::Step, debugger.step_over());
+        assert_eq!(3, debugger.simulator().instruction_address());
+        assert_eq!(1, debugger.simulator().registers().get(Register_a));
+    }
+
+    #[test]
+    fn memory_edits_change_what_runs() {
+        let mut debugger = Debugger::new(&COUNTDOWN, 0x10, 0).unwrap();
+        assert_eq!(Some(0x100), debugger.resolve_location("10:0"));
+        debugger.simulator_mut().memory_mut()[0x101] = 0x01;
+        debugger.step();
+        assert_eq!(1, debugger.simulator().registers().get(Register_c));
+    }
+
+    #[test]
+    fn programs_stay_inside_their_segment() {
+        assert_eq!(
+            Some(SimError::MemoryFault(0x20000)),
+            Debugger::new(&COUNTDOWN, 0x1000, 0xFFFE).err()
+        );
+
+        // FFFF:0010 is address 0 again, but CS:IP still has to match
+        let mut debugger = Debugger::new(&COUNTDOWN, 0xFFFF, 0x0010).unwrap();
+        assert_eq!(0..7, debugger.program());
+        assert_eq!(
+            Some(operation_type_Op_dec),
+            debugger.instruction_at(3).map(|inst| inst.Op)
+        );
+        assert_eq!(StopReason::Halted, debugger.continue_execution());
+
+        let mut debugger = Debugger::new(&COUNTDOWN, 0xFFFF, 0x0010).unwrap();
+        debugger.simulator_mut().registers_mut().set(Register_cs, 0);
+        debugger.simulator_mut().registers_mut().set(Register_ip, 0);
+        assert_eq!(StopReason::ProgramEnd, debugger.step());
+    }
+
+    #[test]
+    fn disassembles_around_an_address() {
+        let debugger = Debugger::new(&COUNTDOWN, 0, 0).unwrap();
+        let addresses: Vec<usize> = debugger
+            .disassemble_around(4, 1, 5)
+            .iter()
+            .map(|(address, _)| *address)
+            .collect();
+        assert_eq!(vec![3, 4, 6], addresses);
+    }
+
+    #[test]
+    fn conditions_and_ignore_counts() {
+        let mut debugger = Debugger::new(&COUNTDOWN, 0, 0).unwrap();
+        debugger.add_breakpoint(3);
+        debugger.breakpoint_mut(3).unwrap().condition = Some(Expression::parse("cx < 3").unwrap());
+        assert_eq!(StopReason::Breakpoint(3), debugger.continue_execution());
+        assert_eq!(2, debugger.simulator().registers().get(Register_c));
+
+        debugger.breakpoint_mut(3).unwrap().ignore_count = 1;
+        assert_eq!(StopReason::Halted, debugger.continue_execution());
+        assert_eq!(2, debugger.breakpoints()[&3].hits);
+    }
+
+    #[test]
+    fn watchpoints_stop_after_the_access() {
+        // mov bx, 0x20 / mov byte [bx], 1 / mov al, [bx+1] / hlt
+        let program = [0xBB, 0x20, 0x00, 0xC6, 0x07, 0x01, 0x8A, 0x47, 0x01, 0xF4];
+        let mut debugger = Debugger::new(&program, 0, 0).unwrap();
+        debugger.add_watchpoint(0x20..0x22, WatchKind::Read);
+        debugger.add_watchpoint(0x20..0x21, WatchKind::Write);
+
+        let reason = debugger.continue_execution();
+        let StopReason::Watchpoint(hit) = reason else {
+            panic!("expected a watchpoint, got {:?}", reason);
+        };
+        assert_eq!(
+            (WatchKind::Write, 0x20, 0, 1),
+            (hit.kind, hit.address, hit.old, hit.new)
+        );
+        assert_eq!(6, debugger.simulator().instruction_address());
+
+        let reason = debugger.continue_execution();
+        assert!(matches!(
+            reason,
+            StopReason::Watchpoint(WatchHit {
+                kind: WatchKind::Read,
+                address: 0x21,
+                ..
+            })
+        ));
+
+        assert!(debugger.remove_watchpoint(0x20));
+        assert!(debugger.watchpoints().is_empty());
+        assert_eq!(StopReason::Halted, debugger.continue_execution());
+    }
+
+    #[test]
+    fn errors_keep_their_kind() {
+        assert_eq!(StopReason::Halted, StopReason::from(&SimError::Halted));
+        assert_eq!(
+            StopReason::MemoryFault(MEM_LEN),
+            StopReason::from(&SimError::MemoryFault(MEM_LEN))
+        );
+
+        // in with no device attached
+        let mut debugger = Debugger::new(&[0xE4, 0x60], 0, 0).unwrap();
+        assert_eq!(
+            StopReason::Unimplemented(operation_type_Op_in),
+            debugger.step()
+        );
+    }
+
+    #[test]
+    fn parses_numbers_as_nasm_does() {
+        for text in ["16", "0x10", "0X10", "10h", "10H", "$10", "0b10000"] {
+            assert_eq!(Some(16u32), parse_number(text), "{}", text);
+        }
+        assert_eq!(Some(0xBu8), parse_number("0bh"));
+        assert_eq!(None, parse_number::<u32>("ah"));
+        assert_eq!(None, parse_number::<u32>("$"));
+        assert_eq!(None, parse_number::<u8>("0x100"));
+    }
+
+    fn parse(debugger: &Debugger, line: &str) -> Result<Command, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        debugger.parse_command(words[0], &words[1..])
+    }
+
+    #[test]
+    fn parses_commands_with_their_defaults() {
+        let mut debugger = Debugger::new(&COUNTDOWN, 0, 0).unwrap();
+        debugger.define_label("again", 3);
+
+        assert!(matches!(parse(&debugger, "s"), Ok(Command::Step(1))));
+        assert!(matches!(
+            parse(&debugger, "step 0x10"),
+            Ok(Command::Step(16))
+        ));
+        assert!(matches!(parse(&debugger, "back 0"), Ok(Command::Back(1))));
+        assert!(matches!(parse(&debugger, "l"), Ok(Command::List(5))));
+        assert!(matches!(
+            parse(&debugger, "x again"),
+            Ok(Command::Examine {
+                address: 3,
+                count: 64
+            })
+        ));
+        assert!(matches!(
+            parse(&debugger, "awatch 10:2 4"),
+            Ok(Command::Watch { range, kind: WatchKind::Access }) if range == (0x102..0x106)
+        ));
+        assert!(matches!(
+            parse(&debugger, "label top"),
+            Ok(Command::Label { name, address: 0 }) if name == "top"
+        ));
+        assert!(matches!(
+            parse(&debugger, "set cx 0x10"),
+            Ok(Command::Set {
+                index: Register_c,
+                value: 16
+            })
+        ));
+        assert!(matches!(
+            parse(&debugger, "poke 0x20 1 0xff"),
+            Ok(Command::Poke { address: 0x20, bytes }) if bytes == [1, 0xFF]
+        ));
+        assert!(matches!(
+            parse(&debugger, "history"),
+            Ok(Command::History(None))
+        ));
+        assert!(matches!(
+            parse(&debugger, "history 10"),
+            Ok(Command::History(Some(10)))
+        ));
+        assert!(matches!(parse(&debugger, "q"), Ok(Command::Quit)));
+    }
+
+    #[test]
+    fn parses_breakpoint_conditions() {
+        let debugger = Debugger::new(&COUNTDOWN, 0, 0).unwrap();
+        let Ok(Command::Break {
+            address: 3,
+            condition: Some(condition),
+        }) = parse(&debugger, "b 3 if cx < 3")
+        else {
+            panic!("expected a conditional breakpoint");
+        };
+        assert!(condition.is_true(debugger.simulator()));
+
+        assert!(matches!(
+            parse(&debugger, "break 3"),
+            Ok(Command::Break {
+                condition: None,
+                ..
+            })
+        ));
+        assert!(parse(&debugger, "break 3 cx").is_err());
+        assert!(parse(&debugger, "break 3 if cx <").is_err());
+    }
+
+    #[test]
+    fn rejects_bad_commands() {
+        let debugger = Debugger::new(&COUNTDOWN, 0, 0).unwrap();
+        assert_eq!(
+            Err("Unknown location nowhere".to_string()),
+            parse(&debugger, "writer nowhere").map(|_| ())
+        );
+        assert_eq!(
+            Err("delete expects a location".to_string()),
+            parse(&debugger, "delete").map(|_| ())
+        );
+        assert!(parse(&debugger, "step many").is_err());
+        assert!(parse(&debugger, "ignore 3").is_err());
+        assert!(parse(&debugger, "set xx 1").is_err());
+        assert!(parse(&debugger, "poke 0x20 0x100").is_err());
+        assert!(parse(&debugger, "save").is_err());
+        assert!(parse(&debugger, "frobnicate").is_err());
+    }
+
+    #[test]
+    fn endless_loops_stop_at_the_step_limit() {
+        // jmp $
+        let mut debugger = Debugger::new(&[0xEB, 0xFE], 0, 0).unwrap();
+        assert_eq!(DEFAULT_STEP_LIMIT, debugger.step_limit());
+        debugger.limit_steps(1000);
+        assert_eq!(StopReason::StepLimit(1000), debugger.continue_execution());
+        assert_eq!(0, debugger.simulator().instruction_address());
+
+        // call $ never returns
+        let mut debugger = Debugger::new(&[0xE8, 0xFD, 0xFF], 0, 0).unwrap();
+        debugger.limit_steps(1000);
+        assert_eq!(StopReason::StepLimit(1000), debugger.step_over());
+    }
+
+    #[test]
+    fn interrupts_stop_a_continue() {
+        let mut debugger = Debugger::new(&[0xEB, 0xFE], 0, 0).unwrap();
+        debugger.limit_steps(u64::MAX);
+
+        // Set from another thread, the way a Ctrl-C handler would
+        let interrupt = debugger.interrupt_flag();
+        let interrupter = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            interrupt.store(true, Ordering::Relaxed);
+        });
+        assert_eq!(StopReason::Interrupted, debugger.continue_execution());
+        interrupter.join().unwrap();
+    }
+
+    #[test]
+    fn history_is_limited_by_default() {
+        let mut debugger = Debugger::new(&[0xEB, 0xFE], 0, 0).unwrap();
+        for _ in 0..DEFAULT_HISTORY_LIMIT + 10 {
+            debugger.step();
+        }
+        assert_eq!(DEFAULT_HISTORY_LIMIT, debugger.simulator().history_len());
+    }
+}
@@ -0,0 +1,277 @@
+use std::collections::BTreeMap;
+
+use crate::cfg::ControlFlowGraph;
+use crate::debugger::parse_number;
+use crate::text::instruction_text;
+use crate::*;
+
+// Keeps runs of data readable and well under NASM's line length limits.
+const DATA_BYTES_PER_LINE: usize = 16;
+
+/// One line of a recursive-descent disassembly.
+#[derive(Clone)]
+pub enum DisassemblyLine {
+    Instruction {
+        address: usize,
+        inst: instruction,
+    },
+    /// Bytes that no path from an entry point decodes as code.
+    Data {
+        address: usize,
+        bytes: Vec<u8>,
+    },
+}
+
+impl DisassemblyLine {
+    pub fn address(&self) -> usize {
+        match self {
+            DisassemblyLine::Instruction { address, .. } => *address,
+            DisassemblyLine::Data { address, .. } => *address,
+        }
+    }
+}
+
+/// The text of a `db` line for `bytes`, in a form NASM reassembles as is.
+pub fn data_text(bytes: &[u8]) -> String {
+    let bytes: Vec<String> = bytes.iter().map(|byte| format!("0x{:02x}", byte)).collect();
+    format!("db {}", bytes.join(", "))
+}
+
+/// Reads a symbol file: one `NAME ADDRESS` pair per line, with the address
+/// in any form `debugger::parse_number` takes. Blank lines and `;` comments
+/// are skipped.
+pub fn parse_symbols(text: &str) -> Result<Vec<(String, usize)>, String> {
+    let mut symbols = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.split(';').next().unwrap_or_default();
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words[..] {
+            [] => {}
+            [name, address] => match parse_number(address) {
+                Some(address) => symbols.push((name.to_string(), address)),
+                None => return Err(format!("line {}: bad address {}", index + 1, address)),
+            },
+            _ => return Err(format!("line {}: expected NAME ADDRESS", index + 1)),
+        }
+    }
+    Ok(symbols)
+}
+
+/// A program split into code and data by following control flow from its
+/// entry points, rather than decoding linearly from the first byte.
+#[derive(Clone)]
+pub struct Disassembly {
+    lines: Vec<DisassemblyLine>,
+    labels: BTreeMap<usize, String>,
+}
+
+impl Disassembly {
+    /// Disassembles `program`, loaded at absolute address `base`, starting
+    /// from each of `entries`. Code that is only reached through indirect
+    /// jumps or calls comes out as data unless it is given as an entry.
+    pub fn new(program: &[u8], base: usize, entries: &[usize]) -> Self {
+        let graph = ControlFlowGraph::build_with_entries(program, base, entries);
+        let code: BTreeMap<usize, instruction> = graph
+            .blocks()
+            .values()
+            .flat_map(|block| block.instructions.iter().copied())
+            .collect();
+
+        let mut lines = Vec::new();
+        let mut at = 0usize;
+        while at < program.len() {
+            let address = base + at;
+            if let Some(inst) = code.get(&address) {
+                lines.push(DisassemblyLine::Instruction {
+                    address,
+                    inst: *inst,
+                });
+                at += inst.Size as usize;
+                continue;
+            }
+
+            // Code that starts inside an instruction already listed has its
+            // bytes covered by that instruction, so only later starts count
+            let end = code
+                .range(address..)
+                .next()
+                .map_or(program.len(), |(next, _)| next - base)
+                .min(at + DATA_BYTES_PER_LINE);
+            lines.push(DisassemblyLine::Data {
+                address,
+                bytes: program[at..end].to_vec(),
+            });
+            at = end;
+        }
+
+        Self {
+            lines,
+            labels: BTreeMap::new(),
+        }
+    }
+
+    pub fn lines(&self) -> &[DisassemblyLine] {
+        &self.lines
+    }
+
+    pub fn labels(&self) -> &BTreeMap<usize, String> {
+        &self.labels
+    }
+
+    /// The label at the start of a line, if there is one.
+    pub fn label_at(&self, address: usize) -> Option<&str> {
+        self.labels
+            .get(&address)
+            .filter(|_| self.line_index(address).is_ok())
+            .map(String::as_str)
+    }
+
+    fn line_index(&self, address: usize) -> Result<usize, usize> {
+        self.lines
+            .binary_search_by_key(&address, DisassemblyLine::address)
+    }
+
+    /// Names `address`, splitting a run of data so the label can go in front
+    /// of the byte. A label inside an instruction is kept but never printed,
+    /// since no line starts there.
+    pub fn define_label(&mut self, address: usize, name: &str) {
+        if let Err(index) = self.line_index(address) {
+            let split = match index.checked_sub(1).map(|index| &mut self.lines[index]) {
+                Some(DisassemblyLine::Data {
+                    address: start,
+                    bytes,
+                }) if address < *start + bytes.len() => Some(bytes.split_off(address - *start)),
+                _ => None,
+            };
+            if let Some(bytes) = split {
+                self.lines
+                    .insert(index, DisassemblyLine::Data { address, bytes });
+            }
+        }
+        self.labels.insert(address, name.to_string());
+    }
+
+    /// Gives every branch target that starts an instruction a `label_XXXX`
+    /// name, unless it already has one.
+    pub fn synthesize_labels(&mut self) {
+        let targets: Vec<usize> = self
+            .lines
+            .iter()
+            .filter_map(|line| match line {
+                DisassemblyLine::Instruction { address, inst } => {
+                    relative_jump_target(*address, inst)
+                }
+                DisassemblyLine::Data { .. } => None,
+            })
+            .collect();
+        for target in targets {
+            let starts_instruction = self.line_index(target).is_ok_and(|index| {
+                matches!(self.lines[index], DisassemblyLine::Instruction { .. })
+            });
+            if starts_instruction && !self.labels.contains_key(&target) {
+                self.define_label(target, &format!("label_{:04x}", target));
+            }
+        }
+    }
+
+    /// One line of NASM source, with branches to labelled lines referring to
+    /// the label instead of a `$` offset.
+    pub fn line_text(&self, line: &DisassemblyLine) -> String {
+        let (address, inst) = match line {
+            DisassemblyLine::Instruction { address, inst } => (*address, inst),
+            DisassemblyLine::Data { bytes, .. } => return data_text(bytes),
+        };
+        let text = instruction_text(inst).trim_end().to_string();
+        let Some(label) = relative_jump_target(address, inst).and_then(|to| self.label_at(to))
+        else {
+            return text;
+        };
+
+        let offset = relative_jump_target(address, inst).unwrap() as i64 - address as i64;
+        let mut text = text.replace(&format!("${:+}", offset), label);
+        // NASM picks the short form of jmp whenever the label is in range
+        if inst.Op == operation_type_Op_jmp && inst.Size == 3 {
+            text = text.replacen("jmp ", "jmp near ", 1);
+        }
+        text
+    }
+
+    /// The listing as NASM source, one label, instruction or `db` run per
+    /// line, which assembles back to the original bytes.
+    pub fn text(&self) -> String {
+        let mut text = String::from("bits 16\n");
+        for line in &self.lines {
+            if let Some(label) = self.label_at(line.address()) {
+                text.push_str(label);
+                text.push_str(":\n");
+            }
+            text.push_str(&self.line_text(line));
+            text.push('\n');
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // jmp $+4 / db 0xff, 0xfe / mov ax, 1 / ret / mov bx, 2 / hlt
+    const PROGRAM: [u8; 13] = [
+        0xEB, 0x02, 0xFF, 0xFE, 0xB8, 0x01, 0x00, 0xC3, 0xBB, 0x02, 0x00, 0xF4, 0x00,
+    ];
+
+    #[test]
+    fn skips_data_between_code() {
+        let disassembly = Disassembly::new(&PROGRAM, 0, &[0]);
+        assert_eq!(
+            "bits 16\njmp $+4\ndb 0xff, 0xfe\nmov ax, 1\nret\ndb 0xbb, 0x02, 0x00, 0xf4, 0x00\n",
+            disassembly.text()
+        );
+    }
+
+    #[test]
+    fn extra_entries_reach_more_code() {
+        let disassembly = Disassembly::new(&PROGRAM, 0x100, &[0x100, 0x108]);
+        let addresses: Vec<usize> = disassembly
+            .lines()
+            .iter()
+            .map(DisassemblyLine::address)
+            .collect();
+        assert_eq!(
+            vec![0x100, 0x102, 0x104, 0x107, 0x108, 0x10b, 0x10c],
+            addresses
+        );
+        assert!(disassembly.text().ends_with("mov bx, 2\nhlt\ndb 0x00\n"));
+    }
+
+    #[test]
+    fn labels_branch_targets() {
+        // mov cx, 3 / dec cx / jne $-1 / jmp near $+5 / db 0xff, 0xfe / hlt
+        let program = [
+            0xB9, 0x03, 0x00, 0x49, 0x75, 0xFD, 0xE9, 0x02, 0x00, 0xFF, 0xFE, 0xF4,
+        ];
+        let mut disassembly = Disassembly::new(&program, 0, &[0]);
+        disassembly.define_label(0x0a, "table");
+        disassembly.define_label(0x0b, "done");
+        disassembly.synthesize_labels();
+        assert_eq!(
+            "bits 16\nmov cx, 3\nlabel_0003:\ndec cx\njne label_0003\njmp near done\n\
+             db 0xff\ntable:\ndb 0xfe\ndone:\nhlt\n",
+            disassembly.text()
+        );
+    }
+
+    #[test]
+    fn parses_symbol_files() {
+        let symbols = parse_symbols("; generated\nstart 0\n\ny_loop_start 0x12 ; inner\n");
+        assert_eq!(
+            Ok(vec![
+                ("start".to_string(), 0),
+                ("y_loop_start".to_string(), 0x12)
+            ]),
+            symbols
+        );
+        assert!(parse_symbols("start").is_err());
+    }
+}
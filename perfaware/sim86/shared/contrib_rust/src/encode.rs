@@ -0,0 +1,472 @@
+use crate::*;
+
+// Encodes an instruction with the decoder's own table: every encoding of the
+// operation is filled in from the operands, decoded again to check it means
+// the same thing, and one of those that do is picked. Checking against the
+// decoder keeps the two from drifting apart, at the cost of speed.
+
+/// Which of the encodings that decode to the same instruction to pick.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncodingChoice {
+    /// The first that fits in the decoder's table, as the table lists them.
+    TableOrder,
+    /// The fewest bytes, breaking ties by table order, as NASM does.
+    Shortest,
+}
+
+/// One way to encode an instruction, without its prefixes.
+pub(crate) struct Candidate {
+    pub bytes: Vec<u8>,
+    /// Whether the operands decode in the order given, rather than swapped.
+    pub in_order: bool,
+    /// Whether immediates decode to the values given, rather than to values
+    /// with the same low bits, such as 0xFFA6 for -90.
+    pub exact: bool,
+    pub wide: bool,
+}
+
+/// Machine code for `inst`, the inverse of `decode_8086_instruction`, using
+/// the first encoding the table has for it. None if no encoding of the
+/// operation takes these operands.
+///
+/// Relative displacements are kept as they are, so they count from the end
+/// of whichever encoding is picked.
+pub fn encode(inst: &instruction) -> Option<Vec<u8>> {
+    encode_with(inst, EncodingChoice::TableOrder)
+}
+
+/// Machine code for `inst`, picking between equivalent encodings by `choice`.
+pub fn encode_with(inst: &instruction, choice: EncodingChoice) -> Option<Vec<u8>> {
+    let candidate = choose(candidates(inst, true), choice)?;
+    let mut bytes = prefix_bytes(inst);
+    bytes.extend(candidate.bytes);
+    Some(bytes)
+}
+
+/// The lock, rep and segment prefixes the flags of `inst` call for, in the
+/// order NASM writes them.
+fn prefix_bytes(inst: &instruction) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    if (inst.Flags & instruction_flag_Inst_Lock) != 0 {
+        bytes.push(0xF0);
+    }
+    if (inst.Flags & instruction_flag_Inst_Rep) != 0 {
+        // The decoder sets RepNE from the Z bit, which is set for plain rep
+        let z = (inst.Flags & instruction_flag_Inst_RepNE) != 0;
+        bytes.push(if z { 0xF3 } else { 0xF2 });
+    }
+    if (inst.Flags & instruction_flag_Inst_Segment) != 0 {
+        bytes.push(segment_prefix_byte(inst.SegmentOverride));
+    }
+    bytes
+}
+
+pub(crate) fn segment_prefix_byte(segment: register_index) -> u8 {
+    0x26 | (((segment - Register_es) as u8) << 3)
+}
+
+/// Every encoding of `target`, in table order. Without `check_wide`, the
+/// width is left to the operands, so both widths may turn up.
+pub(crate) fn candidates(target: &instruction, check_wide: bool) -> Vec<Candidate> {
+    encoding_table()
+        .iter()
+        .filter(|encoding| encoding.Op == target.Op)
+        .flat_map(|encoding| encodings_with(encoding, target, check_wide))
+        .collect()
+}
+
+/// The candidate `choice` prefers, favouring operands in the order given and
+/// then immediates exactly as given.
+pub(crate) fn choose(candidates: Vec<Candidate>, choice: EncodingChoice) -> Option<Candidate> {
+    // min_by_key keeps the first of equals, which is the first in table order
+    candidates.into_iter().min_by_key(|candidate| {
+        let size = match choice {
+            EncodingChoice::TableOrder => 0,
+            EncodingChoice::Shortest => candidate.bytes.len(),
+        };
+        (size, !candidate.in_order, !candidate.exact)
+    })
+}
+
+pub(crate) fn encoding_table() -> &'static [instruction_encoding] {
+    let table = get_8086_instruction_table();
+    // The table is a static array inside the library
+    unsafe { std::slice::from_raw_parts(table.Encodings, table.EncodingCount as usize) }
+}
+
+pub(crate) fn encoding_bits(
+    encoding: &instruction_encoding,
+) -> impl Iterator<Item = &instruction_bits> {
+    encoding
+        .Bits
+        .iter()
+        .take_while(|bits| bits.Usage != instruction_bits_usage_Bits_End)
+}
+
+/// Intel's REG/RM number for a general register, the inverse of the
+/// decoder's lookup table.
+fn register_code(access: &register_access) -> Option<u32> {
+    const ORDER: [register_index; 4] = [Register_a, Register_c, Register_d, Register_b];
+    const WIDE_ORDER: [register_index; 8] = [
+        Register_a,
+        Register_c,
+        Register_d,
+        Register_b,
+        Register_sp,
+        Register_bp,
+        Register_si,
+        Register_di,
+    ];
+    let code = if access.Count == 2 {
+        WIDE_ORDER.iter().position(|index| *index == access.Index)?
+    } else {
+        ORDER.iter().position(|index| *index == access.Index)? + 4 * access.Offset as usize
+    };
+    Some(code as u32)
+}
+
+pub(crate) fn is_segment(access: &register_access) -> bool {
+    (Register_es..=Register_ds).contains(&access.Index)
+}
+
+/// The RM number of a memory operand's base and index registers.
+pub(crate) fn rm_code(terms: &[register_index]) -> Option<u32> {
+    let mut terms = terms.to_vec();
+    terms.sort();
+    let code = match terms[..] {
+        [Register_b, Register_si] => 0,
+        [Register_b, Register_di] => 1,
+        [Register_bp, Register_si] => 2,
+        [Register_bp, Register_di] => 3,
+        [Register_si] => 4,
+        [Register_di] => 5,
+        [Register_bp] => 6,
+        [Register_b] => 7,
+        _ => return None,
+    };
+    Some(code)
+}
+
+fn address_terms(address: &effective_address_expression) -> Vec<register_index> {
+    address
+        .Terms
+        .iter()
+        .map(|term| term.Register.Index)
+        .filter(|index| *index != Register_none)
+        .collect()
+}
+
+fn is_far_address(address: &effective_address_expression) -> bool {
+    (address.Flags & effective_address_flag_Address_ExplicitSegment) != 0
+}
+
+fn operands_of(inst: &instruction) -> Vec<instruction_operand> {
+    inst.Operands
+        .iter()
+        .copied()
+        .filter(|operand| operand.Type != operand_type_Operand_None)
+        .collect()
+}
+
+fn immediate_values(inst: &instruction) -> Vec<i32> {
+    let mut values: Vec<i32> = operands_of(inst)
+        .iter()
+        .filter(|operand| operand.Type == operand_type_Operand_Immediate)
+        .map(|operand| unsafe { operand.__bindgen_anon_1.Immediate.Value })
+        .collect();
+    values.sort();
+    values
+}
+
+fn same_operand(a: &instruction_operand, b: &instruction_operand, wide: bool) -> bool {
+    if a.Type != b.Type {
+        return false;
+    }
+    unsafe {
+        match a.Type {
+            operand_type_Operand_Register => {
+                let (a, b) = (a.__bindgen_anon_1.Register, b.__bindgen_anon_1.Register);
+                (a.Index, a.Offset, a.Count) == (b.Index, b.Offset, b.Count)
+            }
+            operand_type_Operand_Memory => {
+                let (a, b) = (a.__bindgen_anon_1.Address, b.__bindgen_anon_1.Address);
+                let mut terms = (address_terms(&a), address_terms(&b));
+                terms.0.sort();
+                terms.1.sort();
+                is_far_address(&a) == is_far_address(&b)
+                    && a.ExplicitSegment as u16 == b.ExplicitSegment as u16
+                    && a.Displacement as u16 == b.Displacement as u16
+                    && terms.0 == terms.1
+            }
+            operand_type_Operand_Immediate => {
+                let (a, b) = (a.__bindgen_anon_1.Immediate, b.__bindgen_anon_1.Immediate);
+                let relative = (a.Flags & immediate_flag_Immediate_RelativeJumpDisplacement) != 0;
+                let mask = if relative || wide { 0xFFFF } else { 0xFF };
+                a.Flags == b.Flags && (a.Value & mask) == (b.Value & mask)
+            }
+            _ => true,
+        }
+    }
+}
+
+/// Whether `decoded` says the same as `target`, and if so whether its operands
+/// are in the same order. Operands of xchg and test can go either way round.
+fn same_instruction(decoded: &instruction, target: &instruction, check_wide: bool) -> Option<bool> {
+    let wide = (decoded.Flags & instruction_flag_Inst_Wide) != 0;
+    let far = |inst: &instruction| (inst.Flags & instruction_flag_Inst_Far) != 0;
+    if decoded.Op != target.Op
+        || far(decoded) != far(target)
+        || (check_wide && wide != ((target.Flags & instruction_flag_Inst_Wide) != 0))
+    {
+        return None;
+    }
+
+    let (a, b) = (operands_of(decoded), operands_of(target));
+    if a.len() != b.len() {
+        return None;
+    }
+    if a.iter().zip(&b).all(|(a, b)| same_operand(a, b, wide)) {
+        return Some(true);
+    }
+    let commutes = [operation_type_Op_xchg, operation_type_Op_test].contains(&target.Op);
+    (commutes
+        && a.len() == 2
+        && a.iter()
+            .rev()
+            .zip(&b)
+            .all(|(a, b)| same_operand(a, b, wide)))
+    .then_some(false)
+}
+
+/// The values each field of an encoding could take for `target`: the codes
+/// of its registers, the ways to address its memory operand, and so on.
+fn field_choices(target: &instruction, usage: instruction_bits_usage) -> Vec<Vec<(usize, u32)>> {
+    let operands = operands_of(target);
+    let registers = operands
+        .iter()
+        .filter(|operand| operand.Type == operand_type_Operand_Register)
+        .map(|operand| unsafe { operand.__bindgen_anon_1.Register });
+    let single = |value: u32| vec![(usage as usize, value)];
+
+    match usage {
+        instruction_bits_usage_Bits_D
+        | instruction_bits_usage_Bits_S
+        | instruction_bits_usage_Bits_W
+        | instruction_bits_usage_Bits_V
+        | instruction_bits_usage_Bits_Z => vec![single(0), single(1)],
+        instruction_bits_usage_Bits_REG => registers
+            .filter_map(|r| register_code(&r))
+            .map(single)
+            .collect(),
+        instruction_bits_usage_Bits_SR => registers
+            .filter(is_segment)
+            .map(|register| single(register.Index - Register_es))
+            .collect(),
+        instruction_bits_usage_Bits_MOD => {
+            let field = |mode: u32, rm: u32, displacement: i32| {
+                vec![
+                    (instruction_bits_usage_Bits_MOD as usize, mode),
+                    (instruction_bits_usage_Bits_RM as usize, rm),
+                    (
+                        instruction_bits_usage_Bits_Disp as usize,
+                        displacement as u32,
+                    ),
+                ]
+            };
+            let mut choices: Vec<Vec<(usize, u32)>> = registers
+                .filter_map(|register| register_code(&register))
+                .map(|code| field(0b11, code, 0))
+                .collect();
+            for operand in operands
+                .iter()
+                .filter(|operand| operand.Type == operand_type_Operand_Memory)
+            {
+                let address = unsafe { operand.__bindgen_anon_1.Address };
+                let displacement = address.Displacement as i16 as i32;
+                if is_far_address(&address) {
+                    continue;
+                }
+                let Some(rm) = rm_code(&address_terms(&address)) else {
+                    choices.push(field(0b00, 0b110, displacement));
+                    continue;
+                };
+                if displacement == 0 && rm != 0b110 {
+                    choices.push(field(0b00, rm, 0));
+                }
+                if i8::try_from(displacement).is_ok() {
+                    choices.push(field(0b01, rm, displacement));
+                }
+                choices.push(field(0b10, rm, displacement));
+            }
+            choices
+        }
+        _ => vec![Vec::new()],
+    }
+}
+
+/// Everything `target` can be encoded as with one table entry, in the order
+/// the fields are enumerated. Prefixes aren't included.
+fn encodings_with(
+    encoding: &instruction_encoding,
+    target: &instruction,
+    check_wide: bool,
+) -> Vec<Candidate> {
+    let operands = operands_of(target);
+    let mut base = [0u32; instruction_bits_usage_Bits_Count as usize];
+    for operand in &operands {
+        unsafe {
+            match operand.Type {
+                operand_type_Operand_Immediate => {
+                    let immediate = operand.__bindgen_anon_1.Immediate;
+                    if (immediate.Flags & immediate_flag_Immediate_RelativeJumpDisplacement) != 0 {
+                        base[instruction_bits_usage_Bits_Disp as usize] = immediate.Value as u32;
+                    } else {
+                        base[instruction_bits_usage_Bits_Data as usize] = immediate.Value as u32;
+                    }
+                }
+                operand_type_Operand_Memory => {
+                    let address = operand.__bindgen_anon_1.Address;
+                    base[instruction_bits_usage_Bits_Disp as usize] = address.Displacement as u32;
+                    if is_far_address(&address) {
+                        base[instruction_bits_usage_Bits_Data as usize] = address.ExplicitSegment;
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // Fixed fields hold their values, the others get every possible value
+    let mut choices = vec![Vec::new()];
+    for bits in encoding_bits(encoding) {
+        let usage = bits.Usage;
+        if bits.BitCount == 0 {
+            base[usage as usize] |= (bits.Value as u32) << bits.Shift;
+        } else if usage != instruction_bits_usage_Bits_Literal
+            && usage != instruction_bits_usage_Bits_RM
+        {
+            let options = field_choices(target, usage);
+            choices = choices
+                .iter()
+                .flat_map(|choice: &Vec<(usize, u32)>| {
+                    options
+                        .iter()
+                        .map(move |option| [choice.clone(), option.clone()].concat())
+                })
+                .collect();
+        }
+    }
+
+    let mut found = Vec::new();
+    for choice in choices {
+        let mut fields = base;
+        for (usage, value) in choice {
+            fields[usage] = value;
+        }
+
+        let mut bytes: Vec<u8> = Vec::new();
+        let mut used = 8;
+        for bits in encoding_bits(encoding).filter(|bits| bits.BitCount != 0) {
+            if used == 8 {
+                bytes.push(0);
+                used = 0;
+            }
+            let value = match bits.Usage {
+                instruction_bits_usage_Bits_Literal => bits.Value as u32,
+                usage => fields[usage as usize] >> bits.Shift,
+            };
+            used += bits.BitCount;
+            *bytes.last_mut().unwrap() |=
+                ((value & ((1 << bits.BitCount) - 1)) << (8 - used)) as u8;
+        }
+
+        // Displacement and data follow, sized exactly as the decoder reads them
+        let field = |usage: instruction_bits_usage| fields[usage as usize];
+        let has = |usage| encoding_bits(encoding).any(|bits| bits.Usage == usage);
+        let mode = field(instruction_bits_usage_Bits_MOD);
+        let direct = mode == 0b00 && field(instruction_bits_usage_Bits_RM) == 0b110;
+        let has_mod = has(instruction_bits_usage_Bits_MOD);
+        if has(instruction_bits_usage_Bits_Disp)
+            || (has_mod && (mode == 0b01 || mode == 0b10 || direct))
+        {
+            let displacement = field(instruction_bits_usage_Bits_Disp) as u16;
+            if field(instruction_bits_usage_Bits_DispAlwaysW) != 0
+                || (has_mod && (mode == 0b10 || direct))
+            {
+                bytes.extend(displacement.to_le_bytes());
+            } else {
+                bytes.push(displacement as u8);
+            }
+        }
+        if has(instruction_bits_usage_Bits_Data) {
+            let data = field(instruction_bits_usage_Bits_Data) as u16;
+            let wide = field(instruction_bits_usage_Bits_WMakesDataW) != 0
+                && field(instruction_bits_usage_Bits_S) == 0
+                && field(instruction_bits_usage_Bits_W) != 0;
+            if wide {
+                bytes.extend(data.to_le_bytes());
+            } else {
+                bytes.push(data as u8);
+            }
+        }
+
+        let Ok(decoded) = decode_8086_instruction(&bytes) else {
+            continue;
+        };
+        if decoded.Size as usize == bytes.len() {
+            if let Some(in_order) = same_instruction(&decoded, target, check_wide) {
+                found.push(Candidate {
+                    bytes,
+                    in_order,
+                    exact: immediate_values(&decoded) == immediate_values(target),
+                    wide: (decoded.Flags & instruction_flag_Inst_Wide) != 0,
+                });
+            }
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(bytes: &[u8]) -> instruction {
+        decode_8086_instruction(bytes).unwrap()
+    }
+
+    #[test]
+    fn picks_table_order_or_shortest() {
+        // mov ax, [4660]
+        let inst = decode(&[0xA1, 0x34, 0x12]);
+        assert_eq!(Some(vec![0x8B, 0x06, 0x34, 0x12]), encode(&inst));
+        assert_eq!(
+            Some(vec![0xA1, 0x34, 0x12]),
+            encode_with(&inst, EncodingChoice::Shortest)
+        );
+
+        // add cx, -90 keeps its sign-extended form
+        let inst = decode(&[0x83, 0xC1, 0xA6]);
+        assert_eq!(Some(vec![0x83, 0xC1, 0xA6]), encode(&inst));
+    }
+
+    #[test]
+    fn encodes_prefixes() {
+        for bytes in [
+            &[0xF0, 0x86, 0x43, 0xFD][..],
+            &[0xF3, 0xA5],
+            &[0xF2, 0xAE],
+            &[0x26, 0x8B, 0x17],
+        ] {
+            assert_eq!(Some(bytes.to_vec()), encode(&decode(bytes)));
+        }
+    }
+
+    #[test]
+    fn rejects_operands_no_encoding_takes() {
+        // shl ax, 1 with the count turned into 2, which needs cl
+        let mut inst = decode(&[0xD1, 0xE0]);
+        inst.Operands[1].__bindgen_anon_1.Immediate.Value = 2;
+        assert!(encode(&inst).is_none());
+    }
+}
@@ -0,0 +1,385 @@
+use std::fmt;
+
+use crate::simulator::{absolute_address, Hooks, Simulator};
+use crate::simulator::{
+    AUX_CARRY_FLAG, CARRY_FLAG, DIRECTION_FLAG, INTERRUPT_FLAG, OVERFLOW_FLAG, PARITY_FLAG,
+    SIGNED_FLAG, TRAP_FLAG, ZERO_FLAG,
+};
+use crate::*;
+
+// Small C-like expressions over the machine state, for breakpoint conditions:
+//
+//     cx == 0 && [bp+2] > 10
+//
+// Operands are numbers (decimal, or hex as 0x10, 10h or $10), register
+// names (ax, al, cs, ip, flags, ...), flags (cf pf af zf sf tf if df of, which
+// read as 0 or 1) and memory. [OFFSET] reads a word, byte [OFFSET] a byte; the segment is
+// ss when the offset mentions bp and ds otherwise, as for an 8086 effective
+// address, unless one is given as in [es:di]. Operators and their precedence
+// are C's: || && | ^ & == != < <= > >= + - * and unary - ! ~.
+
+const FLAG_NAMES: [(&str, u16); 9] = [
+    ("cf", CARRY_FLAG),
+    ("pf", PARITY_FLAG),
+    ("af", AUX_CARRY_FLAG),
+    ("zf", ZERO_FLAG),
+    ("sf", SIGNED_FLAG),
+    ("tf", TRAP_FLAG),
+    ("if", INTERRUPT_FLAG),
+    ("df", DIRECTION_FLAG),
+    ("of", OVERFLOW_FLAG),
+];
+
+// Longer symbols first, so "<=" isn't read as "<" then "="
+const SYMBOLS: [&str; 21] = [
+    "||", "&&", "==", "!=", "<=", ">=", "<", ">", "+", "-", "*", "&", "|", "^", "!", "~", "(", ")",
+    "[", "]", ":",
+];
+
+// Binary operators from loosest to tightest binding.
+const PRECEDENCE: [&[&str]; 8] = [
+    &["||"],
+    &["&&"],
+    &["|"],
+    &["^"],
+    &["&"],
+    &["==", "!="],
+    &["<", "<=", ">", ">="],
+    &["+", "-"],
+];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(i64),
+    Name(String),
+    Symbol(&'static str),
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    Number(i64),
+    Register(register_access),
+    Flag(u16),
+    Memory {
+        segment: register_index,
+        offset: Box<Node>,
+        wide: bool,
+    },
+    Unary(&'static str, Box<Node>),
+    Binary(&'static str, Box<Node>, Box<Node>),
+}
+
+/// A parsed expression, which displays as the text it was parsed from.
+#[derive(Debug, Clone)]
+pub struct Expression {
+    text: String,
+    root: Node,
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = text.trim_start();
+    while let Some(first) = rest.chars().next() {
+        let length = if first.is_ascii_alphanumeric() || first == '_' || first == '$' {
+            let length = rest[1..]
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .map_or(rest.len(), |length| length + 1);
+            let word = &rest[..length];
+            if first.is_ascii_digit() || first == '$' {
+                let value = debugger::parse_number(word)
+                    .ok_or_else(|| format!("bad number \"{}\"", word))?;
+                tokens.push(Token::Number(value));
+            } else {
+                tokens.push(Token::Name(word.to_ascii_lowercase()));
+            }
+            length
+        } else {
+            let symbol = SYMBOLS
+                .iter()
+                .find(|symbol| rest.starts_with(*symbol))
+                .ok_or_else(|| format!("unexpected \"{}\"", first))?;
+            tokens.push(Token::Symbol(symbol));
+            symbol.len()
+        };
+        rest = rest[length..].trim_start();
+    }
+
+    Ok(tokens)
+}
+
+fn byte_register(name: &str) -> Option<register_access> {
+    (Register_a..=Register_d)
+        .flat_map(|index| {
+            [0, 1].map(|offset| register_access {
+                Index: index,
+                Offset: offset,
+                Count: 1,
+            })
+        })
+        .find(|access| register_name_from_operand(access) == name)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    at: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.at)
+    }
+
+    fn eat(&mut self, symbol: &str) -> bool {
+        let found = matches!(self.peek(), Some(Token::Symbol(s)) if *s == symbol);
+        if found {
+            self.at += 1;
+        }
+        found
+    }
+
+    fn expect(&mut self, symbol: &str) -> Result<(), String> {
+        if self.eat(symbol) {
+            Ok(())
+        } else {
+            Err(format!("expected \"{}\"", symbol))
+        }
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Node, String> {
+        let Some(operators) = PRECEDENCE.get(level) else {
+            return self.product();
+        };
+
+        let mut left = self.binary(level + 1)?;
+        while let Some(Token::Symbol(symbol)) = self.peek() {
+            let Some(operator) = operators.iter().find(|operator| *operator == symbol) else {
+                break;
+            };
+            self.at += 1;
+            let right = self.binary(level + 1)?;
+            left = Node::Binary(operator, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn product(&mut self) -> Result<Node, String> {
+        let mut left = self.unary()?;
+        while self.eat("*") {
+            let right = self.unary()?;
+            left = Node::Binary("*", Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Node, String> {
+        for operator in ["-", "!", "~"] {
+            if self.eat(operator) {
+                return Ok(Node::Unary(operator, Box::new(self.unary()?)));
+            }
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Node, String> {
+        let token = self.peek().cloned().ok_or("unexpected end of expression")?;
+        self.at += 1;
+        match token {
+            Token::Number(value) => Ok(Node::Number(value)),
+            Token::Symbol("(") => {
+                let inner = self.binary(0)?;
+                self.expect(")")?;
+                Ok(inner)
+            }
+            Token::Symbol("[") => self.memory(true),
+            Token::Name(name) if name == "byte" || name == "word" => {
+                self.expect("[")?;
+                self.memory(name == "word")
+            }
+            Token::Name(name) => {
+                if let Some((_, flag)) = FLAG_NAMES.iter().find(|(flag, _)| *flag == name) {
+                    return Ok(Node::Flag(*flag));
+                }
+                if let Some(access) = byte_register(&name) {
+                    return Ok(Node::Register(access));
+                }
+                let index = register_index_from_name(&name)
+                    .ok_or_else(|| format!("unknown name \"{}\"", name))?;
+                Ok(Node::Register(text::wide_register(index)))
+            }
+            Token::Symbol(symbol) => Err(format!("unexpected \"{}\"", symbol)),
+        }
+    }
+
+    /// The rest of a memory operand, after its opening bracket.
+    fn memory(&mut self, wide: bool) -> Result<Node, String> {
+        let explicit = match (self.peek(), self.tokens.get(self.at + 1)) {
+            (Some(Token::Name(name)), Some(Token::Symbol(":"))) => {
+                let index = register_index_from_name(name)
+                    .filter(|index| (Register_es..=Register_ds).contains(index))
+                    .ok_or_else(|| format!("\"{}\" isn't a segment register", name))?;
+                self.at += 2;
+                Some(index)
+            }
+            _ => None,
+        };
+
+        let offset = self.binary(0)?;
+        self.expect("]")?;
+        let segment = explicit.unwrap_or(if offset.mentions(Register_bp) {
+            Register_ss
+        } else {
+            Register_ds
+        });
+        Ok(Node::Memory {
+            segment,
+            offset: Box::new(offset),
+            wide,
+        })
+    }
+}
+
+impl Node {
+    fn mentions(&self, register: register_index) -> bool {
+        match self {
+            Node::Register(access) => access.Index == register,
+            Node::Unary(_, inner) => inner.mentions(register),
+            Node::Binary(_, left, right) => left.mentions(register) || right.mentions(register),
+            _ => false,
+        }
+    }
+
+    fn evaluate<H: Hooks>(&self, simulator: &Simulator<H>) -> i64 {
+        let registers = simulator.registers();
+        match self {
+            Node::Number(value) => *value,
+            Node::Register(access) => registers.read(*access) as i64,
+            Node::Flag(flag) => (registers.flags() & flag != 0) as i64,
+            Node::Memory {
+                segment,
+                offset,
+                wide,
+            } => {
+                let segment = registers.get(*segment);
+                let offset = offset.evaluate(simulator) as u16;
+                let byte = |at: u16| simulator.memory()[absolute_address(segment, at)] as i64;
+                if *wide {
+                    byte(offset) | (byte(offset.wrapping_add(1)) << 8)
+                } else {
+                    byte(offset)
+                }
+            }
+            Node::Unary(operator, inner) => {
+                let value = inner.evaluate(simulator);
+                match *operator {
+                    "-" => value.wrapping_neg(),
+                    "!" => (value == 0) as i64,
+                    _ => !value,
+                }
+            }
+            Node::Binary(operator, left, right) => {
+                let left = left.evaluate(simulator);
+                // || and && only look at the right side when they need to
+                match *operator {
+                    "||" => return (left != 0 || right.evaluate(simulator) != 0) as i64,
+                    "&&" => return (left != 0 && right.evaluate(simulator) != 0) as i64,
+                    _ => {}
+                }
+
+                let right = right.evaluate(simulator);
+                match *operator {
+                    "|" => left | right,
+                    "^" => left ^ right,
+                    "&" => left & right,
+                    "==" => (left == right) as i64,
+                    "!=" => (left != right) as i64,
+                    "<" => (left < right) as i64,
+                    "<=" => (left <= right) as i64,
+                    ">" => (left > right) as i64,
+                    ">=" => (left >= right) as i64,
+                    "+" => left.wrapping_add(right),
+                    "-" => left.wrapping_sub(right),
+                    _ => left.wrapping_mul(right),
+                }
+            }
+        }
+    }
+}
+
+impl Expression {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut parser = Parser {
+            tokens: tokenize(text)?,
+            at: 0,
+        };
+        let root = parser.binary(0)?;
+        if let Some(token) = parser.peek() {
+            return Err(format!("unexpected {:?} after the expression", token));
+        }
+
+        Ok(Self {
+            text: text.trim().to_string(),
+            root,
+        })
+    }
+
+    /// Registers and memory read as unsigned values; comparisons and logical
+    /// operators give 0 or 1.
+    pub fn evaluate<H: Hooks>(&self, simulator: &Simulator<H>) -> i64 {
+        self.root.evaluate(simulator)
+    }
+
+    pub fn is_true<H: Hooks>(&self, simulator: &Simulator<H>) -> bool {
+        self.evaluate(simulator) != 0
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn evaluate(text: &str, simulator: &Simulator) -> i64 {
+        Expression::parse(text).unwrap().evaluate(simulator)
+    }
+
+    #[test]
+    fn follows_c_precedence() {
+        let simulator = Simulator::new();
+        assert_eq!(7, evaluate("1 + 2 * 3", &simulator));
+        assert_eq!(1, evaluate("1 | 2 == 3", &simulator));
+        assert_eq!(0, evaluate("!(0x10 >= 16) || 4 < 3 && 1", &simulator));
+        assert_eq!(-4, evaluate("~3", &simulator));
+    }
+
+    #[test]
+    fn reads_registers_flags_and_memory() {
+        let mut simulator = Simulator::new();
+        let registers = simulator.registers_mut();
+        registers.set(Register_a, 0x1234);
+        registers.set(Register_bp, 0x10);
+        registers.set(Register_ss, 0x100);
+        registers.set(Register_flags, ZERO_FLAG);
+        simulator.memory_mut()[0x1012..0x1014].copy_from_slice(&[0x0B, 0x01]);
+        simulator.memory_mut()[0x12] = 0x55;
+
+        assert_eq!(0x34, evaluate("al", &simulator));
+        assert_eq!(0x12, evaluate("AH", &simulator));
+        assert_eq!(1, evaluate("zf && !cf", &simulator));
+        assert_eq!(1, evaluate("cx == 0 && [bp+2] > 10", &simulator));
+        assert_eq!(0x10B, evaluate("[bp+2]", &simulator));
+        assert_eq!(0x0B, evaluate("byte [bp + 2]", &simulator));
+        assert_eq!(0x55, evaluate("byte [ds:bp+2]", &simulator));
+    }
+
+    #[test]
+    fn reports_parse_errors() {
+        for text in ["", "ax +", "[bx", "zz == 1", "ax = 1", "[ax:0]", "1 2"] {
+            assert!(Expression::parse(text).is_err(), "{}", text);
+        }
+    }
+}
@@ -0,0 +1,490 @@
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+
+use crate::debugger::{Debugger, StopReason, WatchKind};
+use crate::simulator::MEM_LEN;
+use crate::*;
+
+// A gdb remote serial protocol stub. gdb's i8086 architecture uses the i386
+// register file, so registers go over the wire as 32 bits each in this order,
+// and memory addresses are taken as physical addresses. With CS at 0 (the
+// default load address) gdb's $pc and the physical address are the same.
+const GDB_REGISTERS: [register_index; 16] = [
+    Register_a,
+    Register_c,
+    Register_d,
+    Register_b,
+    Register_sp,
+    Register_bp,
+    Register_si,
+    Register_di,
+    Register_ip,
+    Register_flags,
+    Register_cs,
+    Register_ss,
+    Register_ds,
+    Register_es,
+    Register_none, // fs
+    Register_none, // gs
+];
+
+// The largest packet gdb may send or expect back, advertised in qSupported.
+// A memory read replies with two hex digits per byte, so it can ask for half
+// this many bytes.
+const PACKET_SIZE: usize = 0x4000;
+
+// How many instructions to run between checks for a ^C from gdb.
+const INTERRUPT_POLL_INTERVAL: u32 = 4096;
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+const SIGXCPU: u8 = 24;
+
+pub struct GdbServer {
+    debugger: Debugger,
+    no_ack: bool,
+}
+
+fn checksum(data: &str) -> u8 {
+    data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte))
+}
+
+fn hex_bytes(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|at| u8::from_str_radix(text.get(at..at + 2)?, 16).ok())
+        .collect()
+}
+
+fn parse_hex(text: &str) -> Option<usize> {
+    usize::from_str_radix(text, 16).ok()
+}
+
+/// Parses "ADDR,LEN" as sent by the m and M packets, wrapping the address
+/// into the 1MB address space. A length longer than a reply packet can hold
+/// is refused.
+fn parse_range(text: &str) -> Option<(usize, usize)> {
+    let (address, length) = text.split_once(',')?;
+    let length = parse_hex(length).filter(|length| *length <= PACKET_SIZE / 2)?;
+    Some((parse_hex(address)? % MEM_LEN, length))
+}
+
+fn register_value_text(value: u16) -> String {
+    (value as u32)
+        .to_le_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn register_value_from(bytes: &[u8]) -> Option<u16> {
+    let low = *bytes.first()?;
+    let high = bytes.get(1).copied().unwrap_or(0);
+    Some(u16::from_le_bytes([low, high]))
+}
+
+impl GdbServer {
+    pub fn new(debugger: Debugger) -> Self {
+        Self {
+            debugger,
+            no_ack: false,
+        }
+    }
+
+    pub fn debugger(&self) -> &Debugger {
+        &self.debugger
+    }
+
+    /// Serves one gdb connection until it detaches, kills the target or hangs up.
+    pub fn serve(&mut self, mut stream: TcpStream) -> io::Result<()> {
+        stream.set_nodelay(true)?;
+        while let Some(packet) = self.read_packet(&mut stream)? {
+            let reply = match packet.as_str() {
+                "c" => self.run(&mut stream, false)?,
+                "s" => self.run(&mut stream, true)?,
+                "bs" => Self::stop_reply_for(self.debugger.step_back()),
+                "bc" => Self::stop_reply_for(self.debugger.reverse_continue()),
+                "\u{3}" => Self::stop_reply(SIGINT),
+                _ => match self.handle_packet(&packet) {
+                    Some(reply) => reply,
+                    None => {
+                        Self::write_packet(&mut stream, "OK")?;
+                        return Ok(());
+                    }
+                },
+            };
+            Self::write_packet(&mut stream, &reply)?;
+        }
+
+        Ok(())
+    }
+
+    fn stop_reply(signal: u8) -> String {
+        format!("S{:02x}", signal)
+    }
+
+    fn stop_reply_for(reason: StopReason) -> String {
+        match reason {
+            StopReason::Step | StopReason::Breakpoint(_) => Self::stop_reply(SIGTRAP),
+            StopReason::Halted | StopReason::ProgramEnd => "W00".to_string(),
+            StopReason::DecodeError(_) | StopReason::Unimplemented(_) => Self::stop_reply(SIGILL),
+            StopReason::MemoryFault(_) => Self::stop_reply(SIGSEGV),
+            StopReason::StepLimit(_) => Self::stop_reply(SIGXCPU),
+            StopReason::Interrupted => Self::stop_reply(SIGINT),
+            StopReason::HistoryStart => format!("T{:02x}replaylog:begin;", SIGTRAP),
+            StopReason::Watchpoint(hit) => {
+                let kind = match hit.kind {
+                    WatchKind::Write => "watch",
+                    WatchKind::Read => "rwatch",
+                    WatchKind::Access => "awatch",
+                };
+                format!("T{:02x}{}:{:x};", SIGTRAP, kind, hit.address)
+            }
+        }
+    }
+
+    /// Handles everything except the run control packets, which need the
+    /// stream to watch for interrupts. Returns None when the session is over.
+    pub fn handle_packet(&mut self, packet: &str) -> Option<String> {
+        let reply = match packet.as_bytes().first() {
+            Some(b'?') => Self::stop_reply(SIGTRAP),
+            Some(b'g') => self.read_registers(),
+            Some(b'G') => self.write_registers(&packet[1..]),
+            Some(b'p') => self.read_register(&packet[1..]),
+            Some(b'P') => self.write_register(&packet[1..]),
+            Some(b'm') => self.read_memory(&packet[1..]),
+            Some(b'M') => self.write_memory(&packet[1..]),
+            Some(b'Z') | Some(b'z') => self.breakpoint(packet),
+            Some(b'H') => "OK".to_string(),
+            Some(b'k') | Some(b'D') => return None,
+            _ => self.query(packet),
+        };
+
+        Some(reply)
+    }
+
+    fn query(&mut self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            format!(
+                "PacketSize={:x};QStartNoAckMode+;ReverseStep+;ReverseContinue+",
+                PACKET_SIZE
+            )
+        } else if packet == "QStartNoAckMode" {
+            self.no_ack = true;
+            "OK".to_string()
+        } else if packet == "qAttached" {
+            "1".to_string()
+        } else if packet == "qfThreadInfo" {
+            "m1".to_string()
+        } else if packet == "qsThreadInfo" {
+            "l".to_string()
+        } else if packet == "qC" {
+            "QC1".to_string()
+        } else {
+            // An empty reply tells gdb the packet isn't supported
+            String::new()
+        }
+    }
+
+    fn read_registers(&self) -> String {
+        let registers = self.debugger.simulator().registers();
+        GDB_REGISTERS
+            .iter()
+            .map(|index| register_value_text(registers.get(*index)))
+            .collect()
+    }
+
+    fn write_registers(&mut self, data: &str) -> String {
+        let Some(bytes) = hex_bytes(data) else {
+            return "E01".to_string();
+        };
+
+        let registers = self.debugger.simulator_mut().registers_mut();
+        for (index, value) in GDB_REGISTERS.iter().zip(bytes.chunks(4)) {
+            if let Some(value) = register_value_from(value) {
+                if *index != Register_none {
+                    registers.set(*index, value);
+                }
+            }
+        }
+        "OK".to_string()
+    }
+
+    fn read_register(&self, data: &str) -> String {
+        match parse_hex(data).and_then(|number| GDB_REGISTERS.get(number)) {
+            Some(index) => register_value_text(self.debugger.simulator().registers().get(*index)),
+            None => "E01".to_string(),
+        }
+    }
+
+    fn write_register(&mut self, data: &str) -> String {
+        let parsed = data.split_once('=').and_then(|(number, value)| {
+            let index = *GDB_REGISTERS.get(parse_hex(number)?)?;
+            Some((index, register_value_from(&hex_bytes(value)?)?))
+        });
+
+        match parsed {
+            Some((index, value)) => {
+                if index != Register_none {
+                    let registers = self.debugger.simulator_mut().registers_mut();
+                    registers.set(index, value);
+                }
+                "OK".to_string()
+            }
+            None => "E01".to_string(),
+        }
+    }
+
+    fn read_memory(&self, data: &str) -> String {
+        let Some((address, length)) = parse_range(data) else {
+            return "E01".to_string();
+        };
+
+        let memory = self.debugger.simulator().memory();
+        (0..length)
+            .map(|offset| format!("{:02x}", memory[address.wrapping_add(offset) % MEM_LEN]))
+            .collect()
+    }
+
+    fn write_memory(&mut self, data: &str) -> String {
+        let parsed = data.split_once(':').and_then(|(range, bytes)| {
+            let (address, length) = parse_range(range)?;
+            let bytes = hex_bytes(bytes)?;
+            (bytes.len() == length).then_some((address, bytes))
+        });
+        let Some((address, bytes)) = parsed else {
+            return "E01".to_string();
+        };
+
+        let memory = self.debugger.simulator_mut().memory_mut();
+        for (offset, byte) in bytes.iter().enumerate() {
+            memory[address.wrapping_add(offset) % MEM_LEN] = *byte;
+        }
+        "OK".to_string()
+    }
+
+    /// Z0/z0 and Z1/z1: software and hardware breakpoints are the same thing
+    /// here. Z2 to Z4 are write, read and access watchpoints.
+    fn breakpoint(&mut self, packet: &str) -> String {
+        let insert = packet.starts_with('Z');
+        let mut fields = packet[1..].split(',');
+        let kind = fields.next();
+        let address = fields
+            .next()
+            .and_then(parse_hex)
+            .map(|address| address % MEM_LEN);
+        let length = fields
+            .next()
+            .and_then(parse_hex)
+            .unwrap_or(1)
+            .clamp(1, MEM_LEN);
+        let watch = match kind {
+            Some("2") => Some(WatchKind::Write),
+            Some("3") => Some(WatchKind::Read),
+            Some("4") => Some(WatchKind::Access),
+            _ => None,
+        };
+
+        match (kind, watch, address) {
+            (Some("0") | Some("1"), _, Some(address)) => {
+                if insert {
+                    self.debugger.add_breakpoint(address);
+                } else {
+                    self.debugger.remove_breakpoint(address);
+                }
+                "OK".to_string()
+            }
+            (_, Some(watch), Some(address)) => {
+                if insert {
+                    let end = address.wrapping_add(length).min(MEM_LEN);
+                    self.debugger.add_watchpoint(address..end, watch);
+                } else {
+                    self.debugger.remove_watchpoint(address);
+                }
+                "OK".to_string()
+            }
+            _ => String::new(),
+        }
+    }
+
+    fn run(&mut self, stream: &mut TcpStream, single_step: bool) -> io::Result<String> {
+        if single_step {
+            return Ok(Self::stop_reply_for(self.debugger.step()));
+        }
+
+        let mut steps = 0u32;
+        loop {
+            let reason = self.debugger.step();
+            if reason != StopReason::Step {
+                return Ok(Self::stop_reply_for(reason));
+            }
+
+            let here = self.debugger.simulator().instruction_address();
+            if self.debugger.stops_at(here) {
+                return Ok(Self::stop_reply(SIGTRAP));
+            }
+
+            steps += 1;
+            if steps.is_multiple_of(INTERRUPT_POLL_INTERVAL) && Self::interrupted(stream)? {
+                return Ok(Self::stop_reply(SIGINT));
+            }
+        }
+    }
+
+    fn interrupted(stream: &mut TcpStream) -> io::Result<bool> {
+        stream.set_nonblocking(true)?;
+        let mut byte = [0u8];
+        let result = stream.read(&mut byte);
+        stream.set_nonblocking(false)?;
+
+        match result {
+            Ok(1) => Ok(byte[0] == 0x03),
+            Ok(_) => Ok(false),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Reads the next packet, acknowledging it unless no-ack mode is on. A bare
+    /// ^C comes back as a packet of its own.
+    fn read_packet(&self, stream: &mut TcpStream) -> io::Result<Option<String>> {
+        let mut byte = [0u8];
+        loop {
+            if stream.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            match byte[0] {
+                0x03 => return Ok(Some("\u{3}".to_string())),
+                b'$' => {}
+                _ => continue,
+            }
+
+            let mut data = Vec::new();
+            loop {
+                if stream.read(&mut byte)? == 0 {
+                    return Ok(None);
+                }
+                if byte[0] == b'#' {
+                    break;
+                }
+                data.push(byte[0]);
+            }
+            let mut sum = [0u8; 2];
+            stream.read_exact(&mut sum)?;
+
+            let data = String::from_utf8_lossy(&data).into_owned();
+            let expected = std::str::from_utf8(&sum)
+                .ok()
+                .and_then(|sum| u8::from_str_radix(sum, 16).ok());
+            if self.no_ack {
+                return Ok(Some(data));
+            }
+            if expected == Some(checksum(&data)) {
+                stream.write_all(b"+")?;
+                return Ok(Some(data));
+            }
+            stream.write_all(b"-")?;
+        }
+    }
+
+    fn write_packet(stream: &mut TcpStream, data: &str) -> io::Result<()> {
+        write!(stream, "${}#{:02x}", data, checksum(data))?;
+        stream.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::BufReader;
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn packet(data: &str) -> String {
+        format!("${}#{:02x}", data, checksum(data))
+    }
+
+    /// Sends a packet and returns gdb's view of the reply, checking the ack.
+    fn exchange(stream: &mut BufReader<TcpStream>, data: &str) -> String {
+        stream.get_mut().write_all(packet(data).as_bytes()).unwrap();
+
+        let mut byte = [0u8];
+        stream.read_exact(&mut byte).unwrap();
+        assert_eq!(b'+', byte[0]);
+
+        let mut reply = Vec::new();
+        loop {
+            stream.read_exact(&mut byte).unwrap();
+            if byte[0] == b'#' {
+                break;
+            }
+            reply.push(byte[0]);
+        }
+        let mut sum = [0u8; 2];
+        stream.read_exact(&mut sum).unwrap();
+        stream.get_mut().write_all(b"+").unwrap();
+
+        String::from_utf8(reply).unwrap()[1..].to_string()
+    }
+
+    #[test]
+    fn scripted_session() {
+        // mov cx, 3 / dec cx / jne $-1 / hlt
+        let debugger = Debugger::new(&[0xB9, 0x03, 0x00, 0x49, 0x75, 0xFD, 0xF4], 0, 0).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            GdbServer::new(debugger).serve(stream).unwrap();
+        });
+
+        let mut client = BufReader::new(TcpStream::connect(address).unwrap());
+        assert!(exchange(&mut client, "qSupported:swbreak+").contains("PacketSize"));
+        assert_eq!("S05", exchange(&mut client, "?"));
+        assert_eq!("b903", exchange(&mut client, "m0,2"));
+
+        assert_eq!("S05", exchange(&mut client, "s"));
+        assert_eq!("03000000", exchange(&mut client, "p1"));
+        assert_eq!("03000000", exchange(&mut client, "p8"));
+
+        assert_eq!("OK", exchange(&mut client, "Z0,6,1"));
+        assert_eq!("S05", exchange(&mut client, "c"));
+        let registers = exchange(&mut client, "g");
+        assert_eq!(16 * 8, registers.len());
+        assert_eq!("00000000", &registers[8..16]);
+        assert_eq!("06000000", &registers[64..72]);
+
+        assert_eq!("OK", exchange(&mut client, "P1=0500"));
+        assert_eq!("OK", exchange(&mut client, "M10,2:beef"));
+        assert_eq!("beef", exchange(&mut client, "m10,2"));
+
+        // Addresses wrap at 1MB, and nothing bigger than a packet is read
+        assert_eq!("b903", exchange(&mut client, "m100000,2"));
+        assert_eq!("00b9", exchange(&mut client, "mffffffffffffffff,2"));
+        assert_eq!("OK", exchange(&mut client, "Mfffff,2:1234"));
+        assert_eq!("12", exchange(&mut client, "mfffff,1"));
+        assert_eq!("34", exchange(&mut client, "m0,1"));
+        assert_eq!("OK", exchange(&mut client, "M0,1:b9"));
+        assert_eq!("E01", exchange(&mut client, "m0,ffffffffff"));
+        assert_eq!("E01", exchange(&mut client, "m0,2001"));
+        assert_eq!(0x4000, exchange(&mut client, "m0,2000").len());
+        assert_eq!("E01", exchange(&mut client, "m10000000000000000,2"));
+        assert_eq!("OK", exchange(&mut client, "Z2,fffffffffffffffe,ffff"));
+        assert_eq!("OK", exchange(&mut client, "z2,fffffffffffffffe,ffff"));
+        assert_eq!("S05", exchange(&mut client, "bs"));
+        assert_eq!("04000000", exchange(&mut client, "p8"));
+        assert_eq!("T05replaylog:begin;", exchange(&mut client, "bc"));
+        assert_eq!("00000000", exchange(&mut client, "p8"));
+
+        assert_eq!("OK", exchange(&mut client, "z0,6,1"));
+        assert_eq!("W00", exchange(&mut client, "c"));
+        assert_eq!("OK", exchange(&mut client, "D"));
+
+        server.join().unwrap();
+    }
+}
@@ -3,10 +3,77 @@
 #![allow(non_snake_case)]
 
 use std::mem::MaybeUninit;
-use std::{borrow::Cow, ffi::CStr};
+use std::{borrow::Cow, ffi::CStr, fmt};
+
+pub mod asm;
+pub mod cfg;
+pub mod coverage;
+pub mod cycles;
+pub mod debugger;
+pub mod disasm;
+pub mod encode;
+pub mod expr;
+pub mod gdb;
+pub mod lockstep;
+pub mod profile;
+pub mod simulator;
+pub mod singlestep;
+pub mod snapshot;
+pub mod text;
+pub mod trace;
+pub mod trace_text;
 
 include!(concat!(env!("OUT_DIR"), "/sim86_shared.rs"));
 
+// The reference executor, from the shim that build.rs compiles next to the
+// library. Its state is opaque, reached through the accessors below.
+#[repr(C)]
+pub struct sim86_reference {
+    _private: [u8; 0],
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct exec_result {
+    pub ShiftCount: u32_,
+    pub RepCount: u32_,
+    pub BranchTaken: b32,
+    pub AddressIsUnaligned: b32,
+    pub Unimplemented: b32,
+}
+
+extern "C" {
+    pub fn Sim86Reference_Create() -> *mut sim86_reference;
+    pub fn Sim86Reference_Destroy(Reference: *mut sim86_reference);
+    pub fn Sim86Reference_Registers(Reference: *mut sim86_reference) -> *mut u16_;
+    pub fn Sim86Reference_Memory(Reference: *mut sim86_reference) -> *mut u8_;
+    pub fn Sim86Reference_MemorySize() -> u32_;
+    pub fn Sim86Reference_ExecInstruction(
+        Reference: *mut sim86_reference,
+        Instruction: *mut instruction,
+        Dest: *mut exec_result,
+    ) -> *const ::std::os::raw::c_char;
+}
+
+// The shared header doesn't export the register enum, so these mirror the
+// values of `register_access::Index` produced by the decoder.
+pub const Register_none: register_index = 0;
+pub const Register_a: register_index = 1;
+pub const Register_b: register_index = 2;
+pub const Register_c: register_index = 3;
+pub const Register_d: register_index = 4;
+pub const Register_sp: register_index = 5;
+pub const Register_bp: register_index = 6;
+pub const Register_si: register_index = 7;
+pub const Register_di: register_index = 8;
+pub const Register_es: register_index = 9;
+pub const Register_cs: register_index = 10;
+pub const Register_ss: register_index = 11;
+pub const Register_ds: register_index = 12;
+pub const Register_ip: register_index = 13;
+pub const Register_flags: register_index = 14;
+pub const Register_count: register_index = 15;
+
 pub fn get_version() -> u32 {
     unsafe { Sim86_GetVersion() }
 }
@@ -20,7 +87,31 @@ pub fn get_8086_instruction_table() -> instruction_table {
     }
 }
 
-pub fn decode_8086_instruction(source: &[u8]) -> Option<instruction> {
+// The decoder reads a 16-byte window, wrapping offsets past its end.
+const DECODE_WINDOW: usize = 16;
+
+fn is_prefix_byte(byte: u8) -> bool {
+    matches!(byte, 0xF0 | 0xF2 | 0xF3 | 0x26 | 0x2E | 0x36 | 0x3E)
+}
+
+/// Decodes the instruction at the start of `source`. One that would need
+/// more bytes than `source` has is `Truncated`, rather than decoded as though
+/// zeros followed.
+pub fn decode_8086_instruction(source: &[u8]) -> Result<instruction, DecodeError> {
+    let available = source.len();
+
+    // The library copies input shorter than 15 bytes into a zeroed guard
+    // buffer, but reads exactly 15 bytes in place, one past the end when a
+    // run of prefixes leads up to it. Padding here keeps every read inside
+    // memory we own.
+    let mut padded = [0u8; DECODE_WINDOW];
+    let source = if source.len() < DECODE_WINDOW {
+        padded[..source.len()].copy_from_slice(source);
+        &padded[..]
+    } else {
+        source
+    };
+
     // We know for sure that the call to decode the instruction isn't mutating
     // the slice, so casting away the const to the same memory region should
     // be safe
@@ -37,17 +128,166 @@ pub fn decode_8086_instruction(source: &[u8]) -> Option<instruction> {
         decoded_uninitialised.assume_init()
     };
 
-    if decoded.Op != operation_type_Op_None {
-        Some(decoded)
-    } else {
-        None
+    // After a long run of prefixes an instruction can run past the window,
+    // and its size wraps along with the offsets, to no more than the
+    // prefixes alone. A run of nothing but prefixes comes back as the last
+    // of them. Neither is an instruction.
+    let prefixes = source
+        .iter()
+        .take_while(|byte| is_prefix_byte(**byte))
+        .count();
+    let prefix_only = matches!(
+        decoded.Op,
+        operation_type_Op_lock | operation_type_Op_rep | operation_type_Op_segment
+    );
+    if decoded.Op == operation_type_Op_None || prefix_only || decoded.Size as usize <= prefixes {
+        return Err(DecodeError::Unrecognized { offset: 0 });
+    }
+    if decoded.Size as usize > available {
+        return Err(DecodeError::Truncated {
+            offset: 0,
+            needed: decoded.Size as usize,
+            available,
+        });
     }
+    Ok(decoded)
+}
+
+/// An instruction found by `decode_stream`.
+#[derive(Clone, Copy)]
+pub struct Decoded<'a> {
+    /// Where the instruction starts: the stream's base address plus its
+    /// position in the input.
+    pub offset: usize,
+    pub size: usize,
+    pub bytes: &'a [u8],
+    pub instruction: instruction,
+}
+
+/// Why an instruction couldn't be decoded. Offsets count from the start of
+/// the input to `decode_8086_instruction`, or from the base address of a
+/// `decode_stream`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The bytes at `offset` aren't an instruction.
+    Unrecognized { offset: usize },
+    /// The instruction at `offset` is `needed` bytes long, but the input
+    /// ends `available` bytes in.
+    Truncated {
+        offset: usize,
+        needed: usize,
+        available: usize,
+    },
+}
+
+impl DecodeError {
+    /// The same error, for an instruction at `offset` instead of 0.
+    fn starting_at(self, offset: usize) -> Self {
+        match self {
+            DecodeError::Unrecognized { .. } => DecodeError::Unrecognized { offset },
+            DecodeError::Truncated {
+                needed, available, ..
+            } => DecodeError::Truncated {
+                offset,
+                needed,
+                available,
+            },
+        }
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Unrecognized { offset } => {
+                write!(
+                    f,
+                    "Unrecognized binary in instruction stream at 0x{:x}",
+                    offset
+                )
+            }
+            DecodeError::Truncated {
+                offset,
+                needed,
+                available,
+            } => write!(
+                f,
+                "Instruction at 0x{:x} needs {} bytes, but only {} are left",
+                offset, needed, available
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Decodes `source` from its first byte, one instruction after another, as
+/// though it were loaded at `base_addr`. The stream ends after the last
+/// instruction or at the first error, since nothing past a byte that doesn't
+/// decode can be trusted to start on an instruction.
+pub fn decode_stream(
+    source: &[u8],
+    base_addr: usize,
+) -> impl Iterator<Item = Result<Decoded<'_>, DecodeError>> {
+    let mut at = 0usize;
+    std::iter::from_fn(move || {
+        let rest = source.get(at..).filter(|rest| !rest.is_empty())?;
+        let offset = base_addr + at;
+        let result = decode_8086_instruction(rest)
+            .map(|instruction| Decoded {
+                offset,
+                size: instruction.Size as usize,
+                bytes: &rest[..instruction.Size as usize],
+                instruction,
+            })
+            .map_err(|err| err.starting_at(offset));
+        at = match &result {
+            Ok(decoded) => at + decoded.size,
+            Err(_) => source.len(),
+        };
+        Some(result)
+    })
 }
 
 pub fn mnemonic_from_operation_type(op: operation_type) -> Cow<'static, str> {
     unsafe { CStr::from_ptr(Sim86_MnemonicFromOperationType(op)).to_string_lossy() }
 }
 
+pub fn register_name_from_operand(access: &register_access) -> Cow<'static, str> {
+    // The lookup only reads through the pointer, same as the decode call above
+    let mut_ptr = access as *const register_access as *mut register_access;
+
+    unsafe { CStr::from_ptr(Sim86_RegisterNameFromOperand(mut_ptr)).to_string_lossy() }
+}
+
+/// The `register_index` of a 16-bit register name such as "ax" or "flags".
+pub fn register_index_from_name(name: &str) -> Option<register_index> {
+    (1..Register_count).find(|index| {
+        let access = register_access {
+            Index: *index,
+            Offset: 0,
+            Count: 2,
+        };
+        register_name_from_operand(&access) == name
+    })
+}
+
+/// Where a jump, loop or call with a relative displacement goes, given the
+/// address the instruction was decoded from.
+pub fn relative_jump_target(address: usize, inst: &instruction) -> Option<usize> {
+    let operand = inst.Operands[0];
+    if operand.Type != operand_type_Operand_Immediate {
+        return None;
+    }
+    let immediate = unsafe { operand.__bindgen_anon_1.Immediate };
+    if (immediate.Flags & immediate_flag_Immediate_RelativeJumpDisplacement) == 0 {
+        return None;
+    }
+
+    let target = address as i64 + inst.Size as i64 + immediate.Value as i64;
+    usize::try_from(target).ok()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -57,4 +297,118 @@ mod tests {
         let version = get_version();
         assert_eq!(version, SIM86_VERSION);
     }
+
+    #[test]
+    fn register_names_match_shared() {
+        let wide = register_access {
+            Index: Register_c,
+            Offset: 0,
+            Count: 2,
+        };
+        let high = register_access {
+            Index: Register_c,
+            Offset: 1,
+            Count: 1,
+        };
+        let flags = register_access {
+            Index: Register_flags,
+            Offset: 0,
+            Count: 2,
+        };
+
+        assert_eq!(register_name_from_operand(&wide), "cx");
+        assert_eq!(register_name_from_operand(&high), "ch");
+        assert_eq!(register_name_from_operand(&flags), "flags");
+        assert_eq!(register_index_from_name("flags"), Some(Register_flags));
+        assert_eq!(register_index_from_name("ch"), None);
+    }
+
+    #[test]
+    fn fifteen_byte_input_decodes_in_bounds() {
+        // 13 lock prefixes before an add fill the 15 bytes exactly, while
+        // 14 leave the add needing bytes past the end
+        let mut source = [0xF0u8; 15];
+        source[13..].copy_from_slice(&[0x00, 0xC8]);
+        assert_eq!(
+            Ok((operation_type_Op_add, 15)),
+            decode_8086_instruction(&source).map(|inst| (inst.Op, inst.Size))
+        );
+        source[13..].copy_from_slice(&[0xF0, 0x81]);
+        assert!(decode_8086_instruction(&source).is_err());
+    }
+
+    #[test]
+    fn rejects_instructions_cut_off_by_prefixes() {
+        // jp after 14 segment prefixes crosses the decoder's window
+        let mut source = [0x2Eu8; 20];
+        source[14..16].copy_from_slice(&[0x7A, 0x7A]);
+        assert!(decode_8086_instruction(&source).is_err());
+        // Nothing but prefixes, where two fewer leave room for an add
+        assert!(decode_8086_instruction(&[0xF0; 15]).is_err());
+        assert_eq!(
+            Some(DecodeError::Truncated {
+                offset: 0,
+                needed: 15,
+                available: 13,
+            }),
+            decode_8086_instruction(&[0xF0; 13]).err()
+        );
+    }
+
+    #[test]
+    fn reports_truncated_instructions() {
+        // mov ax, imm16 with one byte of the immediate missing
+        assert_eq!(
+            Some(DecodeError::Truncated {
+                offset: 0,
+                needed: 3,
+                available: 2,
+            }),
+            decode_8086_instruction(&[0xB8, 0x34]).err()
+        );
+        assert_eq!(
+            Ok(3),
+            decode_8086_instruction(&[0xB8, 0x34, 0x12]).map(|inst| inst.Size)
+        );
+        assert_eq!(
+            Some(DecodeError::Unrecognized { offset: 0 }),
+            decode_8086_instruction(&[0x60]).err()
+        );
+    }
+
+    #[test]
+    fn streams_instructions_past_64k() {
+        // 70000 nops, then mov ax, 1 cut off after its first byte
+        let mut source = vec![0x90; 70000];
+        source.push(0xB8);
+        let mut stream = decode_stream(&source, 0x100);
+        let nops = stream.by_ref().take_while(Result::is_ok).count();
+        assert_eq!(70000, nops);
+        assert!(stream.next().is_none());
+
+        let last = decode_stream(&source, 0x100).nth(70000).unwrap();
+        assert_eq!(
+            Some(DecodeError::Truncated {
+                offset: 0x100 + 70000,
+                needed: 3,
+                available: 1,
+            }),
+            last.err()
+        );
+
+        let decoded: Vec<(usize, &[u8])> = decode_stream(&[0x01, 0xD8, 0x50, 0x60], 0x10)
+            .map_while(Result::ok)
+            .map(|decoded| (decoded.offset, decoded.bytes))
+            .collect();
+        assert_eq!(
+            vec![(0x10, &[0x01, 0xD8][..]), (0x12, &[0x50][..])],
+            decoded
+        );
+        assert_eq!(
+            Some(DecodeError::Unrecognized { offset: 0x13 }),
+            decode_stream(&[0x01, 0xD8, 0x50, 0x60], 0x10)
+                .nth(2)
+                .and_then(Result::err)
+        );
+    }
 }
@@ -0,0 +1,333 @@
+use std::ffi::CStr;
+use std::fmt;
+use std::ptr::NonNull;
+
+use crate::simulator::{ExecResult, Hooks, Registers, Simulator, MEM_LEN};
+use crate::text::{flags_text, wide_register};
+use crate::*;
+
+/// Why the reference executor didn't run an instruction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReferenceError {
+    /// An instruction the reference can't run, which can be skipped by
+    /// syncing it with `Simulator` afterwards.
+    Unimplemented(operation_type),
+    /// One of the reference's own assertions failed, given as its file, line
+    /// and expression. Its state can't be trusted after that.
+    AssertionFailed(String),
+}
+
+impl fmt::Display for ReferenceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReferenceError::Unimplemented(op) => write!(
+                f,
+                "The reference simulator can't run {}",
+                mnemonic_from_operation_type(*op)
+            ),
+            ReferenceError::AssertionFailed(assertion) => write!(
+                f,
+                "The reference simulator failed an assertion: {}",
+                assertion
+            ),
+        }
+    }
+}
+
+/// The reference simulator's executor, with registers and memory of its own,
+/// for running side by side with `Simulator` and comparing the two.
+pub struct ReferenceSimulator {
+    state: NonNull<sim86_reference>,
+}
+
+impl Default for ReferenceSimulator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ReferenceSimulator {
+    pub fn new() -> Self {
+        // The state is zeroed, like a fresh Simulator
+        let state = NonNull::new(unsafe { Sim86Reference_Create() })
+            .expect("reference simulator state should allocate");
+        assert_eq!(MEM_LEN, unsafe { Sim86Reference_MemorySize() } as usize);
+        Self { state }
+    }
+
+    /// A reference simulator with the same registers and memory as
+    /// `simulator`.
+    pub fn matching<H: Hooks>(simulator: &Simulator<H>) -> Self {
+        let mut reference = Self::new();
+        reference.sync(simulator);
+        reference
+    }
+
+    /// Copies the registers and memory of `simulator`, such as to carry on
+    /// past an instruction the reference can't run.
+    pub fn sync<H: Hooks>(&mut self, simulator: &Simulator<H>) {
+        self.set_registers(simulator.registers());
+        self.memory_mut().copy_from_slice(simulator.memory());
+    }
+
+    fn register_slots(&mut self) -> &mut [u16] {
+        // Indexed by register_index, with slot 0 always zero
+        unsafe {
+            let slots = Sim86Reference_Registers(self.state.as_ptr());
+            std::slice::from_raw_parts_mut(slots, Register_count as usize)
+        }
+    }
+
+    pub fn registers(&self) -> Registers {
+        let slots = unsafe {
+            let slots = Sim86Reference_Registers(self.state.as_ptr());
+            std::slice::from_raw_parts(slots, Register_count as usize)
+        };
+        let mut registers = Registers::default();
+        for index in Register_a..Register_count {
+            registers.set(index, slots[index as usize]);
+        }
+        registers
+    }
+
+    pub fn set_registers(&mut self, registers: &Registers) {
+        let slots = self.register_slots();
+        for index in Register_a..Register_count {
+            slots[index as usize] = registers.get(index);
+        }
+    }
+
+    pub fn memory(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(Sim86Reference_Memory(self.state.as_ptr()), MEM_LEN) }
+    }
+
+    pub fn memory_mut(&mut self) -> &mut [u8] {
+        unsafe {
+            std::slice::from_raw_parts_mut(Sim86Reference_Memory(self.state.as_ptr()), MEM_LEN)
+        }
+    }
+
+    /// Moves ip past `inst` and executes it, as `Simulator` does. Far
+    /// address operands come back unimplemented, since the reference can't
+    /// read them.
+    pub fn execute_instruction(
+        &mut self,
+        inst: &instruction,
+    ) -> Result<ExecResult, ReferenceError> {
+        let mut copy = *inst;
+        let mut result = exec_result::default();
+        let assertion = unsafe {
+            let assertion =
+                Sim86Reference_ExecInstruction(self.state.as_ptr(), &mut copy, &mut result);
+            (!assertion.is_null()).then(|| CStr::from_ptr(assertion).to_string_lossy())
+        };
+        if let Some(assertion) = assertion {
+            return Err(ReferenceError::AssertionFailed(assertion.into_owned()));
+        }
+        if result.Unimplemented != 0 {
+            return Err(ReferenceError::Unimplemented(inst.Op));
+        }
+        Ok(ExecResult {
+            shift_count: result.ShiftCount,
+            rep_count: result.RepCount,
+            branch_taken: result.BranchTaken != 0,
+            address_is_unaligned: result.AddressIsUnaligned != 0,
+        })
+    }
+}
+
+impl Drop for ReferenceSimulator {
+    fn drop(&mut self) {
+        unsafe { Sim86Reference_Destroy(self.state.as_ptr()) }
+    }
+}
+
+/// How the Rust simulator and the reference disagree after an instruction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    /// Each register that differs, with the Rust value and then the
+    /// reference's.
+    pub registers: Vec<(register_index, u16, u16)>,
+    /// The first byte of memory that differs: its address, the Rust value and
+    /// the reference's.
+    pub memory: Option<(usize, u8, u8)>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (index, ours, theirs) in &self.registers {
+            let name = register_name_from_operand(&wide_register(*index));
+            if *index == Register_flags {
+                writeln!(
+                    f,
+                    "    {}: {} here, {} in the reference",
+                    name,
+                    flags_text(*ours),
+                    flags_text(*theirs)
+                )?;
+            } else {
+                writeln!(
+                    f,
+                    "    {}: 0x{:04x} here, 0x{:04x} in the reference",
+                    name, ours, theirs
+                )?;
+            }
+        }
+        if let Some((address, ours, theirs)) = self.memory {
+            writeln!(
+                f,
+                "    [0x{:05x}]: 0x{:02x} here, 0x{:02x} in the reference",
+                address, ours, theirs
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn differing_registers(ours: &Registers, theirs: &Registers) -> Vec<(register_index, u16, u16)> {
+    (Register_a..Register_count)
+        .map(|index| (index, ours.get(index), theirs.get(index)))
+        .filter(|(_, ours, theirs)| ours != theirs)
+        .collect()
+}
+
+fn first_memory_difference<H: Hooks>(
+    simulator: &Simulator<H>,
+    reference: &ReferenceSimulator,
+) -> Option<(usize, u8, u8)> {
+    if simulator.memory() == reference.memory() {
+        return None;
+    }
+    simulator
+        .memory()
+        .iter()
+        .zip(reference.memory())
+        .enumerate()
+        .find(|(_, (ours, theirs))| ours != theirs)
+        .map(|(address, (ours, theirs))| (address, *ours, *theirs))
+}
+
+/// Where `simulator` and `reference` differ after an instruction that wrote
+/// `written` on the Rust side, as `WriteLog` collects them. Only those bytes
+/// of memory are checked, so that each step costs what it wrote rather than
+/// all 1MB. Once something differs, all of memory is swept for the first
+/// byte that does. A write only the reference makes goes unnoticed until
+/// `compare_all`.
+pub fn compare<H: Hooks>(
+    simulator: &Simulator<H>,
+    reference: &ReferenceSimulator,
+    written: &[(usize, u8)],
+) -> Option<Divergence> {
+    let registers = differing_registers(simulator.registers(), &reference.registers());
+    let (ours, theirs) = (simulator.memory(), reference.memory());
+    let wrote_differently = written
+        .iter()
+        .any(|(address, _)| ours[*address] != theirs[*address]);
+    if registers.is_empty() && !wrote_differently {
+        return None;
+    }
+
+    Some(Divergence {
+        registers,
+        memory: first_memory_difference(simulator, reference),
+    })
+}
+
+/// Where `simulator` and `reference` differ, if anywhere. All of memory is
+/// compared, so a write either side makes to the wrong place shows up too.
+pub fn compare_all<H: Hooks>(
+    simulator: &Simulator<H>,
+    reference: &ReferenceSimulator,
+) -> Option<Divergence> {
+    let registers = differing_registers(simulator.registers(), &reference.registers());
+    let memory = first_memory_difference(simulator, reference);
+    (!registers.is_empty() || memory.is_some()).then_some(Divergence { registers, memory })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trace::WriteLog;
+
+    fn run(program: &[u8]) -> Option<Divergence> {
+        let mut simulator = Simulator::with_hooks(WriteLog::default());
+        let mut reference = ReferenceSimulator::matching(&simulator);
+        let mut at = 0;
+        while let Ok(inst) = decode_8086_instruction(&program[at..]) {
+            simulator.execute_instruction(&inst).unwrap();
+            reference.execute_instruction(&inst).unwrap();
+            if let Some(divergence) = compare(&simulator, &reference, simulator.hooks().writes()) {
+                return Some(divergence);
+            }
+            at = simulator.registers().ip() as usize;
+            if at >= program.len() {
+                break;
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn matches_the_reference() {
+        // mov sp, 0x100 / mov ax, 0x1234 / push ax / add al, 0xF0 / mov [bx+2], ax
+        let program = [
+            0xBC, 0x00, 0x01, 0xB8, 0x34, 0x12, 0x50, 0x04, 0xF0, 0x89, 0x47, 0x02,
+        ];
+        assert_eq!(None, run(&program));
+    }
+
+    #[test]
+    fn reports_what_differs() {
+        let mut simulator = Simulator::new();
+        let mut reference = ReferenceSimulator::matching(&simulator);
+        simulator.registers_mut().set(Register_c, 3);
+        reference.memory_mut()[0x1234] = 0x56;
+        assert_eq!(
+            Some(Divergence {
+                registers: vec![(Register_c, 3, 0)],
+                memory: Some((0x1234, 0, 0x56)),
+            }),
+            compare(&simulator, &reference, &[])
+        );
+        simulator.registers_mut().set(Register_c, 0);
+
+        // Memory nobody wrote on the Rust side only shows in a full sweep
+        assert_eq!(None, compare(&simulator, &reference, &[]));
+        assert_eq!(
+            Some(Divergence {
+                registers: Vec::new(),
+                memory: Some((0x1234, 0, 0x56)),
+            }),
+            compare(&simulator, &reference, &[(0x1234, 0)])
+        );
+        assert_eq!(
+            compare(&simulator, &reference, &[(0x1234, 0)]),
+            compare_all(&simulator, &reference)
+        );
+
+        // Far jumps can't be run by the reference at all
+        let jump = decode_8086_instruction(&[0xEA, 0x00, 0x00, 0x00, 0x10]).unwrap();
+        assert_eq!(
+            Err(ReferenceError::Unimplemented(operation_type_Op_jmp)),
+            reference.execute_instruction(&jump)
+        );
+    }
+
+    #[test]
+    fn reports_failed_assertions() {
+        // mov ax, bx, with bx read from a register byte that doesn't exist
+        let mut inst = decode_8086_instruction(&[0x89, 0xD8]).unwrap();
+        inst.Operands[1].__bindgen_anon_1.Register.Offset = 2;
+
+        let mut reference = ReferenceSimulator::new();
+        let Err(ReferenceError::AssertionFailed(assertion)) = reference.execute_instruction(&inst)
+        else {
+            panic!("the reference should fail an assertion");
+        };
+        assert!(assertion.contains("Register.Offset <= 1"), "{}", assertion);
+
+        // The next instruction runs normally
+        let inst = decode_8086_instruction(&[0x89, 0xD8]).unwrap();
+        assert!(reference.execute_instruction(&inst).is_ok());
+    }
+}
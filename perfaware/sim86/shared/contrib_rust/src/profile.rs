@@ -0,0 +1,260 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::cycles::ClockInterval;
+use crate::simulator::ExecResult;
+use crate::text::instruction_text;
+use crate::*;
+
+/// Clocks added up over a whole run, which can outgrow `ClockInterval`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ClockTotal {
+    pub min: u64,
+    pub max: u64,
+}
+
+impl ClockTotal {
+    fn add(&mut self, other: ClockTotal) {
+        self.min += other.min;
+        self.max += other.max;
+    }
+
+    /// Percentage of `total`, going by the minimum estimate.
+    pub fn share_of(&self, total: &ClockTotal) -> f64 {
+        if total.min == 0 {
+            0.0
+        } else {
+            self.min as f64 * 100.0 / total.min as f64
+        }
+    }
+}
+
+fn clock_total_text(clocks: &ClockTotal) -> String {
+    if clocks.min != clocks.max {
+        format!("[{},{}]", clocks.min, clocks.max)
+    } else {
+        format!("{}", clocks.min)
+    }
+}
+
+/// Everything recorded for one instruction address.
+#[derive(Clone, Copy)]
+pub struct ProfileLine {
+    pub instruction: instruction,
+    pub count: u64,
+    /// Times the instruction branched, for jumps and loops.
+    pub taken: u64,
+    pub clocks: ClockTotal,
+}
+
+/// A backward branch and the instructions it jumps back over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProfileLoop {
+    /// Absolute addresses from the branch target up to and including the
+    /// branch.
+    pub start: usize,
+    pub end: usize,
+    /// Times the branch was taken.
+    pub iterations: u64,
+    pub clocks: ClockTotal,
+}
+
+/// Execution counts and estimated clocks per instruction address over a run.
+#[derive(Default)]
+pub struct Profile {
+    lines: BTreeMap<usize, ProfileLine>,
+    total: ClockTotal,
+}
+
+impl Profile {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(
+        &mut self,
+        address: usize,
+        inst: &instruction,
+        result: &ExecResult,
+        clocks: ClockInterval,
+    ) {
+        let clocks = ClockTotal {
+            min: clocks.min as u64,
+            max: clocks.max as u64,
+        };
+        let line = self.lines.entry(address).or_insert(ProfileLine {
+            instruction: *inst,
+            count: 0,
+            taken: 0,
+            clocks: ClockTotal::default(),
+        });
+        line.count += 1;
+        line.taken += result.branch_taken as u64;
+        line.clocks.add(clocks);
+        self.total.add(clocks);
+    }
+
+    pub fn lines(&self) -> &BTreeMap<usize, ProfileLine> {
+        &self.lines
+    }
+
+    pub fn total(&self) -> ClockTotal {
+        self.total
+    }
+
+    pub fn instruction_count(&self) -> u64 {
+        self.lines.values().map(|line| line.count).sum()
+    }
+
+    /// The `count` instructions that took the most clocks, most first.
+    pub fn hot_spots(&self, count: usize) -> Vec<(usize, &ProfileLine)> {
+        let mut lines: Vec<(usize, &ProfileLine)> = self
+            .lines
+            .iter()
+            .map(|(address, line)| (*address, line))
+            .collect();
+        lines.sort_by(|a, b| b.1.clocks.min.cmp(&a.1.clocks.min).then(a.0.cmp(&b.0)));
+        lines.truncate(count);
+        lines
+    }
+
+    /// Every taken backward branch, as a loop, in address order. Nested loops
+    /// each get their own entry, and the outer one includes the inner.
+    pub fn loops(&self) -> Vec<ProfileLoop> {
+        let mut loops: Vec<ProfileLoop> = self
+            .lines
+            .iter()
+            .filter(|(_, line)| line.taken > 0)
+            .filter_map(|(address, line)| {
+                let start = relative_jump_target(*address, &line.instruction)?;
+                let end = address + line.instruction.Size as usize;
+                (start <= *address).then_some(ProfileLoop {
+                    start,
+                    end,
+                    iterations: line.taken,
+                    clocks: ClockTotal::default(),
+                })
+            })
+            .collect();
+
+        for found in &mut loops {
+            for line in self
+                .lines
+                .range(found.start..found.end)
+                .map(|(_, line)| line)
+            {
+                found.clocks.add(line.clocks);
+            }
+        }
+        loops.sort_by_key(|found| (found.start, found.end));
+        loops
+    }
+
+    /// Annotated disassembly with each line's share of the clocks, followed
+    /// by the hot spots and the loops.
+    pub fn report(&self, hot_spots: usize) -> String {
+        let mut text = String::new();
+        let _ = writeln!(
+            text,
+            "Profile: {} instructions, {} clocks",
+            self.instruction_count(),
+            clock_total_text(&self.total)
+        );
+        let _ = writeln!(
+            text,
+            "{:>10} {:>12} {:>7}  instruction",
+            "count", "clocks", "share"
+        );
+        for (address, line) in &self.lines {
+            let _ = writeln!(
+                text,
+                "{:>10} {:>12} {:>6.2}%  0x{:05x}: {}",
+                line.count,
+                clock_total_text(&line.clocks),
+                line.clocks.share_of(&self.total),
+                address,
+                instruction_text(&line.instruction).trim_end()
+            );
+        }
+
+        let _ = writeln!(text, "\nHot spots:");
+        for (address, line) in self.hot_spots(hot_spots) {
+            let _ = writeln!(
+                text,
+                "{:>6.2}%  0x{:05x}: {} ({} clocks over {} executions)",
+                line.clocks.share_of(&self.total),
+                address,
+                instruction_text(&line.instruction).trim_end(),
+                clock_total_text(&line.clocks),
+                line.count
+            );
+        }
+
+        let loops = self.loops();
+        if !loops.is_empty() {
+            let _ = writeln!(text, "\nLoops:");
+        }
+        for found in loops {
+            let _ = writeln!(
+                text,
+                "{:>6.2}%  0x{:05x}-0x{:05x}: {} clocks, {} iterations",
+                found.clocks.share_of(&self.total),
+                found.start,
+                found.end - 1,
+                clock_total_text(&found.clocks),
+                found.iterations
+            );
+        }
+
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::Simulator;
+
+    fn profile_of(code: &[u8]) -> Profile {
+        let mut simulator = Simulator::new();
+        let mut profile = Profile::new();
+        let mut offset = 0usize;
+        while offset < code.len() {
+            let inst = decode_8086_instruction(&code[offset..]).unwrap();
+            let result = simulator.execute_instruction(&inst).unwrap();
+            // A flat cost keeps the arithmetic in the test obvious
+            let clocks = ClockInterval { min: 2, max: 2 };
+            profile.record(offset, &inst, &result, clocks);
+            offset = simulator.registers().ip() as usize;
+        }
+        profile
+    }
+
+    #[test]
+    fn counts_lines_and_loops() {
+        // mov cx, 3 / dec cx / jne $-1 / hlt
+        let profile = profile_of(&[0xB9, 0x03, 0x00, 0x49, 0x75, 0xFD, 0xF4]);
+        assert_eq!(8, profile.instruction_count());
+        assert_eq!(ClockTotal { min: 16, max: 16 }, profile.total());
+        assert_eq!(3, profile.lines()[&3].count);
+        assert_eq!(2, profile.lines()[&4].taken);
+
+        assert_eq!(
+            vec![ProfileLoop {
+                start: 3,
+                end: 6,
+                iterations: 2,
+                clocks: ClockTotal { min: 12, max: 12 },
+            }],
+            profile.loops()
+        );
+
+        let hot: Vec<usize> = profile
+            .hot_spots(2)
+            .iter()
+            .map(|(address, _)| *address)
+            .collect();
+        assert_eq!(vec![3, 4], hot);
+        assert!(profile.report(5).contains(" 37.50%  0x00003: dec cx"));
+    }
+}
@@ -1,402 +1,1197 @@
-use sim86_shared::*;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::hash::{BuildHasherDefault, Hasher};
+
+use crate::*;
 
 const REG_LEN: usize = 8;
 const BIU_LEN: usize = 5;
-const MEM_LEN: usize = u16::MAX as usize;
+pub const MEM_LEN: usize = 1 << 20;
+const MEM_MASK: u32 = (MEM_LEN - 1) as u32;
+const SEGMENT_LEN: usize = 1 << 16;
+
+// Bytes read from CS:IP for each fetch: the decoder's whole window, which
+// holds the longest instruction with room to spare.
+const FETCH_WINDOW: usize = 16;
+
+// Enough bytes to show all of an instruction that fails to decode, bar
+// prefixes.
+const LONGEST_INSTRUCTION: usize = 6;
+
+/// The most bytes any instruction can take, prefixes and all.
+pub const MAX_INSTRUCTION_SIZE: usize = 15;
+
+pub const CARRY_FLAG: u16 = 0x0001u16;
+pub const PARITY_FLAG: u16 = 0x0004u16;
+pub const AUX_CARRY_FLAG: u16 = 0x0010u16;
+pub const ZERO_FLAG: u16 = 0x0040u16;
+pub const SIGNED_FLAG: u16 = 0x0080u16;
+pub const TRAP_FLAG: u16 = 0x0100u16;
+pub const INTERRUPT_FLAG: u16 = 0x0200u16;
+pub const DIRECTION_FLAG: u16 = 0x0400u16;
+pub const OVERFLOW_FLAG: u16 = 0x0800u16;
+
+pub(crate) const FLAG_MASK_8086: u16 = CARRY_FLAG
+    | PARITY_FLAG
+    | AUX_CARRY_FLAG
+    | ZERO_FLAG
+    | SIGNED_FLAG
+    | TRAP_FLAG
+    | INTERRUPT_FLAG
+    | DIRECTION_FLAG
+    | OVERFLOW_FLAG;
 
-const PARITY_FLAG: u16 = 0x0004u16;
-const SIGNED_FLAG: u16 = 0x0080u16;
-const ZERO_FLAG: u16 = 0x0040u16;
-const OVERFLOW_FLAG: u16 = 0x0800u16;
+// Bits of the flags register that have no flag in them and always read as
+// set on the 8086: bit 1 and bits 12 to 15.
+const FLAG_BITS_ALWAYS_SET: u16 = 0xF002;
 
-struct Registers {
-    pub(crate) arr: Vec<u16>,
+// The flags that were in the 8080, which is all that lahf/sahf move around.
+const FLAG_MASK_OLD_8080: u16 = CARRY_FLAG | PARITY_FLAG | AUX_CARRY_FLAG | ZERO_FLAG | SIGNED_FLAG;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Registers {
+    pub(crate) arr: [u16; REG_LEN],
     pub(crate) flags: u16,
-    pub(crate) biu: Vec<u16>,
+    pub(crate) biu: [u16; BIU_LEN],
+}
+
+impl Registers {
+    /// Full 16-bit value of a register by its `register_index`. Index 0 is
+    /// the "no register" slot and always reads as zero.
+    pub fn get(&self, index: register_index) -> u16 {
+        let idx = index as usize;
+        match index {
+            Register_a..=Register_di => self.arr[idx - 1],
+            Register_es..=Register_ip => self.biu[idx - (REG_LEN + 1)],
+            Register_flags => self.flags,
+            _ => 0,
+        }
+    }
+
+    pub fn set(&mut self, index: register_index, value: u16) {
+        let idx = index as usize;
+        match index {
+            Register_a..=Register_di => self.arr[idx - 1] = value,
+            Register_es..=Register_ip => self.biu[idx - (REG_LEN + 1)] = value,
+            Register_flags => self.flags = value,
+            _ => {}
+        }
+    }
+
+    pub fn flags(&self) -> u16 {
+        self.flags
+    }
+
+    pub fn ip(&self) -> u16 {
+        self.get(Register_ip)
+    }
+
+    pub fn cs(&self) -> u16 {
+        self.get(Register_cs)
+    }
+
+    /// Reads through a decoded register operand, so `ah` gives the high
+    /// byte of `ax` and so on.
+    pub fn read(&self, access: register_access) -> u16 {
+        let val = self.get(access.Index);
+        if access.Count == 2 {
+            val
+        } else if access.Offset == 0 {
+            val & 0x00FF
+        } else {
+            val >> 8
+        }
+    }
+
+    pub fn write(&mut self, access: register_access, value: u16) {
+        let val = if access.Count == 2 {
+            value
+        } else if access.Offset == 0 {
+            (self.get(access.Index) & 0xFF00) | (value & 0x00FF)
+        } else {
+            (self.get(access.Index) & 0x00FF) | (value << 8)
+        };
+        self.set(access.Index, val);
+    }
+}
+
+/// What the timing estimate needs to know about an instruction that has
+/// just been executed, mirroring `exec_result` in the reference simulator.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ExecResult {
+    pub shift_count: u32,
+    pub rep_count: u32,
+    pub branch_taken: bool,
+    pub address_is_unaligned: bool,
+}
+
+/// Why the simulator couldn't carry on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SimError {
+    /// The bytes at an absolute address aren't an instruction.
+    DecodeError { address: usize, bytes: Vec<u8> },
+    /// An operation the simulator can't run, or one needing a device that
+    /// isn't attached.
+    Unimplemented(operation_type),
+    /// Code that doesn't fit in its segment. The address is the first byte
+    /// past the end of the segment, before wrapping at 1MB.
+    MemoryFault(usize),
+    /// The processor is stopped at a hlt.
+    Halted,
+    /// The step limit ran out after this many instructions.
+    StepLimit(u64),
 }
 
-#[allow(dead_code)]
-pub(crate) struct Simulator {
+impl fmt::Display for SimError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SimError::DecodeError { address, bytes } => {
+                let bytes: Vec<String> = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+                write!(
+                    f,
+                    "No instruction decodes from the bytes at 0x{:05x} ({})",
+                    address,
+                    bytes.join(" ")
+                )
+            }
+            SimError::Unimplemented(op) => write!(
+                f,
+                "Unimplemented instruction ({})",
+                mnemonic_from_operation_type(*op)
+            ),
+            SimError::MemoryFault(address) => write!(
+                f,
+                "Code runs past the end of its segment at 0x{:x}",
+                address
+            ),
+            SimError::Halted => write!(f, "The processor is halted"),
+            SimError::StepLimit(steps) => write!(f, "Step limit of {} reached", steps),
+        }
+    }
+}
+
+impl std::error::Error for SimError {}
+
+#[derive(Debug, Clone, Copy)]
+enum Operand {
+    None,
+    Register(register_access),
+    Memory { segment: u16, offset: u16 },
+    Far { segment: u16, offset: u16 },
+    Immediate(i32),
+}
+
+/// Everything one instruction changed, so that it can be undone.
+#[derive(Debug, Clone)]
+pub(crate) struct UndoRecord {
     registers: Registers,
-    memory: Vec<u8>,
+    halted: bool,
+    /// Absolute address and previous value of each byte written, in order.
+    pub(crate) memory: Vec<(usize, u8)>,
+}
+
+/// A byte written by an instruction in the recorded history.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryWrite {
+    /// Position of the instruction in the history, counting from zero at the
+    /// oldest instruction still recorded.
+    pub step: usize,
+    pub instruction_address: usize,
+    pub old: u8,
+}
+
+/// Observes a `Simulator` as it runs. Every method has an empty default, so
+/// an implementation only overrides what it cares about; the default
+/// `NoHooks` compiles down to nothing.
+#[allow(unused_variables)]
+pub trait Hooks {
+    /// Called before each instruction that `Simulator::implements`, with the
+    /// registers as they were before it ran.
+    fn before_instruction(&mut self, registers: &Registers, inst: &instruction) {}
+
+    /// Called after each instruction that `before_instruction` saw.
+    fn after_instruction(
+        &mut self,
+        registers: &Registers,
+        inst: &instruction,
+        result: &ExecResult,
+    ) {
+    }
+
+    /// A byte read from memory by an executing instruction. Instruction
+    /// fetches aren't reported.
+    fn memory_read(&mut self, address: usize, value: u8) {}
+
+    fn memory_write(&mut self, address: usize, old: u8, new: u8) {}
+
+    /// Value for an `in` from PORT. Returning None, as the default does,
+    /// leaves `in` unimplemented: with no devices attached there is nothing
+    /// to read, which matches the reference simulator.
+    fn port_in(&mut self, port: u16, wide: bool) -> Option<u16> {
+        None
+    }
+
+    /// Takes the value of an `out` to PORT. Returns false, as the default
+    /// does, to leave `out` unimplemented.
+    fn port_out(&mut self, port: u16, wide: bool, value: u16) -> bool {
+        false
+    }
+
+    /// Called for int, int3 and a taken into, before the flags and return
+    /// address are pushed.
+    fn interrupt(&mut self, vector: u8) {}
 }
 
-unsafe fn into_u8_ptr(x: *mut u16, high: bool) -> *mut u8 {
-    let ptr: *mut u8 = x.cast();
-    if high {
-        return ptr;
+/// Hashes an absolute address with a single multiply, which is plenty for
+/// keys that are already well spread and much cheaper than the default.
+#[derive(Default)]
+struct AddressHasher(u64);
+
+impl Hasher for AddressHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 << 8) | *byte as u64;
+        }
+    }
+
+    fn write_usize(&mut self, value: usize) {
+        self.0 = (value as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    }
+}
+
+/// Instructions already decoded from memory, by absolute address, so a loop
+/// is only decoded the first time round. A bit per byte of memory marks the
+/// bytes any cached instruction came from, so a write only has to look for
+/// instructions to drop when it lands on one. Bits are only cleared along
+/// with the whole cache: one left behind just costs a needless look.
+#[derive(Default)]
+pub(crate) struct DecodeCache {
+    instructions: HashMap<usize, instruction, BuildHasherDefault<AddressHasher>>,
+    code: Vec<u64>,
+}
+
+impl DecodeCache {
+    fn get(&self, address: usize) -> Option<&instruction> {
+        self.instructions.get(&address)
+    }
+
+    fn insert(&mut self, address: usize, inst: instruction) {
+        if self.code.is_empty() {
+            self.code = vec![0; MEM_LEN / 64];
+        }
+        for at in address..address + inst.Size as usize {
+            self.code[at / 64] |= 1 << (at % 64);
+        }
+        self.instructions.insert(address, inst);
+    }
+
+    /// Drops every instruction decoded from the byte at `address`.
+    fn invalidate(&mut self, address: usize) {
+        let marked = self
+            .code
+            .get(address / 64)
+            .is_some_and(|bits| bits & (1 << (address % 64)) != 0);
+        if !marked {
+            return;
+        }
+        for start in address.saturating_sub(MAX_INSTRUCTION_SIZE - 1)..=address {
+            if self
+                .instructions
+                .get(&start)
+                .is_some_and(|inst| start + inst.Size as usize > address)
+            {
+                self.instructions.remove(&start);
+            }
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.instructions.clear();
+        self.code.fill(0);
+    }
+}
+
+/// Hooks that observe nothing.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoHooks;
+
+impl Hooks for NoHooks {}
+
+pub struct Simulator<H: Hooks = NoHooks> {
+    pub(crate) registers: Registers,
+    pub(crate) memory: Vec<u8>,
+    pub(crate) halted: bool,
+    pub(crate) history: Option<VecDeque<UndoRecord>>,
+    history_limit: usize,
+    pub(crate) decode_cache: Option<DecodeCache>,
+    hooks: H,
+}
+
+impl Default for Simulator {
+    fn default() -> Self {
+        Self::new()
     }
-    return ptr.add(1);
 }
 
-#[allow(non_upper_case_globals)]
 impl Simulator {
     pub fn new() -> Self {
+        Self::with_hooks(NoHooks)
+    }
+}
+
+/// The 20-bit physical address of SEGMENT:OFFSET, wrapping at 1MB.
+pub fn absolute_address(segment: u16, offset: u16) -> usize {
+    ((((segment as u32) << 4) + offset as u32) & MEM_MASK) as usize
+}
+
+fn sign_bit(wide: bool) -> u32 {
+    if wide {
+        0x8000
+    } else {
+        0x80
+    }
+}
+
+fn width_mask(wide: bool) -> u32 {
+    if wide {
+        0xFFFF
+    } else {
+        0xFF
+    }
+}
+
+#[allow(non_upper_case_globals)]
+impl<H: Hooks> Simulator<H> {
+    pub fn with_hooks(hooks: H) -> Self {
         Self {
-            registers: Registers {
-                arr: vec![0u16; REG_LEN],
-                flags: 0u16,
-                biu: vec![0u16; BIU_LEN],
-            },
+            registers: Registers::default(),
             memory: vec![0u8; MEM_LEN],
+            halted: false,
+            history: None,
+            history_limit: usize::MAX,
+            decode_cache: Some(DecodeCache::default()),
+            hooks,
         }
     }
 
-    pub fn execute_instruction(&mut self, inst: &instruction) -> u16 {
-        self.registers.biu[4] += inst.Size as u16;
-        match inst.Op {
-            operation_type_Op_mov => unsafe {
-                self.execute_mov(inst);
-            },
-            operation_type_Op_add => unsafe {
-                if (inst.Flags & 8u32) == 8 {
-                    self.execute_arithmetic(inst);
-                } else {
-                    self.execute_u8_arithmetic(inst);
-                }
-            },
-            operation_type_Op_sub => unsafe {
-                if (inst.Flags & 8u32) == 8 {
-                    self.execute_arithmetic(inst);
-                } else {
-                    self.execute_u8_arithmetic(inst);
-                }
-            },
-            operation_type_Op_cmp => unsafe {
-                if (inst.Flags & 8u32) == 8 {
-                    self.execute_arithmetic(inst);
-                } else {
-                    self.execute_u8_arithmetic(inst);
-                }
-            },
-            operation_type_Op_jne => unsafe {
-                self.cnd_jmp(inst, ZERO_FLAG, false);
-            },
-            operation_type_Op_je => unsafe {
-                self.cnd_jmp(inst, ZERO_FLAG, true);
-            },
-            operation_type_Op_jnp => unsafe {
-                self.cnd_jmp(inst, PARITY_FLAG, false);
-            },
-            operation_type_Op_jp => unsafe {
-                self.cnd_jmp(inst, PARITY_FLAG, true);
-            },
-            operation_type_Op_jnb => unsafe {
-                self.cnd_jmp(inst, OVERFLOW_FLAG, false);
-            },
-            operation_type_Op_jb => unsafe {
-                self.cnd_jmp(inst, OVERFLOW_FLAG, true);
-            },
-            operation_type_Op_loopnz => unsafe {
-                self.cx_loop(inst, false);
-            },
-            operation_type_Op_loopz => unsafe {
-                self.cx_loop(inst, true);
-            }
-            _ => {
-                unimplemented!();
+    pub fn hooks(&self) -> &H {
+        &self.hooks
+    }
+
+    pub fn hooks_mut(&mut self) -> &mut H {
+        &mut self.hooks
+    }
+
+    pub fn registers(&self) -> &Registers {
+        &self.registers
+    }
+
+    pub fn registers_mut(&mut self) -> &mut Registers {
+        &mut self.registers
+    }
+
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    /// Memory to change by hand. Any of it might be code, so decoded
+    /// instructions are forgotten.
+    pub fn memory_mut(&mut self) -> &mut [u8] {
+        if let Some(cache) = &mut self.decode_cache {
+            cache.clear();
+        }
+        &mut self.memory
+    }
+
+    /// Starts or stops caching decoded instructions, which `fetch_instruction`
+    /// does by default. Writes to memory drop exactly the instructions they
+    /// change, so turning it off only matters for measuring what it saves.
+    pub fn cache_decoding(&mut self, enabled: bool) {
+        self.decode_cache = enabled.then(DecodeCache::default);
+    }
+
+    pub fn halted(&self) -> bool {
+        self.halted
+    }
+
+    /// Starts or stops keeping an undo log of every executed instruction.
+    /// Starting discards any history already recorded.
+    pub fn record_history(&mut self, enabled: bool) {
+        self.history = enabled.then(VecDeque::new);
+    }
+
+    /// Keeps only the `limit` most recent instructions in the history,
+    /// forgetting the oldest as new ones run. There is no limit by default.
+    pub fn limit_history(&mut self, limit: usize) {
+        self.history_limit = limit;
+        if let Some(history) = &mut self.history {
+            let excess = history.len().saturating_sub(limit);
+            history.drain(..excess);
+        }
+    }
+
+    pub fn history_limit(&self) -> usize {
+        self.history_limit
+    }
+
+    /// How many executed instructions can be undone with `step_back`.
+    pub fn history_len(&self) -> usize {
+        self.history.as_ref().map_or(0, VecDeque::len)
+    }
+
+    /// Undoes the most recently executed instruction. Returns false if there
+    /// is no recorded history left.
+    pub fn step_back(&mut self) -> bool {
+        let Some(record) = self.history.as_mut().and_then(VecDeque::pop_back) else {
+            return false;
+        };
+
+        for (address, old) in record.memory.iter().rev() {
+            self.memory[*address] = *old;
+            if let Some(cache) = &mut self.decode_cache {
+                cache.invalidate(*address);
             }
+        }
+        self.registers = record.registers;
+        self.halted = record.halted;
+
+        true
+    }
+
+    /// The most recent instruction in the history that wrote `address`.
+    pub fn last_writer(&self, address: usize) -> Option<MemoryWrite> {
+        let history = self.history.as_ref()?;
+        history.iter().enumerate().rev().find_map(|(step, record)| {
+            // The first write in the record holds the value from before it
+            let (_, old) = record.memory.iter().find(|(at, _)| *at == address)?;
+            Some(MemoryWrite {
+                step,
+                instruction_address: absolute_address(record.registers.cs(), record.registers.ip()),
+                old: *old,
+            })
+        })
+    }
+
+    /// Absolute address of CS:IP, where the next instruction comes from.
+    pub fn instruction_address(&self) -> usize {
+        absolute_address(self.registers.cs(), self.registers.ip())
+    }
+
+    /// Whether `execute_instruction` knows how to run this operation at all.
+    pub fn implements(op: operation_type) -> bool {
+        !matches!(
+            op,
+            operation_type_Op_None
+                | operation_type_Op_aaa
+                | operation_type_Op_daa
+                | operation_type_Op_aas
+                | operation_type_Op_das
+                | operation_type_Op_mul
+                | operation_type_Op_imul
+                | operation_type_Op_aam
+                | operation_type_Op_div
+                | operation_type_Op_idiv
+                | operation_type_Op_aad
+                | operation_type_Op_movs
+                | operation_type_Op_cmps
+                | operation_type_Op_scas
+                | operation_type_Op_lods
+                | operation_type_Op_stos
+                | operation_type_Op_esc
+        ) && op < operation_type_Op_Count
+    }
+
+    /// Copies `program` into memory at SEGMENT:OFFSET, where CS:IP reaches
+    /// it. A program that would run past the end of the segment isn't
+    /// loaded, since ip would wrap back to the segment's start rather than
+    /// reach the rest of it.
+    pub fn load(&mut self, segment: u16, offset: u16, program: &[u8]) -> Result<(), SimError> {
+        if offset as usize + program.len() > SEGMENT_LEN {
+            return Err(SimError::MemoryFault(
+                ((segment as usize) << 4) + SEGMENT_LEN,
+            ));
+        }
+        let memory = self.memory_mut();
+        for (at, byte) in program.iter().enumerate() {
+            memory[absolute_address(segment, offset.wrapping_add(at as u16))] = *byte;
+        }
+        Ok(())
+    }
+
+    /// The `len` bytes of code at SEGMENT:OFFSET, wrapping at the end of the
+    /// segment as ip does.
+    pub fn code_bytes_at(&self, segment: u16, offset: u16, len: usize) -> Vec<u8> {
+        (0..len)
+            .map(|at| self.memory[absolute_address(segment, offset.wrapping_add(at as u16))])
+            .collect()
+    }
+
+    /// The `len` bytes of code at CS:IP.
+    pub fn code_bytes(&self, len: usize) -> Vec<u8> {
+        self.code_bytes_at(self.registers.cs(), self.registers.ip(), len)
+    }
+
+    /// Decodes the instruction at SEGMENT:OFFSET as `fetch_instruction`
+    /// would with CS:IP there, but without the decode cache.
+    pub fn decode_at(&self, segment: u16, offset: u16) -> Result<instruction, SimError> {
+        let window = self.code_bytes_at(segment, offset, FETCH_WINDOW);
+        decode_8086_instruction(&window).map_err(|_| SimError::DecodeError {
+            address: absolute_address(segment, offset),
+            bytes: window[..LONGEST_INSTRUCTION].to_vec(),
+        })
+    }
+
+    /// Decodes the instruction at CS:IP from memory, so code the program has
+    /// written over runs as it now is.
+    pub fn fetch_instruction(&mut self) -> Result<instruction, SimError> {
+        let address = self.instruction_address();
+        // An instruction that wraps at the end of the segment, or of memory,
+        // isn't in consecutive bytes at its address, so it isn't cached
+        let fits = |inst: &instruction| {
+            let end = self.registers.ip() as usize + inst.Size as usize;
+            end <= SEGMENT_LEN && address + inst.Size as usize <= MEM_LEN
         };
+        if let Some(inst) = self
+            .decode_cache
+            .as_ref()
+            .and_then(|cache| cache.get(address))
+        {
+            if fits(inst) {
+                return Ok(*inst);
+            }
+        }
 
-        println!(
-            "{:0>4X?}{:0>4X?}[{:0>4X}]",
-            self.registers.arr, self.registers.biu, self.registers.flags
-        );
+        let inst = self.decode_at(self.registers.cs(), self.registers.ip())?;
+        if fits(&inst) {
+            if let Some(cache) = &mut self.decode_cache {
+                cache.insert(address, inst);
+            }
+        }
+        Ok(inst)
+    }
 
-        return self.registers.biu[4];
+    /// Runs one instruction, leaving the machine untouched if it can't.
+    pub fn execute_instruction(&mut self, inst: &instruction) -> Result<ExecResult, SimError> {
+        if self.halted {
+            return Err(SimError::Halted);
+        }
+        if !Self::implements(inst.Op) {
+            return Err(SimError::Unimplemented(inst.Op));
+        }
+
+        self.hooks.before_instruction(&self.registers, inst);
+        let result = self.execute(inst)?;
+        self.hooks.after_instruction(&self.registers, inst, &result);
+        Ok(result)
     }
 
-    unsafe fn execute_mov(&mut self, inst: &instruction) -> () {
-        let src_inst = inst.Operands[1];
-        let dst_inst = inst.Operands[0];
-        let wide = (inst.Flags & 8u32) == 8;
-        let dst = self.u16_ptr(dst_inst);
+    fn execute(&mut self, inst: &instruction) -> Result<ExecResult, SimError> {
+        let mut result = ExecResult::default();
+        let before = self.registers;
+        let limit = self.history_limit;
+        if let Some(history) = self.history.as_mut().filter(|_| limit > 0) {
+            if history.len() == limit {
+                history.pop_front();
+            }
+            history.push_back(UndoRecord {
+                registers: self.registers,
+                halted: self.halted,
+                memory: Vec::new(),
+            });
+        }
 
-        match src_inst.Type {
-            operand_type_Operand_Register => {
-                if wide {
-                    let idx = src_inst.__bindgen_anon_1.Register.Index as usize;
-                    *dst = *self.register_ptr(idx);
-                } else {
-                    let idx = src_inst.__bindgen_anon_1.Register.Index as usize;
-                    let high_src = src_inst.__bindgen_anon_1.Register.Offset == 0;
-                    let high_dst = dst_inst.__bindgen_anon_1.Register.Offset == 0;
-                    let val = *into_u8_ptr(self.register_ptr(idx), high_src);
-                    // Shadow 16bit pointer.
-                    let dst = into_u8_ptr(dst, high_dst);
-                    *dst = val;
+        let ip = self.registers.ip().wrapping_add(inst.Size as u16);
+        self.registers.set(Register_ip, ip);
+
+        let wide = (inst.Flags & instruction_flag_Inst_Wide) != 0;
+        let dst = self.operand(inst, 0);
+        let src = self.operand(inst, 1);
+        for op in [dst, src] {
+            if let Operand::Memory { offset, .. } = op {
+                result.address_is_unaligned |= (offset & 1) == 1;
+            }
+        }
+
+        match inst.Op {
+            operation_type_Op_mov => {
+                let val = self.read(src, wide);
+                self.write(dst, wide, val);
+            }
+            operation_type_Op_push => {
+                let val = self.read(dst, true);
+                self.push(val);
+            }
+            operation_type_Op_pop => {
+                let val = self.pop();
+                self.write(dst, true, val);
+            }
+            operation_type_Op_xchg => {
+                let a = self.read(dst, wide);
+                let b = self.read(src, wide);
+                self.write(dst, wide, b);
+                self.write(src, wide, a);
+            }
+            operation_type_Op_xlat => {
+                let segment = self.segment_for(inst, Register_ds);
+                let offset = self
+                    .registers
+                    .get(Register_b)
+                    .wrapping_add(self.registers.get(Register_a) & 0xFF);
+                let val = self.read_u8(segment, offset);
+                self.registers.write(
+                    register_access {
+                        Index: Register_a,
+                        Offset: 0,
+                        Count: 1,
+                    },
+                    val as u16,
+                );
+            }
+            operation_type_Op_lea => {
+                if let Operand::Memory { offset, .. } = src {
+                    self.write(dst, true, offset);
                 }
             }
-            operand_type_Operand_Immediate => {
-                if wide {
-                    *dst = src_inst.__bindgen_anon_1.Immediate.Value as u16;
-                } else {
-                    let high_dst = dst_inst.__bindgen_anon_1.Register.Offset == 0;
-                    let dst = into_u8_ptr(dst, high_dst);
-                    *dst = src_inst.__bindgen_anon_1.Immediate.Value as u8;
+            operation_type_Op_lds | operation_type_Op_les => {
+                if let Operand::Memory { segment, offset } = src {
+                    let val = self.read_u16(segment, offset);
+                    let seg = self.read_u16(segment, offset.wrapping_add(2));
+                    self.write(dst, true, val);
+                    let seg_index = if inst.Op == operation_type_Op_lds {
+                        Register_ds
+                    } else {
+                        Register_es
+                    };
+                    self.registers.set(seg_index, seg);
                 }
-            },
-            operand_type_Operand_Memory => {
-                let address = src_inst.__bindgen_anon_1.Address;
-                let src = self.memory_ptr(address.Displacement as usize);
-                *dst = *src;
-            }
-            _ => {
-                panic!("No legal destination for a mov.")
-            }
-        }
-    }
-
-    unsafe fn execute_arithmetic(&mut self, inst: &instruction) -> () {
-        let src_inst = inst.Operands[1];
-        let dst_inst = inst.Operands[0];
-        let dst = self.u16_ptr(dst_inst);
-        let mut alu: i32;
-        match src_inst.Type {
-            operand_type_Operand_Register => {
-                let val = *self.register_ptr(src_inst.__bindgen_anon_1.Register.Index as usize);
-                match inst.Op {
-                    operation_type_Op_add => unsafe {
-                        alu = (*dst as i32) << 8;
-                        alu = alu + ((val as i32) << 8);
-                        *dst = ((alu << 8) >> 16) as u16;
-                    }
-                    operation_type_Op_cmp => unsafe {
-                        alu = (*dst as i32) << 8;
-                        alu = alu - ((val as i32) << 8);
-                    }
-                    operation_type_Op_sub => unsafe {
-                        alu = (*dst as i32) << 8;
-                        alu = alu - ((val as i32) << 8);
-                        *dst = ((alu << 8) >> 16) as u16;
-                    }
-                    _ => {
-                        panic!("Illegal operation type");
-                    }
+            }
+            operation_type_Op_lahf => {
+                let ah = register_access {
+                    Index: Register_a,
+                    Offset: 1,
+                    Count: 1,
                 };
+                self.registers
+                    .write(ah, self.registers.flags & FLAG_MASK_OLD_8080);
             }
-            operand_type_Operand_Immediate => {
-                let val = src_inst.__bindgen_anon_1.Immediate.Value as u16;
-                match inst.Op {
-                    operation_type_Op_add => unsafe {
-                        alu = (*dst as i32) << 8;
-                        alu = alu + ((val as i32) << 8);
-                        *dst = ((alu << 8) >> 16) as u16;
-                    }
-                    operation_type_Op_cmp => unsafe {
-                        alu = (*dst as i32) << 8;
-                        alu = alu - ((val as i32) << 8);
-                    }
-                    operation_type_Op_sub => unsafe {
-                        alu = (*dst as i32) << 8;
-                        alu = alu - ((val as i32) << 8);
-                        *dst = ((alu << 8) >> 16) as u16;
-                    }
-                    _ => {
-                        panic!("Illegal operation type");
-                    }
+            operation_type_Op_sahf => {
+                let ah = register_access {
+                    Index: Register_a,
+                    Offset: 1,
+                    Count: 1,
                 };
+                let val = self.registers.read(ah) & FLAG_MASK_OLD_8080;
+                self.registers.flags = (self.registers.flags & !FLAG_MASK_OLD_8080) | val;
             }
-            _ => {
-                panic!("Illegal operand for op.")
+            operation_type_Op_pushf => {
+                self.push(self.pushed_flags());
             }
-        };
-
-        self.set_zero_flag(alu as u32);
-        self.set_signed_flag(alu as u32);
-        self.set_overflow_flag(alu as u32);
-        self.set_parity_flag(alu as u32);
-    }
-
-    unsafe fn execute_u8_arithmetic(&mut self, inst: &instruction) -> () {
-        let src_inst = inst.Operands[1];
-        let dst_inst = inst.Operands[0];
-        let dst = self.u16_ptr(dst_inst);
-        let mut alu: u32;
-
-        match src_inst.Type {
-            operand_type_Operand_Register => {
-                let reg_index = src_inst.__bindgen_anon_1.Register.Index as usize;
-                let high_src = src_inst.__bindgen_anon_1.Register.Offset == 0;
-                let val = *into_u8_ptr(self.register_ptr(reg_index), high_src);
-                let high_dst = dst_inst.__bindgen_anon_1.Register.Offset == 0;
-                let sub_dst = into_u8_ptr(dst, high_dst);
-                match inst.Op {
-                    operation_type_Op_add => {
-                        alu = (*sub_dst as u32) << 8;
-                        alu = alu + ((val as u32) << 8);
-                        *sub_dst = ((alu << 16) >> 24) as u8;
-                    }
-                    operation_type_Op_cmp => {
-                        alu = (*sub_dst as u32) << 8;
-                        alu = alu - ((val as u32) << 8);
-                    }
-                    operation_type_Op_sub => {
-                        alu = (*sub_dst as u32) << 8;
-                        alu = alu - ((val as u32) << 8);
-                        *sub_dst = ((alu << 16) >> 24) as u8;
-                    }
-                    _ => {
-                        panic!("Illegal operation type");
-                    }
+            operation_type_Op_popf => {
+                self.registers.flags = self.pop() & FLAG_MASK_8086;
+            }
+            operation_type_Op_add | operation_type_Op_adc => {
+                let carry = if inst.Op == operation_type_Op_adc {
+                    self.carry()
+                } else {
+                    0
                 };
+                let (a, b) = (self.read(dst, wide), self.read(src, wide));
+                let val = self.add_with_flags(a as u32, b as u32, carry, wide);
+                self.write(dst, wide, val);
             }
-            operand_type_Operand_Immediate => {
-                let sub_dst = into_u8_ptr(dst, dst_inst.__bindgen_anon_1.Register.Offset == 0);
-                let val = src_inst.__bindgen_anon_1.Immediate.Value as u8;
-                match inst.Op {
-                    operation_type_Op_add => {
-                        alu = (*sub_dst as u32) << 8;
-                        alu = alu + ((val as u32) << 8);
-                        *sub_dst = ((alu << 16) >> 24) as u8;
-                    }
-                    operation_type_Op_cmp => {
-                        alu = (*sub_dst as u32) << 8;
-                        alu = alu - ((val as u32) << 8);
-                    }
-                    operation_type_Op_sub => {
-                        alu = (*sub_dst as u32) << 8;
-                        alu = alu - ((val as u32) << 8);
-                        *sub_dst = ((alu << 16) >> 24) as u8;
-                    }
-                    _ => {
-                        panic!("Illegal operation type");
-                    }
+            operation_type_Op_sub | operation_type_Op_sbb => {
+                let borrow = if inst.Op == operation_type_Op_sbb {
+                    self.carry()
+                } else {
+                    0
+                };
+                let (a, b) = (self.read(dst, wide), self.read(src, wide));
+                let val = self.sub_with_flags(a as u32, b as u32, borrow, wide);
+                self.write(dst, wide, val);
+            }
+            operation_type_Op_cmp => {
+                let (a, b) = (self.read(dst, wide), self.read(src, wide));
+                self.sub_with_flags(a as u32, b as u32, 0, wide);
+            }
+            operation_type_Op_inc | operation_type_Op_dec => {
+                // inc/dec are the only arithmetic ops that leave CF alone
+                let carry = self.registers.flags & CARRY_FLAG;
+                let a = self.read(dst, wide) as u32;
+                let val = if inst.Op == operation_type_Op_inc {
+                    self.add_with_flags(a, 1, 0, wide)
+                } else {
+                    self.sub_with_flags(a, 1, 0, wide)
+                };
+                self.registers.flags = (self.registers.flags & !CARRY_FLAG) | carry;
+                self.write(dst, wide, val);
+            }
+            operation_type_Op_neg => {
+                let a = self.read(dst, wide);
+                let val = self.sub_with_flags(0, a as u32, 0, wide);
+                self.write(dst, wide, val);
+            }
+            operation_type_Op_cbw => {
+                let al = self.registers.get(Register_a) & 0xFF;
+                let ax = if al & 0x80 != 0 { al | 0xFF00 } else { al };
+                self.registers.set(Register_a, ax);
+            }
+            operation_type_Op_cwd => {
+                let dx = if self.registers.get(Register_a) & 0x8000 != 0 {
+                    0xFFFF
+                } else {
+                    0
                 };
+                self.registers.set(Register_d, dx);
+            }
+            operation_type_Op_not => {
+                // not is the one logical op that doesn't touch the flags
+                let val = !self.read(dst, wide);
+                self.write(dst, wide, val);
+            }
+            operation_type_Op_and
+            | operation_type_Op_or
+            | operation_type_Op_xor
+            | operation_type_Op_test => {
+                let (a, b) = (self.read(dst, wide), self.read(src, wide));
+                let val = match inst.Op {
+                    operation_type_Op_or => a | b,
+                    operation_type_Op_xor => a ^ b,
+                    _ => a & b,
+                };
+                let val = self.logic_with_flags(val as u32, wide);
+                if inst.Op != operation_type_Op_test {
+                    self.write(dst, wide, val);
+                }
+            }
+            operation_type_Op_shl
+            | operation_type_Op_shr
+            | operation_type_Op_sar
+            | operation_type_Op_rol
+            | operation_type_Op_ror
+            | operation_type_Op_rcl
+            | operation_type_Op_rcr => {
+                let count = (self.read(src, false) & 0xFF) as u32;
+                let a = self.read(dst, wide);
+                let val = self.shift_with_flags(inst.Op, a as u32, count, wide);
+                self.write(dst, wide, val);
+                result.shift_count = count;
+            }
+            operation_type_Op_call | operation_type_Op_jmp => {
+                let far = (inst.Flags & instruction_flag_Inst_Far) != 0;
+                let (segment, offset) = match dst {
+                    Operand::Immediate(disp) => (self.registers.cs(), ip.wrapping_add(disp as u16)),
+                    Operand::Far { segment, offset } => (segment, offset),
+                    Operand::Memory { segment, offset } if far => (
+                        self.read_u16(segment, offset.wrapping_add(2)),
+                        self.read_u16(segment, offset),
+                    ),
+                    op => (self.registers.cs(), self.read(op, true)),
+                };
+                if inst.Op == operation_type_Op_call {
+                    if far || matches!(dst, Operand::Far { .. }) {
+                        self.push(self.registers.cs());
+                    }
+                    self.push(ip);
+                }
+                self.registers.set(Register_cs, segment);
+                self.registers.set(Register_ip, offset);
+            }
+            operation_type_Op_ret | operation_type_Op_retf => {
+                let ip = self.pop();
+                self.registers.set(Register_ip, ip);
+                if inst.Op == operation_type_Op_retf {
+                    let cs = self.pop();
+                    self.registers.set(Register_cs, cs);
+                }
+                if let Operand::Immediate(extra) = dst {
+                    let sp = self.registers.get(Register_sp).wrapping_add(extra as u16);
+                    self.registers.set(Register_sp, sp);
+                }
+            }
+            operation_type_Op_je => self.cnd_jmp(inst, self.flag(ZERO_FLAG), &mut result),
+            operation_type_Op_jne => self.cnd_jmp(inst, !self.flag(ZERO_FLAG), &mut result),
+            operation_type_Op_jl => self.cnd_jmp(
+                inst,
+                self.flag(SIGNED_FLAG) != self.flag(OVERFLOW_FLAG),
+                &mut result,
+            ),
+            operation_type_Op_jnl => self.cnd_jmp(
+                inst,
+                self.flag(SIGNED_FLAG) == self.flag(OVERFLOW_FLAG),
+                &mut result,
+            ),
+            operation_type_Op_jle => {
+                let taken =
+                    self.flag(ZERO_FLAG) || self.flag(SIGNED_FLAG) != self.flag(OVERFLOW_FLAG);
+                self.cnd_jmp(inst, taken, &mut result)
+            }
+            operation_type_Op_jg => {
+                let taken =
+                    !self.flag(ZERO_FLAG) && self.flag(SIGNED_FLAG) == self.flag(OVERFLOW_FLAG);
+                self.cnd_jmp(inst, taken, &mut result)
+            }
+            operation_type_Op_jb => self.cnd_jmp(inst, self.flag(CARRY_FLAG), &mut result),
+            operation_type_Op_jnb => self.cnd_jmp(inst, !self.flag(CARRY_FLAG), &mut result),
+            operation_type_Op_jbe => {
+                let taken = self.flag(CARRY_FLAG) || self.flag(ZERO_FLAG);
+                self.cnd_jmp(inst, taken, &mut result)
+            }
+            operation_type_Op_ja => {
+                let taken = !self.flag(CARRY_FLAG) && !self.flag(ZERO_FLAG);
+                self.cnd_jmp(inst, taken, &mut result)
+            }
+            operation_type_Op_jp => self.cnd_jmp(inst, self.flag(PARITY_FLAG), &mut result),
+            operation_type_Op_jnp => self.cnd_jmp(inst, !self.flag(PARITY_FLAG), &mut result),
+            operation_type_Op_jo => self.cnd_jmp(inst, self.flag(OVERFLOW_FLAG), &mut result),
+            operation_type_Op_jno => self.cnd_jmp(inst, !self.flag(OVERFLOW_FLAG), &mut result),
+            operation_type_Op_js => self.cnd_jmp(inst, self.flag(SIGNED_FLAG), &mut result),
+            operation_type_Op_jns => self.cnd_jmp(inst, !self.flag(SIGNED_FLAG), &mut result),
+            operation_type_Op_loop => self.cx_loop(inst, None, &mut result),
+            operation_type_Op_loopz => self.cx_loop(inst, Some(true), &mut result),
+            operation_type_Op_loopnz => self.cx_loop(inst, Some(false), &mut result),
+            operation_type_Op_jcxz => {
+                let taken = self.registers.get(Register_c) == 0;
+                self.cnd_jmp(inst, taken, &mut result)
+            }
+            operation_type_Op_in => {
+                // The port is either an imm8 or dx
+                let port = self.read(src, false);
+                match self.hooks.port_in(port, wide) {
+                    Some(val) => self.write(dst, wide, val),
+                    None => return Err(self.abandon(before, inst.Op)),
+                }
+            }
+            operation_type_Op_out => {
+                let port = self.read(dst, false);
+                let val = self.read(src, wide);
+                if !self.hooks.port_out(port, wide, val) {
+                    return Err(self.abandon(before, inst.Op));
+                }
+            }
+            operation_type_Op_int => {
+                if let Operand::Immediate(kind) = dst {
+                    self.interrupt(kind as u16);
+                }
             }
-            _ => {
-                panic!("Illegal operand for op.")
+            operation_type_Op_int3 => self.interrupt(3),
+            operation_type_Op_into if self.flag(OVERFLOW_FLAG) => self.interrupt(4),
+            operation_type_Op_iret => {
+                let ip = self.pop();
+                let cs = self.pop();
+                self.registers.set(Register_ip, ip);
+                self.registers.set(Register_cs, cs);
+                self.registers.flags = self.pop() & FLAG_MASK_8086;
             }
+            operation_type_Op_clc => self.set_flag(CARRY_FLAG, false),
+            operation_type_Op_cmc => self.set_flag(CARRY_FLAG, !self.flag(CARRY_FLAG)),
+            operation_type_Op_stc => self.set_flag(CARRY_FLAG, true),
+            operation_type_Op_cld => self.set_flag(DIRECTION_FLAG, false),
+            operation_type_Op_std => self.set_flag(DIRECTION_FLAG, true),
+            operation_type_Op_cli => self.set_flag(INTERRUPT_FLAG, false),
+            operation_type_Op_sti => self.set_flag(INTERRUPT_FLAG, true),
+            operation_type_Op_hlt => {
+                self.halted = true;
+            }
+            // wait only matters with a coprocessor attached, and the prefixes
+            // are folded into the following instruction by the decoder.
+            _ => {}
         };
 
-        self.set_zero_flag(alu);
-        self.set_signed_flag_u8(alu);
-        self.set_overflow_flag_u8(alu);
-        self.set_parity_flag(alu);
+        Ok(result)
     }
 
-    unsafe fn register_ptr(&mut self, idx: usize) -> *mut u16 {
-        if idx > REG_LEN {
-            if idx > REG_LEN + BIU_LEN {
-                panic!("illegal access");
-            }
-            let biu_index = idx - (REG_LEN + 1);
-            return self.registers.biu.as_mut_ptr().add(biu_index);
+    /// Puts back what `execute` did before finding out that the instruction
+    /// can't run after all, so it is reported as unimplemented with the
+    /// machine untouched.
+    fn abandon(&mut self, registers: Registers, op: operation_type) -> SimError {
+        self.registers = registers;
+        if let Some(history) = &mut self.history {
+            history.pop_back();
         }
-        return self.registers.arr.as_mut_ptr().add(idx - 1);
+        SimError::Unimplemented(op)
     }
 
-    unsafe fn memory_ptr(&mut self, idx: usize) -> *mut u16 {
-        if idx > MEM_LEN {
-            panic!("illegal access")
+    fn operand(&self, inst: &instruction, index: usize) -> Operand {
+        let operand = inst.Operands[index];
+        unsafe {
+            match operand.Type {
+                operand_type_Operand_Register => {
+                    Operand::Register(operand.__bindgen_anon_1.Register)
+                }
+                operand_type_Operand_Memory => {
+                    let address = operand.__bindgen_anon_1.Address;
+                    if (address.Flags & effective_address_flag_Address_ExplicitSegment) != 0 {
+                        return Operand::Far {
+                            segment: address.ExplicitSegment as u16,
+                            offset: address.Displacement as u16,
+                        };
+                    }
+
+                    let mut offset = address.Displacement as u16;
+                    for term in address.Terms {
+                        if term.Register.Index != Register_none {
+                            let val = self.registers.read(term.Register);
+                            offset = offset.wrapping_add((term.Scale as u16).wrapping_mul(val));
+                        }
+                    }
+
+                    let default = if address.Terms[0].Register.Index == Register_bp {
+                        Register_ss
+                    } else {
+                        Register_ds
+                    };
+                    let segment = self.segment_for(inst, default);
+                    Operand::Memory { segment, offset }
+                }
+                operand_type_Operand_Immediate => {
+                    Operand::Immediate(operand.__bindgen_anon_1.Immediate.Value)
+                }
+                _ => Operand::None,
+            }
         }
+    }
 
-        return self.memory[idx-1..idx].align_to_mut::<u16>().1.as_mut_ptr();
+    fn segment_for(&self, inst: &instruction, default: register_index) -> u16 {
+        if inst.SegmentOverride != Register_none {
+            self.registers.get(inst.SegmentOverride)
+        } else {
+            self.registers.get(default)
+        }
     }
 
-    unsafe fn u16_ptr(&mut self, inst: instruction_operand) -> *mut u16 {
-        match inst.Type {
-            operand_type_Operand_Register => {
-                let reg_index = inst.__bindgen_anon_1.Register.Index as usize;
-                self.register_ptr(reg_index)
+    fn read(&mut self, op: Operand, wide: bool) -> u16 {
+        match op {
+            Operand::Register(access) => self.registers.read(access),
+            Operand::Memory { segment, offset } => {
+                if wide {
+                    self.read_u16(segment, offset)
+                } else {
+                    self.read_u8(segment, offset) as u16
+                }
             }
-            operand_type_Operand_Memory => {
-                let address = inst.__bindgen_anon_1.Address;
+            Operand::Immediate(val) => (val as u32 & width_mask(wide)) as u16,
+            Operand::Far { offset, .. } => offset,
+            Operand::None => 0,
+        }
+    }
 
-                if address.Terms[0].Register.Index == 0 {
-                    let mem_index = address.Displacement as usize;
-                    return self.memory_ptr(mem_index);
+    fn write(&mut self, op: Operand, wide: bool, value: u16) {
+        match op {
+            Operand::Register(access) => self.registers.write(access, value),
+            Operand::Memory { segment, offset } => {
+                if wide {
+                    self.write_u16(segment, offset, value);
+                } else {
+                    self.write_u8(segment, offset, value as u8);
                 }
-                let idx = self.registers.arr[(address.Terms[0].Register.Index -1) as usize] as usize;
-                let idx = idx + address.Displacement as usize;
-                return self.memory_ptr(idx);
-            }
-            _ => {
-                panic!("No legal destination for a mov.")
             }
+            _ => {}
         }
     }
 
-    fn set_zero_flag(&mut self, result_val: u32) -> () {
-        self.registers.flags = if result_val == 0 {
-            self.registers.flags | ZERO_FLAG
-        } else {
-            self.registers.flags & (u16::MAX - ZERO_FLAG)
-        };
+    fn read_u8(&mut self, segment: u16, offset: u16) -> u8 {
+        let address = absolute_address(segment, offset);
+        let value = self.memory[address];
+        self.hooks.memory_read(address, value);
+        value
     }
 
-    fn set_signed_flag_u8(&mut self, alu: u32) -> () {
-        self.registers.flags = if (((alu >> 8) as u8) & 0x80) >> 7 == 1 {
-            self.registers.flags | SIGNED_FLAG
-        } else {
-            self.registers.flags & (u16::MAX - SIGNED_FLAG)
-        };
+    fn write_u8(&mut self, segment: u16, offset: u16, value: u8) {
+        let address = absolute_address(segment, offset);
+        if let Some(record) = self.history.as_mut().and_then(|history| history.back_mut()) {
+            record.memory.push((address, self.memory[address]));
+        }
+        self.hooks
+            .memory_write(address, self.memory[address], value);
+        self.memory[address] = value;
+        if let Some(cache) = &mut self.decode_cache {
+            cache.invalidate(address);
+        }
     }
 
-    fn set_signed_flag(&mut self, alu: u32) -> () {
-        self.registers.flags = if (((alu >> 8) as u16) & 0x8000) >> 15 == 1 {
-            self.registers.flags | SIGNED_FLAG
-        } else {
-            self.registers.flags & (u16::MAX - SIGNED_FLAG)
-        };
+    fn read_u16(&mut self, segment: u16, offset: u16) -> u16 {
+        let lo = self.read_u8(segment, offset) as u16;
+        let hi = self.read_u8(segment, offset.wrapping_add(1)) as u16;
+        lo | (hi << 8)
     }
 
-    fn set_parity_flag(&mut self, alu: u32) -> () {
-        let total = ((alu >> 8) >> 8).count_ones();
-        self.registers.flags = if total % 2 == 0 {
-            self.registers.flags | PARITY_FLAG
-        } else {
-            self.registers.flags & (u16::MAX - PARITY_FLAG)
-        }
+    fn write_u16(&mut self, segment: u16, offset: u16, value: u16) {
+        self.write_u8(segment, offset, value as u8);
+        self.write_u8(segment, offset.wrapping_add(1), (value >> 8) as u8);
     }
 
-    fn set_overflow_flag(&mut self, alu: u32) -> () {
-        self.registers.flags = if (alu >> 8) > u16::MAX as u32 {
-            self.registers.flags | OVERFLOW_FLAG
-        } else {
-            self.registers.flags & (u16::MAX - OVERFLOW_FLAG)
-        };
+    fn push(&mut self, value: u16) {
+        let sp = self.registers.get(Register_sp).wrapping_sub(2);
+        self.registers.set(Register_sp, sp);
+        self.write_u16(self.registers.get(Register_ss), sp, value);
+    }
+
+    fn pop(&mut self) -> u16 {
+        let sp = self.registers.get(Register_sp);
+        let val = self.read_u16(self.registers.get(Register_ss), sp);
+        self.registers.set(Register_sp, sp.wrapping_add(2));
+        val
+    }
+
+    /// The flags as pushf and interrupts store them.
+    fn pushed_flags(&self) -> u16 {
+        (self.registers.flags & FLAG_MASK_8086) | FLAG_BITS_ALWAYS_SET
+    }
+
+    fn interrupt(&mut self, kind: u16) {
+        self.hooks.interrupt(kind as u8);
+        self.push(self.pushed_flags());
+        self.push(self.registers.cs());
+        self.push(self.registers.ip());
+        self.registers.flags &= !(TRAP_FLAG | INTERRUPT_FLAG);
+
+        let vector = kind.wrapping_mul(4);
+        let ip = self.read_u16(0, vector);
+        let cs = self.read_u16(0, vector.wrapping_add(2));
+        self.registers.set(Register_ip, ip);
+        self.registers.set(Register_cs, cs);
     }
 
-    fn set_overflow_flag_u8(&mut self, alu: u32) -> () {
-        self.registers.flags = if (alu >> 8) > u8::MAX as u32 {
-            self.registers.flags | OVERFLOW_FLAG
+    fn flag(&self, flag: u16) -> bool {
+        (self.registers.flags & flag) != 0
+    }
+
+    fn carry(&self) -> u32 {
+        (self.registers.flags & CARRY_FLAG) as u32
+    }
+
+    fn set_flag(&mut self, flag: u16, on: bool) {
+        self.registers.flags = if on {
+            self.registers.flags | flag
         } else {
-            self.registers.flags & (u16::MAX - OVERFLOW_FLAG)
+            self.registers.flags & !flag
         };
     }
 
-    unsafe fn cnd_jmp(&mut self, jmp: &instruction, flag: u16, exp: bool) -> () {
-        let zero = self.registers.flags & flag > 0;
-        if zero == exp {
-            self.set_ip_to_jmp(jmp);
-        }
+    fn set_common_flags(&mut self, result: u32, wide: bool) {
+        let masked = result & width_mask(wide);
+        self.set_flag(ZERO_FLAG, masked == 0);
+        self.set_flag(SIGNED_FLAG, (masked & sign_bit(wide)) != 0);
+        // Parity only ever looks at the low 8 bits, even for wide results
+        self.set_flag(PARITY_FLAG, (masked & 0xFF).count_ones().is_multiple_of(2));
     }
 
-    unsafe fn set_ip_to_jmp(&mut self, jmp: &instruction) {
-        let current = self.registers.biu[4];
-        let abs = jmp.Operands[0].__bindgen_anon_1.Immediate.Value.abs() as u16;
-        if jmp.Operands[0].__bindgen_anon_1.Immediate.Value > 0 {
-            self.registers.biu[4] = current + abs;
-        } else {
-            if abs > current {
-                panic!("Broken code")
-            }
-            self.registers.biu[4] = current - abs;
+    fn add_with_flags(&mut self, a: u32, b: u32, carry: u32, wide: bool) -> u16 {
+        let mask = width_mask(wide);
+        let alu = a + b + carry;
+        self.set_flag(CARRY_FLAG, alu > mask);
+        self.set_flag(AUX_CARRY_FLAG, ((a ^ b ^ alu) & 0x10) != 0);
+        self.set_flag(OVERFLOW_FLAG, (!(a ^ b) & (a ^ alu) & sign_bit(wide)) != 0);
+        self.set_common_flags(alu, wide);
+        (alu & mask) as u16
+    }
+
+    fn sub_with_flags(&mut self, a: u32, b: u32, borrow: u32, wide: bool) -> u16 {
+        let mask = width_mask(wide);
+        let alu = a.wrapping_sub(b).wrapping_sub(borrow);
+        self.set_flag(CARRY_FLAG, b + borrow > a);
+        self.set_flag(AUX_CARRY_FLAG, ((a ^ b ^ alu) & 0x10) != 0);
+        self.set_flag(OVERFLOW_FLAG, ((a ^ b) & (a ^ alu) & sign_bit(wide)) != 0);
+        self.set_common_flags(alu, wide);
+        (alu & mask) as u16
+    }
+
+    fn logic_with_flags(&mut self, result: u32, wide: bool) -> u16 {
+        self.set_flag(CARRY_FLAG, false);
+        self.set_flag(OVERFLOW_FLAG, false);
+        self.set_flag(AUX_CARRY_FLAG, false);
+        self.set_common_flags(result, wide);
+        (result & width_mask(wide)) as u16
+    }
+
+    fn shift_with_flags(&mut self, op: operation_type, a: u32, count: u32, wide: bool) -> u16 {
+        // A zero count is a no-op, flags included
+        if count == 0 {
+            return a as u16;
         }
+
+        let mask = width_mask(wide);
+        let sign = sign_bit(wide);
+        let mut alu = a & mask;
+        let mut carry = self.flag(CARRY_FLAG);
+        for _ in 0..count {
+            let low = (alu & 1) != 0;
+            let high = (alu & sign) != 0;
+            alu = match op {
+                operation_type_Op_shl => (alu << 1) & mask,
+                operation_type_Op_shr => alu >> 1,
+                operation_type_Op_sar => (alu >> 1) | (alu & sign),
+                operation_type_Op_rol => ((alu << 1) & mask) | high as u32,
+                operation_type_Op_ror => (alu >> 1) | if low { sign } else { 0 },
+                operation_type_Op_rcl => ((alu << 1) & mask) | carry as u32,
+                _ => (alu >> 1) | if carry { sign } else { 0 },
+            };
+            carry = match op {
+                operation_type_Op_shl | operation_type_Op_rol | operation_type_Op_rcl => high,
+                _ => low,
+            };
+        }
+
+        let top = (alu & sign) != 0;
+        let overflow = match op {
+            operation_type_Op_shl | operation_type_Op_rol | operation_type_Op_rcl => top != carry,
+            operation_type_Op_shr => (a & sign) != 0,
+            operation_type_Op_sar => false,
+            _ => top != ((alu & (sign >> 1)) != 0),
+        };
+        self.set_flag(CARRY_FLAG, carry);
+        self.set_flag(OVERFLOW_FLAG, overflow);
+        if matches!(
+            op,
+            operation_type_Op_shl | operation_type_Op_shr | operation_type_Op_sar
+        ) {
+            self.set_common_flags(alu, wide);
+        }
+
+        alu as u16
     }
 
-    unsafe fn cx_loop(&mut self, jmp: &instruction, exp: bool) -> () {
-        self.registers.arr[2] -= 1;
-        if ((self.registers.flags & ZERO_FLAG) > 1) == exp && self.registers.arr[2] != 0 {
+    fn cnd_jmp(&mut self, jmp: &instruction, taken: bool, result: &mut ExecResult) {
+        if taken {
             self.set_ip_to_jmp(jmp);
         }
+        result.branch_taken = taken;
     }
 
+    fn set_ip_to_jmp(&mut self, jmp: &instruction) {
+        let disp = unsafe { jmp.Operands[0].__bindgen_anon_1.Immediate.Value };
+        let ip = self.registers.ip().wrapping_add(disp as u16);
+        self.registers.set(Register_ip, ip);
+    }
+
+    fn cx_loop(&mut self, jmp: &instruction, zero: Option<bool>, result: &mut ExecResult) {
+        let cx = self.registers.get(Register_c).wrapping_sub(1);
+        self.registers.set(Register_c, cx);
+        let taken = cx != 0 && zero.is_none_or(|exp| self.flag(ZERO_FLAG) == exp);
+        self.cnd_jmp(jmp, taken, result);
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
+    fn run<H: Hooks>(simulator: &mut Simulator<H>, code: &[u8]) {
+        let mut offset = 0usize;
+        while offset < code.len() {
+            let decoded =
+                decode_8086_instruction(&code[offset..]).expect("test code should decode");
+            simulator
+                .execute_instruction(&decoded)
+                .expect("test code should run");
+            offset = simulator.registers().ip() as usize;
+        }
+    }
+
     #[test]
     fn flag_swap_on() {
         let pre = 0xF000u16;
@@ -411,7 +1206,7 @@ mod tests {
 
     #[test]
     fn bit_counting() {
-        let mut total = 57u16.count_ones();
+        let total = 57u16.count_ones();
         assert_eq!(4, total)
     }
 
@@ -421,4 +1216,261 @@ mod tests {
         let shifted = val >> 1;
         assert_eq!(0, shifted);
     }
+
+    #[test]
+    fn byte_registers_alias_wide() {
+        let mut simulator = Simulator::new();
+        // mov ax, 0x1234 / mov ah, al
+        run(&mut simulator, &[0xB8, 0x34, 0x12, 0x88, 0xC4]);
+        assert_eq!(0x3434, simulator.registers().get(Register_a));
+    }
+
+    #[test]
+    fn add_sets_carry_and_overflow() {
+        let mut simulator = Simulator::new();
+        // mov bx, 30000 / add bx, 10000 / sub bx, 5000
+        run(&mut simulator, &[0xBB, 0x30, 0x75, 0x81, 0xC3, 0x10, 0x27]);
+        assert_eq!(SIGNED_FLAG | OVERFLOW_FLAG, simulator.registers().flags());
+        run(&mut simulator, &[0x81, 0xEB, 0x88, 0x13]);
+        assert_eq!(0x88B8, simulator.registers().get(Register_b));
+        assert_eq!(
+            PARITY_FLAG | AUX_CARRY_FLAG | SIGNED_FLAG,
+            simulator.registers().flags()
+        );
+    }
+
+    #[test]
+    fn memory_uses_base_and_index() {
+        let mut simulator = Simulator::new();
+        // mov bx, 1000 / mov si, 2 / mov word [bx+si+4], 0x0102 / mov al, [1007]
+        run(
+            &mut simulator,
+            &[
+                0xBB, 0xE8, 0x03, 0xBE, 0x02, 0x00, 0xC7, 0x40, 0x04, 0x02, 0x01, 0xA0, 0xEF, 0x03,
+            ],
+        );
+        assert_eq!([0x02, 0x01], simulator.memory()[1006..1008]);
+        assert_eq!(0x01, simulator.registers().get(Register_a));
+    }
+
+    #[test]
+    fn step_back_undoes_registers_and_memory() {
+        let mut simulator = Simulator::new();
+        simulator.record_history(true);
+        // mov bx, 0x20 / mov word [bx], 0x1234 / inc bx / mov byte [bx], 0x56
+        run(
+            &mut simulator,
+            &[
+                0xBB, 0x20, 0x00, 0xC7, 0x07, 0x34, 0x12, 0x43, 0xC6, 0x07, 0x56,
+            ],
+        );
+        assert_eq!(4, simulator.history_len());
+        assert_eq!(&[0x34, 0x56], &simulator.memory()[0x20..0x22]);
+
+        let writer = simulator.last_writer(0x21).unwrap();
+        assert_eq!(3, writer.step);
+        assert_eq!(8, writer.instruction_address);
+        assert_eq!(0x12, writer.old);
+
+        assert!(simulator.step_back());
+        assert!(simulator.step_back());
+        assert_eq!(&[0x34, 0x12], &simulator.memory()[0x20..0x22]);
+        assert_eq!(0x20, simulator.registers().get(Register_b));
+        assert_eq!(3, simulator.last_writer(0x21).unwrap().instruction_address);
+
+        assert!(simulator.step_back());
+        assert!(simulator.step_back());
+        assert!(!simulator.step_back());
+        assert_eq!(Registers::default(), *simulator.registers());
+        assert_eq!(&[0, 0], &simulator.memory()[0x20..0x22]);
+    }
+
+    #[test]
+    fn limited_history_keeps_the_latest_instructions() {
+        let mut simulator = Simulator::new();
+        simulator.record_history(true);
+        simulator.limit_history(2);
+        // inc ax / inc ax / inc ax
+        run(&mut simulator, &[0x40, 0x40, 0x40]);
+        assert_eq!(2, simulator.history_len());
+
+        assert!(simulator.step_back());
+        assert!(simulator.step_back());
+        assert!(!simulator.step_back());
+        assert_eq!(1, simulator.registers().get(Register_a));
+
+        simulator.limit_history(0);
+        simulator.registers_mut().set(Register_ip, 0);
+        run(&mut simulator, &[0x40, 0x40, 0x40]);
+        assert_eq!(0, simulator.history_len());
+    }
+
+    #[derive(Default)]
+    struct Counter {
+        instructions: usize,
+        reads: Vec<(usize, u8)>,
+        writes: Vec<(usize, u8, u8)>,
+        ports: Vec<(u16, u16)>,
+        interrupts: Vec<u8>,
+    }
+
+    impl Hooks for Counter {
+        fn after_instruction(&mut self, _: &Registers, _: &instruction, _: &ExecResult) {
+            self.instructions += 1;
+        }
+
+        fn memory_read(&mut self, address: usize, value: u8) {
+            self.reads.push((address, value));
+        }
+
+        fn memory_write(&mut self, address: usize, old: u8, new: u8) {
+            self.writes.push((address, old, new));
+        }
+
+        fn port_in(&mut self, port: u16, _: bool) -> Option<u16> {
+            Some(port.wrapping_add(1))
+        }
+
+        fn port_out(&mut self, port: u16, _: bool, value: u16) -> bool {
+            self.ports.push((port, value));
+            true
+        }
+
+        fn interrupt(&mut self, vector: u8) {
+            self.interrupts.push(vector);
+        }
+    }
+
+    #[test]
+    fn hooks_see_memory_ports_and_interrupts() {
+        let mut simulator = Simulator::with_hooks(Counter::default());
+        simulator.memory_mut()[0x10] = 0x77;
+        // in al, 0x40 / mov dx, 0x3f8 / out dx, al / mov bl, [0x10] / mov [0x11], bl
+        run(
+            &mut simulator,
+            &[
+                0xE4, 0x40, 0xBA, 0xF8, 0x03, 0xEE, 0x8A, 0x1E, 0x10, 0x00, 0x88, 0x1E, 0x11, 0x00,
+            ],
+        );
+        assert_eq!(0x41, simulator.registers().get(Register_a));
+
+        let hooks = simulator.hooks();
+        assert_eq!(5, hooks.instructions);
+        assert_eq!(vec![(0x3F8, 0x41)], hooks.ports);
+        assert_eq!(vec![(0x10, 0x77)], hooks.reads);
+        assert_eq!(vec![(0x11, 0, 0x77)], hooks.writes);
+
+        // int 0x21
+        let int = decode_8086_instruction(&[0xCD, 0x21]).unwrap();
+        simulator.execute_instruction(&int).unwrap();
+        assert_eq!(vec![0x21], simulator.hooks().interrupts);
+    }
+
+    #[test]
+    fn port_io_without_devices_is_unimplemented() {
+        let mut simulator = Simulator::new();
+        simulator.record_history(true);
+        // in ax, dx
+        let inst = decode_8086_instruction(&[0xED]).unwrap();
+        assert_eq!(
+            Err(SimError::Unimplemented(operation_type_Op_in)),
+            simulator.execute_instruction(&inst)
+        );
+        assert_eq!(Registers::default(), *simulator.registers());
+        assert_eq!(0, simulator.history_len());
+    }
+
+    #[test]
+    fn fetches_code_as_written() {
+        let mut simulator = Simulator::new();
+        // mov byte [5], 0x40 rewrites the nop after it into inc ax
+        simulator
+            .load(0, 0, &[0xC6, 0x06, 0x05, 0x00, 0x40, 0x90])
+            .unwrap();
+        let inst = simulator.fetch_instruction().unwrap();
+        simulator.execute_instruction(&inst).unwrap();
+        let inst = simulator.fetch_instruction().unwrap();
+        assert_eq!(operation_type_Op_inc, inst.Op);
+
+        // Fetching wraps at the end of the segment: mov ax, 0x1234 split
+        // across 0x1000:FFFF and 0x1000:0000
+        let registers = simulator.registers_mut();
+        registers.set(Register_cs, 0x1000);
+        registers.set(Register_ip, 0xFFFF);
+        simulator.memory_mut()[0x1FFFF] = 0xB8;
+        simulator.load(0x1000, 0, &[0x34, 0x12]).unwrap();
+        assert_eq!(vec![0xB8, 0x34, 0x12], simulator.code_bytes(3));
+        let inst = simulator.fetch_instruction().unwrap();
+        simulator.execute_instruction(&inst).unwrap();
+        assert_eq!(0x1234, simulator.registers().get(Register_a));
+        assert_eq!(2, simulator.registers().ip());
+
+        simulator.load(0x1000, 2, &[0x60]).unwrap();
+        assert_eq!(
+            Some(SimError::DecodeError {
+                address: 0x10002,
+                bytes: vec![0x60, 0, 0, 0, 0, 0],
+            }),
+            simulator.fetch_instruction().err()
+        );
+    }
+
+    #[test]
+    fn writes_drop_cached_instructions() {
+        let mut simulator = Simulator::new();
+        simulator.record_history(true);
+        // mov ax, 1 / mov byte [2], 5 / jmp $-8, which rewrites the high byte
+        // of the first mov's immediate, well past its first byte
+        simulator
+            .load(
+                0,
+                0,
+                &[0xB8, 0x01, 0x00, 0xC6, 0x06, 0x02, 0x00, 0x05, 0xEB, 0xF6],
+            )
+            .unwrap();
+        let step = |simulator: &mut Simulator| {
+            let inst = simulator.fetch_instruction().unwrap();
+            simulator.execute_instruction(&inst).unwrap();
+        };
+        for _ in 0..4 {
+            step(&mut simulator);
+        }
+        assert_eq!(0x0501, simulator.registers().get(Register_a));
+
+        // Undoing the write puts the old code back too
+        for _ in 0..3 {
+            simulator.step_back();
+        }
+        simulator.registers_mut().set(Register_ip, 0);
+        step(&mut simulator);
+        assert_eq!(0x0001, simulator.registers().get(Register_a));
+
+        // As does writing memory by hand
+        simulator.registers_mut().set(Register_ip, 0);
+        simulator.memory_mut()[1] = 0x07;
+        step(&mut simulator);
+        assert_eq!(0x0007, simulator.registers().get(Register_a));
+    }
+
+    #[test]
+    fn stops_at_hlt_and_bad_loads() {
+        let mut simulator = Simulator::new();
+        assert_eq!(
+            Err(SimError::MemoryFault(0x20000)),
+            simulator.load(0x1000, 0xFFFE, &[0xB8, 0x34, 0x12])
+        );
+        assert_eq!(0, simulator.memory()[0x1FFFE]);
+
+        // Segments wrap at 1MB, as addresses do
+        simulator.load(0xFFFF, 0x000F, &[0xF4, 0xF4]).unwrap();
+        assert_eq!(
+            [0xF4, 0xF4],
+            [simulator.memory()[MEM_LEN - 1], simulator.memory()[0]]
+        );
+
+        let hlt = decode_8086_instruction(&[0xF4]).unwrap();
+        simulator.execute_instruction(&hlt).unwrap();
+        assert_eq!(Err(SimError::Halted), simulator.execute_instruction(&hlt));
+        assert_eq!(1, simulator.registers().ip());
+    }
 }
@@ -0,0 +1,716 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+
+use crate::simulator::{
+    SimError, Simulator, AUX_CARRY_FLAG, CARRY_FLAG, FLAG_MASK_8086, MEM_LEN, OVERFLOW_FLAG,
+    PARITY_FLAG, SIGNED_FLAG, ZERO_FLAG,
+};
+use crate::text::flags_text;
+use crate::*;
+
+// Runs the single-step CPU test suites published for the 8088 and 8086,
+// one JSON file per opcode (or per opcode and reg field, as "F6.4.json"),
+// each holding an array of cases like:
+//
+//     {
+//         "name": "add byte [ss:bp+di-64h], cl",
+//         "bytes": [0, 75, 156],
+//         "initial": {"regs": {"ax": 12345, ...}, "ram": [[address, value], ...]},
+//         "final": {"regs": {"ax": 12346}, "ram": [[address, value], ...]},
+//         "cycles": [...]
+//     }
+//
+// Registers missing from "final" are expected to be unchanged, and so is
+// any byte of memory neither state lists. The bus cycles and prefetch queue
+// aren't modelled by `Simulator`, so they are ignored. The suite's metadata
+// file can give a "flags-mask" per opcode, overriding the built-in masks of
+// the flags each opcode leaves undefined.
+
+/// A parsed JSON value, as much of JSON as the suites use.
+#[derive(Debug, Clone, PartialEq)]
+enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(members) => members
+                .iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    fn as_u32(&self) -> Option<u32> {
+        match self {
+            Json::Number(number)
+                if number.fract() == 0.0 && (0.0..=u32::MAX as f64).contains(number) =>
+            {
+                Some(*number as u32)
+            }
+            _ => None,
+        }
+    }
+}
+
+// Far deeper than the suites go, and shallow enough that a corrupt file of
+// nested brackets can't overflow the stack.
+const MAX_JSON_DEPTH: usize = 64;
+
+struct JsonParser<'a> {
+    text: &'a [u8],
+    at: usize,
+    /// Arrays and objects open around the value being parsed.
+    depth: usize,
+}
+
+impl<'a> JsonParser<'a> {
+    fn parse(text: &'a str) -> Result<Json, String> {
+        let mut parser = JsonParser {
+            text: text.as_bytes(),
+            at: 0,
+            depth: 0,
+        };
+        let value = parser.value()?;
+        parser.skip_whitespace();
+        if parser.at != parser.text.len() {
+            return Err(parser.error("trailing characters"));
+        }
+        Ok(value)
+    }
+
+    fn error(&self, what: &str) -> String {
+        format!("{} at byte {}", what, self.at)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.text.get(self.at).is_some_and(u8::is_ascii_whitespace) {
+            self.at += 1;
+        }
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), String> {
+        self.skip_whitespace();
+        if self.text.get(self.at) != Some(&byte) {
+            return Err(self.error(&format!("expected '{}'", byte as char)));
+        }
+        self.at += 1;
+        Ok(())
+    }
+
+    fn literal(&mut self, word: &str, value: Json) -> Result<Json, String> {
+        if !self.text[self.at..].starts_with(word.as_bytes()) {
+            return Err(self.error("unexpected character"));
+        }
+        self.at += word.len();
+        Ok(value)
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        self.skip_whitespace();
+        match self.text.get(self.at) {
+            None => Err(self.error("unexpected end")),
+            Some(b'n') => self.literal("null", Json::Null),
+            Some(b't') => self.literal("true", Json::Bool(true)),
+            Some(b'f') => self.literal("false", Json::Bool(false)),
+            Some(b'"') => self.string().map(Json::String),
+            Some(b'[' | b'{') if self.depth == MAX_JSON_DEPTH => {
+                Err(self.error("nested too deeply"))
+            }
+            Some(b'[') => {
+                self.at += 1;
+                self.depth += 1;
+                let mut items = Vec::new();
+                if !self.close(b']') {
+                    loop {
+                        items.push(self.value()?);
+                        if self.close(b']') {
+                            break;
+                        }
+                        self.expect(b',')?;
+                    }
+                }
+                self.depth -= 1;
+                Ok(Json::Array(items))
+            }
+            Some(b'{') => {
+                self.at += 1;
+                self.depth += 1;
+                let mut members = Vec::new();
+                if !self.close(b'}') {
+                    loop {
+                        self.skip_whitespace();
+                        let name = self.string()?;
+                        self.expect(b':')?;
+                        members.push((name, self.value()?));
+                        if self.close(b'}') {
+                            break;
+                        }
+                        self.expect(b',')?;
+                    }
+                }
+                self.depth -= 1;
+                Ok(Json::Object(members))
+            }
+            Some(_) => self.number(),
+        }
+    }
+
+    /// Consumes `byte` if it is next, ending an array or object.
+    fn close(&mut self, byte: u8) -> bool {
+        self.skip_whitespace();
+        let found = self.text.get(self.at) == Some(&byte);
+        if found {
+            self.at += 1;
+        }
+        found
+    }
+
+    fn number(&mut self) -> Result<Json, String> {
+        let start = self.at;
+        while self
+            .text
+            .get(self.at)
+            .is_some_and(|byte| byte.is_ascii_digit() || b"+-.eE".contains(byte))
+        {
+            self.at += 1;
+        }
+        std::str::from_utf8(&self.text[start..self.at])
+            .ok()
+            .and_then(|number| number.parse().ok())
+            .map(Json::Number)
+            .ok_or_else(|| self.error("bad number"))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect(b'"')?;
+        let mut bytes = Vec::new();
+        loop {
+            let Some(&byte) = self.text.get(self.at) else {
+                return Err(self.error("unterminated string"));
+            };
+            self.at += 1;
+            match byte {
+                b'"' => break,
+                b'\\' => {
+                    let Some(&escape) = self.text.get(self.at) else {
+                        return Err(self.error("unterminated string"));
+                    };
+                    self.at += 1;
+                    let unescaped = match escape {
+                        b'n' => '\n',
+                        b't' => '\t',
+                        b'r' => '\r',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'u' => {
+                            let code = self
+                                .text
+                                .get(self.at..self.at + 4)
+                                .and_then(|hex| std::str::from_utf8(hex).ok())
+                                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                                .ok_or_else(|| self.error("bad \\u escape"))?;
+                            self.at += 4;
+                            char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER)
+                        }
+                        other => other as char,
+                    };
+                    let mut buf = [0u8; 4];
+                    bytes.extend_from_slice(unescaped.encode_utf8(&mut buf).as_bytes());
+                }
+                _ => bytes.push(byte),
+            }
+        }
+        String::from_utf8(bytes).map_err(|_| self.error("string is not UTF-8"))
+    }
+}
+
+/// Registers and memory, as a test case gives them before or after its
+/// instruction.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct MachineState {
+    pub registers: Vec<(register_index, u16)>,
+    /// Absolute address and value of each byte.
+    pub ram: Vec<(usize, u8)>,
+}
+
+/// One case from a suite: a single instruction, with the state before and
+/// after it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestCase {
+    pub name: String,
+    pub bytes: Vec<u8>,
+    pub initial: MachineState,
+    pub final_state: MachineState,
+}
+
+fn machine_state(json: &Json) -> Result<MachineState, String> {
+    let mut state = MachineState::default();
+    if let Some(Json::Object(registers)) = json.get("regs") {
+        for (name, value) in registers {
+            let index = register_index_from_name(name)
+                .ok_or_else(|| format!("unknown register \"{}\"", name))?;
+            let value = value
+                .as_u32()
+                .filter(|value| *value <= 0xFFFF)
+                .ok_or_else(|| format!("bad value for register {}", name))?;
+            state.registers.push((index, value as u16));
+        }
+    }
+    if let Some(Json::Array(ram)) = json.get("ram") {
+        for entry in ram {
+            let pair = match entry {
+                Json::Array(pair) if pair.len() == 2 => pair[0].as_u32().zip(pair[1].as_u32()),
+                _ => None,
+            };
+            match pair {
+                Some((address, value)) if (address as usize) < MEM_LEN && value <= 0xFF => {
+                    state.ram.push((address as usize, value as u8))
+                }
+                _ => return Err("bad ram entry".to_string()),
+            }
+        }
+    }
+    Ok(state)
+}
+
+fn test_case(json: &Json) -> Result<TestCase, String> {
+    let name = match json.get("name") {
+        Some(Json::String(name)) => name.clone(),
+        _ => return Err("case has no name".to_string()),
+    };
+    let bytes = match json.get("bytes") {
+        Some(Json::Array(bytes)) => bytes
+            .iter()
+            .map(|byte| {
+                byte.as_u32()
+                    .filter(|byte| *byte <= 0xFF)
+                    .map(|byte| byte as u8)
+            })
+            .collect::<Option<Vec<u8>>>(),
+        _ => None,
+    }
+    .ok_or_else(|| format!("{}: bad bytes", name))?;
+    let state = |key| {
+        json.get(key)
+            .ok_or_else(|| format!("{}: no {} state", name, key))
+            .and_then(|state| machine_state(state).map_err(|err| format!("{}: {}", name, err)))
+    };
+    Ok(TestCase {
+        initial: state("initial")?,
+        final_state: state("final")?,
+        name,
+        bytes,
+    })
+}
+
+/// The cases in the text of one suite file.
+pub fn parse_tests(text: &str) -> Result<Vec<TestCase>, String> {
+    match JsonParser::parse(text)? {
+        Json::Array(cases) => cases.iter().map(test_case).collect(),
+        _ => Err("expected an array of test cases".to_string()),
+    }
+}
+
+// The 8086's reg-field opcode groups, where the operation depends on the
+// ModRM byte.
+const GROUP_OPCODES: [u8; 12] = [
+    0x80, 0x81, 0x82, 0x83, 0xD0, 0xD1, 0xD2, 0xD3, 0xF6, 0xF7, 0xFE, 0xFF,
+];
+
+const PREFIXES: [u8; 7] = [0x26, 0x2E, 0x36, 0x3E, 0xF0, 0xF2, 0xF3];
+
+/// The opcode of an instruction's bytes, past any prefixes, with the reg
+/// field of the ModRM byte for opcodes that pick their operation with it.
+pub fn opcode_key(bytes: &[u8]) -> Option<(u8, Option<u8>)> {
+    let at = bytes.iter().position(|byte| !PREFIXES.contains(byte))?;
+    let opcode = bytes[at];
+    if GROUP_OPCODES.contains(&opcode) {
+        Some((opcode, Some((bytes.get(at + 1)? >> 3) & 7)))
+    } else {
+        Some((opcode, None))
+    }
+}
+
+/// The flags the 8086 documents as undefined after an opcode.
+fn undefined_flags(opcode: u8, reg: Option<u8>) -> u16 {
+    const ARITHMETIC: u16 = OVERFLOW_FLAG | SIGNED_FLAG | ZERO_FLAG | AUX_CARRY_FLAG | PARITY_FLAG;
+    match (opcode, reg) {
+        // or, and, xor and test
+        (0x08..=0x0D | 0x20..=0x25 | 0x30..=0x35 | 0x84 | 0x85 | 0xA8 | 0xA9, _)
+        | (0x80..=0x83, Some(1 | 4 | 6))
+        | (0xF6 | 0xF7, Some(0 | 1)) => AUX_CARRY_FLAG,
+        // daa and das
+        (0x27 | 0x2F, _) => OVERFLOW_FLAG,
+        // aaa and aas
+        (0x37 | 0x3F, _) => OVERFLOW_FLAG | SIGNED_FLAG | ZERO_FLAG | PARITY_FLAG,
+        // Shifts leave AF undefined; OF only means anything for a count of 1
+        (0xD0 | 0xD1, Some(4..=7)) => AUX_CARRY_FLAG,
+        (0xD2 | 0xD3, Some(4..=7)) => AUX_CARRY_FLAG | OVERFLOW_FLAG,
+        (0xD2 | 0xD3, Some(0..=3)) => OVERFLOW_FLAG,
+        // mul and imul, then div and idiv
+        (0xF6 | 0xF7, Some(4 | 5)) => SIGNED_FLAG | ZERO_FLAG | AUX_CARRY_FLAG | PARITY_FLAG,
+        (0xF6 | 0xF7, Some(6 | 7)) => ARITHMETIC | CARRY_FLAG,
+        // aam and aad
+        (0xD4 | 0xD5, _) => OVERFLOW_FLAG | AUX_CARRY_FLAG | CARRY_FLAG,
+        _ => 0,
+    }
+}
+
+/// Which flags to compare after each opcode.
+#[derive(Debug, Default, Clone)]
+pub struct FlagMasks {
+    overrides: HashMap<(u8, Option<u8>), u16>,
+}
+
+impl FlagMasks {
+    /// Reads the "flags-mask" entries of a suite's metadata file, which look
+    /// like {"opcodes": {"27": {"flags-mask": 63487}, "F6": {"reg": {"4":
+    /// {"flags-mask": 63274}}}}}.
+    pub fn from_metadata(text: &str) -> Result<Self, String> {
+        let json = JsonParser::parse(text)?;
+        let Some(Json::Object(opcodes)) = json.get("opcodes") else {
+            return Err("metadata has no opcodes".to_string());
+        };
+
+        let mask = |entry: &Json| entry.get("flags-mask").and_then(Json::as_u32);
+        let mut overrides = HashMap::new();
+        for (opcode, entry) in opcodes {
+            let opcode = u8::from_str_radix(opcode, 16)
+                .map_err(|_| format!("bad opcode \"{}\" in metadata", opcode))?;
+            if let Some(mask) = mask(entry) {
+                overrides.insert((opcode, None), mask as u16);
+            }
+            if let Some(Json::Object(regs)) = entry.get("reg") {
+                for (reg, entry) in regs {
+                    let reg = reg
+                        .parse::<u8>()
+                        .map_err(|_| format!("bad reg \"{}\" in metadata", reg))?;
+                    if let Some(mask) = mask(entry) {
+                        overrides.insert((opcode, Some(reg)), mask as u16);
+                    }
+                }
+            }
+        }
+        Ok(Self { overrides })
+    }
+
+    /// The flags worth comparing after the instruction in `bytes`: the
+    /// metadata's mask if it has one, otherwise every flag the 8086 defines
+    /// for it.
+    pub fn compared_flags(&self, bytes: &[u8]) -> u16 {
+        let Some((opcode, reg)) = opcode_key(bytes) else {
+            return FLAG_MASK_8086;
+        };
+        let mask = self
+            .overrides
+            .get(&(opcode, reg))
+            .or_else(|| self.overrides.get(&(opcode, None)))
+            .copied()
+            .unwrap_or(!undefined_flags(opcode, reg));
+        mask & FLAG_MASK_8086
+    }
+}
+
+/// A field of the final state that came out wrong.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mismatch {
+    Register {
+        index: register_index,
+        actual: u16,
+        expected: u16,
+    },
+    /// A byte of memory, where `expected` is None for a byte the case
+    /// expects to be left alone.
+    Memory {
+        address: usize,
+        actual: u8,
+        expected: Option<u8>,
+    },
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Mismatch::Register {
+                index: Register_flags,
+                actual,
+                expected,
+            } => write!(
+                f,
+                "flags: {}, expected {}",
+                flags_text(actual),
+                flags_text(expected)
+            ),
+            Mismatch::Register {
+                index,
+                actual,
+                expected,
+            } => {
+                let access = register_access {
+                    Index: index,
+                    Offset: 0,
+                    Count: 2,
+                };
+                let name = register_name_from_operand(&access);
+                write!(f, "{}: 0x{:04x}, expected 0x{:04x}", name, actual, expected)
+            }
+            Mismatch::Memory {
+                address,
+                actual,
+                expected: Some(expected),
+            } => write!(
+                f,
+                "[0x{:05x}]: 0x{:02x}, expected 0x{:02x}",
+                address, actual, expected
+            ),
+            Mismatch::Memory {
+                address,
+                actual,
+                expected: None,
+            } => write!(
+                f,
+                "[0x{:05x}]: wrote 0x{:02x}, expected no write",
+                address, actual
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CaseResult {
+    Passed,
+    Failed(Vec<Mismatch>),
+    /// The bytes at CS:IP don't decode.
+    DecodeError,
+    /// `Simulator` can't run the instruction.
+    Unimplemented,
+    /// The simulator stopped with some other error, which counts as a
+    /// failure.
+    Error(SimError),
+}
+
+/// Runs one case in a fresh `Simulator`, comparing only the flags in
+/// `compared_flags`.
+pub fn run_case(case: &TestCase, compared_flags: u16) -> CaseResult {
+    let mut simulator = Simulator::new();
+    for (index, value) in &case.initial.registers {
+        simulator.registers_mut().set(*index, *value);
+    }
+    for (address, value) in &case.initial.ram {
+        simulator.memory_mut()[*address] = *value;
+    }
+
+    let inst = match simulator.fetch_instruction() {
+        Ok(inst) => inst,
+        Err(SimError::DecodeError { .. }) => return CaseResult::DecodeError,
+        Err(err) => return CaseResult::Error(err),
+    };
+
+    simulator.record_history(true);
+    match simulator.execute_instruction(&inst) {
+        Ok(_) => {}
+        Err(SimError::Unimplemented(_)) => return CaseResult::Unimplemented,
+        Err(err) => return CaseResult::Error(err),
+    }
+
+    let mut mismatches = Vec::new();
+    let mut registers: BTreeMap<register_index, u16> =
+        case.initial.registers.iter().copied().collect();
+    registers.extend(case.final_state.registers.iter().copied());
+    for (index, expected) in registers {
+        let mask = if index == Register_flags {
+            compared_flags
+        } else {
+            0xFFFF
+        };
+        let actual = simulator.registers().get(index);
+        if (actual ^ expected) & mask != 0 {
+            mismatches.push(Mismatch::Register {
+                index,
+                actual: actual & mask,
+                expected: expected & mask,
+            });
+        }
+    }
+
+    let mut ram: BTreeMap<usize, u8> = case.initial.ram.iter().copied().collect();
+    ram.extend(case.final_state.ram.iter().copied());
+    let written: BTreeSet<usize> = simulator
+        .history
+        .iter()
+        .flatten()
+        .flat_map(|record| record.memory.iter().map(|(address, _)| *address))
+        .collect();
+    for address in ram
+        .keys()
+        .chain(written.difference(&ram.keys().copied().collect()))
+    {
+        let actual = simulator.memory()[*address];
+        let expected = ram.get(address).copied();
+        if expected != Some(actual) {
+            mismatches.push(Mismatch::Memory {
+                address: *address,
+                actual,
+                expected,
+            });
+        }
+    }
+
+    if mismatches.is_empty() {
+        CaseResult::Passed
+    } else {
+        CaseResult::Failed(mismatches)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // add al, 0x0F with al = 0xF1, then the same with and
+    const CASES: &str = r#"[
+        {
+            "name": "add al, 0fh",
+            "bytes": [4, 15],
+            "initial": {
+                "regs": {"ax": 241, "cs": 4096, "ip": 16, "flags": 61442},
+                "ram": [[65552, 4], [65553, 15]],
+                "queue": []
+            },
+            "final": {"regs": {"ax": 0, "ip": 18, "flags": 61527}, "ram": [], "queue": []},
+            "cycles": [[0, "CODE", "T1"]],
+            "hash": "a\"b\\cA"
+        },
+        {
+            "name": "and al, 0fh",
+            "bytes": [36, 15],
+            "initial": {
+                "regs": {"ax": 241, "cs": 4096, "ip": 16, "flags": 61442},
+                "ram": [[65552, 36], [65553, 15]]
+            },
+            "final": {"regs": {"ax": 1, "ip": 18, "flags": 61458}, "ram": []}
+        }
+    ]"#;
+
+    #[test]
+    fn parses_and_runs_cases() {
+        let cases = parse_tests(CASES).unwrap();
+        assert_eq!(2, cases.len());
+        assert_eq!("add al, 0fh", cases[0].name);
+        assert_eq!(vec![4, 15], cases[0].bytes);
+        assert_eq!((0x10010, 4), cases[0].initial.ram[0]);
+
+        let masks = FlagMasks::default();
+        assert_eq!(
+            CaseResult::Passed,
+            run_case(&cases[0], masks.compared_flags(&cases[0].bytes))
+        );
+
+        // The case claims AF is set after and, which the 8086 leaves
+        // undefined; comparing every flag catches it
+        assert_eq!(
+            CaseResult::Passed,
+            run_case(&cases[1], masks.compared_flags(&cases[1].bytes))
+        );
+        assert_eq!(
+            CaseResult::Failed(vec![Mismatch::Register {
+                index: Register_flags,
+                actual: 0,
+                expected: AUX_CARRY_FLAG,
+            }]),
+            run_case(&cases[1], FLAG_MASK_8086 & (AUX_CARRY_FLAG | PARITY_FLAG))
+        );
+    }
+
+    #[test]
+    fn reports_wrong_and_stray_writes() {
+        // mov [bx], al with bx = 2, expecting the write at 3 instead
+        let mut case = parse_tests(
+            r#"[{"name": "mov [bx], al", "bytes": [136, 7],
+                 "initial": {"regs": {"ax": 255, "bx": 2, "ip": 256}, "ram": [[256, 136], [257, 7]]},
+                 "final": {"regs": {"ip": 258}, "ram": [[3, 255]]}}]"#,
+        )
+        .unwrap()
+        .remove(0);
+        assert_eq!(
+            CaseResult::Failed(vec![
+                Mismatch::Memory {
+                    address: 3,
+                    actual: 0,
+                    expected: Some(0xFF),
+                },
+                Mismatch::Memory {
+                    address: 2,
+                    actual: 0xFF,
+                    expected: None,
+                },
+            ]),
+            run_case(&case, FLAG_MASK_8086)
+        );
+
+        case.final_state.ram = vec![(2, 0xFF)];
+        assert_eq!(CaseResult::Passed, run_case(&case, FLAG_MASK_8086));
+    }
+
+    #[test]
+    fn pushf_stores_the_fixed_bits() {
+        // pushf with only CF set, into 0000:00FE
+        let case = parse_tests(
+            r#"[{"name": "pushf", "bytes": [156],
+                 "initial": {"regs": {"ip": 0, "ss": 0, "sp": 256, "flags": 61443},
+                             "ram": [[0, 156]]},
+                 "final": {"regs": {"ip": 1, "sp": 254}, "ram": [[254, 3], [255, 240]]}}]"#,
+        )
+        .unwrap()
+        .remove(0);
+        assert_eq!(CaseResult::Passed, run_case(&case, FLAG_MASK_8086));
+    }
+
+    #[test]
+    fn unimplemented_instructions_are_skipped() {
+        // in al, dx isn't simulated
+        let case = parse_tests(
+            r#"[{"name": "in al, dx", "bytes": [236],
+                 "initial": {"regs": {"ip": 0}, "ram": [[0, 236]]}, "final": {"regs": {}}}]"#,
+        )
+        .unwrap()
+        .remove(0);
+        assert_eq!(CaseResult::Unimplemented, run_case(&case, FLAG_MASK_8086));
+    }
+
+    #[test]
+    fn rejects_deep_nesting() {
+        let nested = |depth| format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+        assert!(JsonParser::parse(&nested(MAX_JSON_DEPTH)).is_ok());
+        assert_eq!(
+            Err(format!("nested too deeply at byte {}", MAX_JSON_DEPTH)),
+            JsonParser::parse(&nested(MAX_JSON_DEPTH + 1))
+        );
+        assert!(parse_tests(&"[{\"a\": ".repeat(100_000)).is_err());
+    }
+
+    #[test]
+    fn metadata_masks_override_the_defaults() {
+        let masks = FlagMasks::from_metadata(
+            r#"{"opcodes": {"24": {"status": "normal", "flags-mask": 65535},
+                            "F6": {"reg": {"4": {"flags-mask": 2261}}}}}"#,
+        )
+        .unwrap();
+        assert_eq!(FLAG_MASK_8086, masks.compared_flags(&[0x24, 0x0F]));
+        assert_eq!(2261 & FLAG_MASK_8086, masks.compared_flags(&[0xF6, 0xE0]));
+        // Not in the metadata, so the 8086's undefined flags still apply
+        assert_eq!(
+            FLAG_MASK_8086 & !AUX_CARRY_FLAG,
+            masks.compared_flags(&[0x2E, 0x20, 0x07])
+        );
+        assert_eq!(Some((0xF7, Some(6))), opcode_key(&[0xF3, 0xF7, 0xF1]));
+    }
+}
@@ -0,0 +1,257 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use crate::simulator::{Hooks, Registers, Simulator};
+use crate::*;
+
+// Snapshot layout, all integers little endian:
+//
+//     "S86S" magic, u16 version
+//     chunks of [4-byte tag, u32 length, payload] until the end of the file
+//
+// Chunks:
+//     REGS  u16 per register, Register_a up to (not including) Register_count
+//     CPU   u8 halted
+//     MEM   runs of [u32 address, u32 length, bytes]; all other memory is zero
+//
+// Readers skip chunks they don't know, so new state (attached devices, say)
+// can be added as new chunks without a version bump. Changing the layout of
+// an existing chunk needs a new version.
+
+pub const SNAPSHOT_VERSION: u16 = 1;
+
+const MAGIC: &[u8; 4] = b"S86S";
+const REGISTERS_TAG: &[u8; 4] = b"REGS";
+const CPU_TAG: &[u8; 4] = b"CPU ";
+const MEMORY_TAG: &[u8; 4] = b"MEM ";
+
+// Zero gaps shorter than a run header are cheaper to store than to skip.
+const MIN_MEMORY_GAP: usize = 8;
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    NotASnapshot,
+    UnsupportedVersion(u16),
+    Corrupt(&'static str),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Io(err) => write!(f, "{}", err),
+            SnapshotError::NotASnapshot => write!(f, "not a sim86 snapshot"),
+            SnapshotError::UnsupportedVersion(version) => {
+                write!(f, "unsupported snapshot version {}", version)
+            }
+            SnapshotError::Corrupt(what) => write!(f, "corrupt snapshot: {}", what),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<io::Error> for SnapshotError {
+    fn from(err: io::Error) -> Self {
+        SnapshotError::Io(err)
+    }
+}
+
+/// Start and end of each stretch of memory worth storing.
+fn memory_runs(memory: &[u8]) -> Vec<(usize, usize)> {
+    let mut runs: Vec<(usize, usize)> = Vec::new();
+    let mut at = 0;
+    while let Some(start) = memory[at..].iter().position(|byte| *byte != 0) {
+        let start = at + start;
+        let end = memory[start..]
+            .iter()
+            .position(|byte| *byte == 0)
+            .map_or(memory.len(), |length| start + length);
+
+        match runs.last_mut() {
+            Some(last) if start - last.1 < MIN_MEMORY_GAP => last.1 = end,
+            _ => runs.push((start, end)),
+        }
+        at = end;
+    }
+
+    runs
+}
+
+fn write_chunk<W: Write>(writer: &mut W, tag: &[u8; 4], payload: &[u8]) -> io::Result<()> {
+    writer.write_all(tag)?;
+    writer.write_all(&(payload.len() as u32).to_le_bytes())?;
+    writer.write_all(payload)
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(bytes.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn restore_registers(payload: &[u8], registers: &mut Registers) -> Result<(), SnapshotError> {
+    let count = (Register_count - Register_a) as usize;
+    if payload.len() != count * 2 {
+        return Err(SnapshotError::Corrupt("register chunk has the wrong size"));
+    }
+
+    for (index, value) in (Register_a..Register_count).zip(payload.chunks(2)) {
+        registers.set(index, u16::from_le_bytes([value[0], value[1]]));
+    }
+    Ok(())
+}
+
+fn restore_memory(payload: &[u8], memory: &mut [u8]) -> Result<(), SnapshotError> {
+    let mut at = 0;
+    while at < payload.len() {
+        let (Some(address), Some(length)) = (read_u32(payload, at), read_u32(payload, at + 4))
+        else {
+            return Err(SnapshotError::Corrupt("truncated memory run"));
+        };
+        let (address, length) = (address as usize, length as usize);
+        at += 8;
+
+        let bytes = payload
+            .get(at..at + length)
+            .ok_or(SnapshotError::Corrupt("truncated memory run"))?;
+        memory
+            .get_mut(address..address + length)
+            .ok_or(SnapshotError::Corrupt("memory run outside 1MB"))?
+            .copy_from_slice(bytes);
+        at += length;
+    }
+
+    Ok(())
+}
+
+impl<H: Hooks> Simulator<H> {
+    /// Writes registers, flags, halted state and the non-zero parts of memory.
+    /// The undo history isn't part of a snapshot.
+    pub fn save_snapshot<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&SNAPSHOT_VERSION.to_le_bytes())?;
+
+        let registers: Vec<u8> = (Register_a..Register_count)
+            .flat_map(|index| self.registers.get(index).to_le_bytes())
+            .collect();
+        write_chunk(writer, REGISTERS_TAG, &registers)?;
+        write_chunk(writer, CPU_TAG, &[self.halted as u8])?;
+
+        let mut memory = Vec::new();
+        for (start, end) in memory_runs(&self.memory) {
+            memory.extend_from_slice(&(start as u32).to_le_bytes());
+            memory.extend_from_slice(&((end - start) as u32).to_le_bytes());
+            memory.extend_from_slice(&self.memory[start..end]);
+        }
+        write_chunk(writer, MEMORY_TAG, &memory)
+    }
+
+    /// Replaces the machine state with a snapshot, keeping the hooks. Any
+    /// recorded history is discarded, though recording carries on.
+    pub fn restore_snapshot<R: Read>(&mut self, reader: &mut R) -> Result<(), SnapshotError> {
+        let loaded = Simulator::load_snapshot(reader)?;
+        self.registers = loaded.registers;
+        self.memory = loaded.memory;
+        self.halted = loaded.halted;
+        if let Some(cache) = &mut self.decode_cache {
+            cache.clear();
+        }
+        if let Some(history) = &mut self.history {
+            history.clear();
+        }
+        Ok(())
+    }
+}
+
+impl Simulator {
+    pub fn load_snapshot<R: Read>(reader: &mut R) -> Result<Simulator, SnapshotError> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+
+        if bytes.get(..4) != Some(MAGIC.as_slice()) || bytes.len() < 6 {
+            return Err(SnapshotError::NotASnapshot);
+        }
+        let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+        if version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+
+        let mut simulator = Simulator::new();
+        let mut at = 6;
+        while at < bytes.len() {
+            let tag = bytes.get(at..at + 4);
+            let length = read_u32(&bytes, at + 4)
+                .ok_or(SnapshotError::Corrupt("truncated chunk header"))?
+                as usize;
+            at += 8;
+            let payload = bytes
+                .get(at..at + length)
+                .ok_or(SnapshotError::Corrupt("truncated chunk"))?;
+            at += length;
+
+            if tag == Some(REGISTERS_TAG.as_slice()) {
+                restore_registers(payload, &mut simulator.registers)?;
+            } else if tag == Some(CPU_TAG.as_slice()) {
+                simulator.halted = payload.first().is_some_and(|halted| *halted != 0);
+            } else if tag == Some(MEMORY_TAG.as_slice()) {
+                restore_memory(payload, &mut simulator.memory)?;
+            }
+        }
+
+        Ok(simulator)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::MEM_LEN;
+
+    #[test]
+    fn round_trips_machine_state() {
+        let mut simulator = Simulator::new();
+        simulator.registers_mut().set(Register_b, 0x1234);
+        simulator.registers_mut().set(Register_flags, 0x0841);
+        simulator.registers_mut().set(Register_ip, 0x0100);
+        simulator.halted = true;
+        simulator.memory_mut()[0x10..0x14].copy_from_slice(&[1, 2, 0, 3]);
+        simulator.memory_mut()[MEM_LEN - 1] = 0xFF;
+
+        let mut saved = Vec::new();
+        simulator.save_snapshot(&mut saved).unwrap();
+        assert!(saved.len() < 100);
+
+        let restored = Simulator::load_snapshot(&mut saved.as_slice()).unwrap();
+        assert_eq!(simulator.registers(), restored.registers());
+        assert!(restored.halted());
+        assert!(simulator.memory() == restored.memory());
+    }
+
+    #[test]
+    fn merges_short_gaps() {
+        let mut memory = vec![0u8; 64];
+        memory[1] = 1;
+        memory[4] = 1;
+        memory[40] = 1;
+        assert_eq!(vec![(1, 5), (40, 41)], memory_runs(&memory));
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let mut saved = Vec::new();
+        Simulator::new().save_snapshot(&mut saved).unwrap();
+        assert!(matches!(
+            Simulator::load_snapshot(&mut &saved[..saved.len() - 1]),
+            Err(SnapshotError::Corrupt(_))
+        ));
+
+        saved[4] = 99;
+        assert!(matches!(
+            Simulator::load_snapshot(&mut saved.as_slice()),
+            Err(SnapshotError::UnsupportedVersion(99))
+        ));
+        assert!(matches!(
+            Simulator::load_snapshot(&mut b"MZ\x90\x00".as_slice()),
+            Err(SnapshotError::NotASnapshot)
+        ));
+    }
+}
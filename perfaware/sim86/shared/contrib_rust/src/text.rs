@@ -0,0 +1,259 @@
+use std::fmt::Write;
+
+use crate::cycles::{ClockInterval, InstructionTiming};
+use crate::simulator::*;
+use crate::*;
+
+// Output here follows sim86_text.cpp character for character, so traces from
+// this crate can be diffed directly against the reference simulator.
+
+pub(crate) fn wide_register(index: register_index) -> register_access {
+    register_access {
+        Index: index,
+        Offset: 0,
+        Count: 2,
+    }
+}
+
+fn effective_address_text(address: &effective_address_expression) -> String {
+    let mut text = String::new();
+    let mut separator = "";
+    for term in address.Terms {
+        if term.Register.Index != Register_none {
+            text.push_str(separator);
+            if term.Scale != 1 {
+                let _ = write!(text, "{}*", term.Scale);
+            }
+            text.push_str(&register_name_from_operand(&term.Register));
+            separator = "+";
+        }
+    }
+
+    if text.is_empty() || address.Displacement != 0 {
+        let _ = write!(text, "{:+}", address.Displacement);
+    }
+
+    text
+}
+
+/// Intel syntax for a decoded instruction, as NASM would accept it. Like the
+/// reference, the mnemonic is always followed by a space, even with no operands.
+pub fn instruction_text(inst: &instruction) -> String {
+    let mut text = String::new();
+    let flags = inst.Flags;
+    let wide = (flags & instruction_flag_Inst_Wide) != 0;
+
+    let mut operands = inst.Operands;
+    if (flags & instruction_flag_Inst_Lock) != 0 {
+        if inst.Op == operation_type_Op_xchg {
+            // Purely to match what assemblers expect to see
+            operands.swap(0, 1);
+        }
+        text.push_str("lock ");
+    }
+
+    let mut suffix = "";
+    if (flags & instruction_flag_Inst_Rep) != 0 {
+        // Despite its name, the decoder sets RepNE from the prefix's Z bit,
+        // which is set for rep/repe (0xF3) and clear for repne (0xF2)
+        let z = (flags & instruction_flag_Inst_RepNE) != 0;
+        text.push_str(if z { "rep " } else { "repne " });
+        suffix = if wide { "w" } else { "b" };
+    }
+
+    let _ = write!(text, "{}{} ", mnemonic_from_operation_type(inst.Op), suffix);
+
+    let mut separator = "";
+    for operand in operands {
+        if operand.Type == operand_type_Operand_None {
+            continue;
+        }
+        text.push_str(separator);
+        separator = ", ";
+
+        unsafe {
+            match operand.Type {
+                operand_type_Operand_Register => {
+                    text.push_str(&register_name_from_operand(
+                        &operand.__bindgen_anon_1.Register,
+                    ));
+                }
+                operand_type_Operand_Memory => {
+                    let address = operand.__bindgen_anon_1.Address;
+                    if (address.Flags & effective_address_flag_Address_ExplicitSegment) != 0 {
+                        let _ =
+                            write!(text, "{}:{}", address.ExplicitSegment, address.Displacement);
+                        continue;
+                    }
+
+                    if (flags & instruction_flag_Inst_Far) != 0 {
+                        text.push_str("far ");
+                    }
+                    if operands[0].Type != operand_type_Operand_Register {
+                        text.push_str(if wide { "word " } else { "byte " });
+                    }
+                    if (flags & instruction_flag_Inst_Segment) != 0 {
+                        let _ = write!(
+                            text,
+                            "{}:",
+                            register_name_from_operand(&wide_register(inst.SegmentOverride))
+                        );
+                    }
+                    let _ = write!(text, "[{}]", effective_address_text(&address));
+                }
+                operand_type_Operand_Immediate => {
+                    let immediate = operand.__bindgen_anon_1.Immediate;
+                    if (immediate.Flags & immediate_flag_Immediate_RelativeJumpDisplacement) != 0 {
+                        let _ = write!(text, "${:+}", immediate.Value + inst.Size as i32);
+                    } else {
+                        let _ = write!(text, "{}", immediate.Value);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    text
+}
+
+/// Flag letters in the order the reference prints them.
+pub(crate) const FLAG_LETTERS: [(u16, char); 9] = [
+    (CARRY_FLAG, 'C'),
+    (PARITY_FLAG, 'P'),
+    (AUX_CARRY_FLAG, 'A'),
+    (ZERO_FLAG, 'Z'),
+    (SIGNED_FLAG, 'S'),
+    (TRAP_FLAG, 'T'),
+    (INTERRUPT_FLAG, 'I'),
+    (DIRECTION_FLAG, 'D'),
+    (OVERFLOW_FLAG, 'O'),
+];
+
+pub fn flags_text(flags: u16) -> String {
+    FLAG_LETTERS
+        .iter()
+        .filter(|(flag, _)| (flags & flag) != 0)
+        .map(|(_, letter)| letter)
+        .collect()
+}
+
+/// The "Final registers:" block, one line per non-zero register.
+pub fn registers_text(registers: &Registers) -> String {
+    let mut text = String::new();
+    for index in 0..Register_count {
+        let value = registers.get(index);
+        let name = register_name_from_operand(&wide_register(index));
+        if value == 0 || name.is_empty() {
+            continue;
+        }
+
+        let _ = write!(text, "{:>8}: ", name);
+        if index == Register_flags {
+            text.push_str(&flags_text(value));
+        } else {
+            let _ = write!(text, "0x{:04x} ({})", value, value);
+        }
+        text.push('\n');
+    }
+
+    text
+}
+
+/// The `reg:old->new` changes between two register states, each followed by
+/// a space.
+pub fn register_difference_text(old: &Registers, new: &Registers) -> String {
+    let mut text = String::new();
+    for index in 0..Register_count {
+        let (old_val, new_val) = (old.get(index), new.get(index));
+        if old_val == new_val {
+            continue;
+        }
+
+        let _ = write!(
+            text,
+            "{}:",
+            register_name_from_operand(&wide_register(index))
+        );
+        if index == Register_flags {
+            let _ = write!(text, "{}->{}", flags_text(old_val), flags_text(new_val));
+        } else {
+            let _ = write!(text, "0x{:x}->0x{:x}", old_val, new_val);
+        }
+        text.push(' ');
+    }
+
+    text
+}
+
+pub fn clock_interval_text(clocks: &ClockInterval) -> String {
+    if clocks.min != clocks.max {
+        format!("[{},{}]", clocks.min, clocks.max)
+    } else {
+        format!("{}", clocks.min)
+    }
+}
+
+/// The "Clocks: +N = T" part of a trace line.
+pub fn clocks_text(clocks: &ClockInterval, total: &ClockInterval) -> String {
+    if total.min != total.max {
+        format!(
+            "Clocks: +[{},{}] = [{},{}]",
+            clocks.min, clocks.max, total.min, total.max
+        )
+    } else {
+        format!("Clocks: +{} = {}", clocks.min, total.min)
+    }
+}
+
+/// The " (base + Nea + Np)" breakdown printed by -explainclocks.
+pub fn explain_timing_text(timing: &InstructionTiming, clocks: &ClockInterval) -> String {
+    let mut text = String::new();
+    if timing.base.min == clocks.min {
+        return text;
+    }
+
+    let _ = write!(text, " ({}", clock_interval_text(&timing.base));
+    if timing.ea_clocks != 0 {
+        let _ = write!(text, " + {}ea", timing.ea_clocks);
+    }
+    let penalty = clocks.min - (timing.base.min + timing.ea_clocks);
+    if penalty != 0 {
+        let _ = write!(text, " + {}p", penalty);
+    }
+    text.push(')');
+
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text_of(code: &[u8]) -> String {
+        instruction_text(&decode_8086_instruction(code).unwrap())
+    }
+
+    #[test]
+    fn matches_reference_syntax() {
+        assert_eq!("mov cx, [bp+di+1000]", text_of(&[0x8B, 0x8B, 0xE8, 0x03]));
+        assert_eq!("mov dx, [+1000]", text_of(&[0x8B, 0x16, 0xE8, 0x03]));
+        assert_eq!("add word [bp+si], 76", text_of(&[0x83, 0x02, 0x4C]));
+        assert_eq!("jne $-6", text_of(&[0x75, 0xF8]));
+        assert_eq!("mov ax, [bx+di-37]", text_of(&[0x8B, 0x41, 0xDB]));
+        assert_eq!("rep movsb ", text_of(&[0xF3, 0xA4]));
+        assert_eq!("repne scasw ", text_of(&[0xF2, 0xAF]));
+    }
+
+    #[test]
+    fn register_differences() {
+        let old = Registers::default();
+        let mut new = old;
+        new.set(Register_b, 0x7530);
+        new.set(Register_flags, PARITY_FLAG);
+        assert_eq!(
+            "bx:0x0->0x7530 flags:->P ",
+            register_difference_text(&old, &new)
+        );
+    }
+}
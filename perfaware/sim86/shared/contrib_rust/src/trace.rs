@@ -0,0 +1,467 @@
+use std::fmt::{self, Write as _};
+use std::io::{self, Write};
+
+use crate::cycles::ClockInterval;
+use crate::simulator::{Hooks, Registers};
+use crate::text::{clock_interval_text, flags_text, instruction_text, wide_register};
+use crate::*;
+
+// Binary execution traces. Integers are unsigned LEB128 varints unless noted.
+//
+//     "S86T" magic, u16 little endian version
+//     initial registers: a varint per register, Register_a up to Register_count
+//     one record per executed instruction:
+//         address, byte count, instruction bytes
+//         changed register count, then [u8 register index, varint new value]
+//         memory write count, then [address, u8 value]
+//         min clocks, max - min clocks
+//
+// Register changes only store the new value; readers replay them from the
+// initial registers to recover the old one.
+
+pub const TRACE_VERSION: u16 = 1;
+
+const MAGIC: &[u8; 4] = b"S86T";
+
+/// One executed instruction.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TraceRecord {
+    pub address: usize,
+    pub bytes: Vec<u8>,
+    /// Register index, old value and new value, in register order.
+    pub registers: Vec<(register_index, u16, u16)>,
+    /// Absolute address and new value of each byte written.
+    pub memory_writes: Vec<(usize, u8)>,
+    pub clocks: ClockInterval,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TraceError {
+    NotATrace,
+    UnsupportedVersion(u16),
+    Truncated,
+    /// A length or clock count too big to be real.
+    Corrupt,
+}
+
+impl fmt::Display for TraceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TraceError::NotATrace => write!(f, "not a sim86 trace"),
+            TraceError::UnsupportedVersion(version) => {
+                write!(f, "unsupported trace version {}", version)
+            }
+            TraceError::Truncated => write!(f, "trace is truncated"),
+            TraceError::Corrupt => write!(f, "trace is corrupt"),
+        }
+    }
+}
+
+impl std::error::Error for TraceError {}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+struct Cursor<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+impl Cursor<'_> {
+    fn byte(&mut self) -> Result<u8, TraceError> {
+        let byte = *self.bytes.get(self.at).ok_or(TraceError::Truncated)?;
+        self.at += 1;
+        Ok(byte)
+    }
+
+    fn varint(&mut self) -> Result<u64, TraceError> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            value |= ((byte & 0x7F) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(TraceError::Truncated)
+    }
+
+    fn slice(&mut self, length: usize) -> Result<&[u8], TraceError> {
+        let end = self.at.checked_add(length).ok_or(TraceError::Corrupt)?;
+        let slice = self.bytes.get(self.at..end).ok_or(TraceError::Truncated)?;
+        self.at += length;
+        Ok(slice)
+    }
+}
+
+/// Hooks that collect the memory writes of the instruction being executed,
+/// in the form `TraceWriter::record` takes them.
+#[derive(Debug, Default, Clone)]
+pub struct WriteLog {
+    writes: Vec<(usize, u8)>,
+}
+
+impl WriteLog {
+    /// Absolute address and new value of each byte written by the most recent
+    /// instruction.
+    pub fn writes(&self) -> &[(usize, u8)] {
+        &self.writes
+    }
+}
+
+impl Hooks for WriteLog {
+    fn before_instruction(&mut self, _: &Registers, _: &instruction) {
+        self.writes.clear();
+    }
+
+    fn memory_write(&mut self, address: usize, _: u8, new: u8) {
+        self.writes.push((address, new));
+    }
+}
+
+/// Streams trace records to `writer` as instructions execute.
+pub struct TraceWriter<W: Write> {
+    writer: W,
+    registers: Registers,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> TraceWriter<W> {
+    pub fn new(mut writer: W, initial: &Registers) -> io::Result<Self> {
+        let mut buffer = Vec::new();
+        buffer.extend_from_slice(MAGIC);
+        buffer.extend_from_slice(&TRACE_VERSION.to_le_bytes());
+        for index in Register_a..Register_count {
+            write_varint(&mut buffer, initial.get(index) as u64);
+        }
+        writer.write_all(&buffer)?;
+
+        Ok(Self {
+            writer,
+            registers: *initial,
+            buffer,
+        })
+    }
+
+    /// Records one instruction, given the registers after it ran.
+    pub fn record(
+        &mut self,
+        address: usize,
+        bytes: &[u8],
+        after: &Registers,
+        memory_writes: &[(usize, u8)],
+        clocks: ClockInterval,
+    ) -> io::Result<()> {
+        let buffer = &mut self.buffer;
+        buffer.clear();
+        write_varint(buffer, address as u64);
+        write_varint(buffer, bytes.len() as u64);
+        buffer.extend_from_slice(bytes);
+
+        let changed: Vec<register_index> = (Register_a..Register_count)
+            .filter(|index| self.registers.get(*index) != after.get(*index))
+            .collect();
+        write_varint(buffer, changed.len() as u64);
+        for index in changed {
+            buffer.push(index as u8);
+            write_varint(buffer, after.get(index) as u64);
+        }
+
+        write_varint(buffer, memory_writes.len() as u64);
+        for (address, value) in memory_writes {
+            write_varint(buffer, *address as u64);
+            buffer.push(*value);
+        }
+
+        write_varint(buffer, clocks.min as u64);
+        write_varint(buffer, clocks.max.saturating_sub(clocks.min) as u64);
+
+        self.registers = *after;
+        self.writer.write_all(buffer)
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+pub fn read_trace(bytes: &[u8]) -> Result<Vec<TraceRecord>, TraceError> {
+    if bytes.get(..4) != Some(MAGIC.as_slice()) {
+        return Err(TraceError::NotATrace);
+    }
+    let version = u16::from_le_bytes([
+        *bytes.get(4).ok_or(TraceError::Truncated)?,
+        *bytes.get(5).ok_or(TraceError::Truncated)?,
+    ]);
+    if version != TRACE_VERSION {
+        return Err(TraceError::UnsupportedVersion(version));
+    }
+
+    let mut cursor = Cursor { bytes, at: 6 };
+    let mut registers = Registers::default();
+    for index in Register_a..Register_count {
+        registers.set(index, cursor.varint()? as u16);
+    }
+
+    let mut records = Vec::new();
+    while cursor.at < bytes.len() {
+        let mut record = TraceRecord {
+            address: cursor.varint()? as usize,
+            ..Default::default()
+        };
+        let length = cursor.varint()? as usize;
+        record.bytes = cursor.slice(length)?.to_vec();
+
+        for _ in 0..cursor.varint()? {
+            let index = cursor.byte()? as register_index;
+            let new = cursor.varint()? as u16;
+            if !(Register_a..Register_count).contains(&index) {
+                return Err(TraceError::NotATrace);
+            }
+            record.registers.push((index, registers.get(index), new));
+            registers.set(index, new);
+        }
+
+        for _ in 0..cursor.varint()? {
+            let address = cursor.varint()? as usize;
+            record.memory_writes.push((address, cursor.byte()?));
+        }
+
+        let min = u32::try_from(cursor.varint()?).map_err(|_| TraceError::Corrupt)?;
+        let spread = u32::try_from(cursor.varint()?).map_err(|_| TraceError::Corrupt)?;
+        record.clocks = ClockInterval {
+            min,
+            max: min.checked_add(spread).ok_or(TraceError::Corrupt)?,
+        };
+        records.push(record);
+    }
+
+    Ok(records)
+}
+
+impl fmt::Display for TraceRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = decode_8086_instruction(&self.bytes)
+            .map(|inst| instruction_text(&inst))
+            .unwrap_or_else(|_| format!("db {:02x?}", self.bytes));
+        write!(f, "0x{:05x}: {} ;", self.address, text.trim_end())?;
+
+        for (index, old, new) in &self.registers {
+            let name = register_name_from_operand(&wide_register(*index));
+            if *index == Register_flags {
+                write!(f, " {}:{}->{}", name, flags_text(*old), flags_text(*new))?;
+            } else {
+                write!(f, " {}:0x{:x}->0x{:x}", name, old, new)?;
+            }
+        }
+        for (address, value) in &self.memory_writes {
+            write!(f, " [0x{:05x}]=0x{:02x}", address, value)?;
+        }
+        if self.clocks.max != 0 {
+            write!(f, " +{}", clock_interval_text(&self.clocks))?;
+        }
+        Ok(())
+    }
+}
+
+/// Where two traces part ways.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    /// Index of the first record that differs.
+    pub index: usize,
+    /// Names of the parts that differ, e.g. "address", "ax", "memory".
+    pub fields: Vec<String>,
+}
+
+fn differing_fields(a: &TraceRecord, b: &TraceRecord) -> Vec<String> {
+    let mut fields = Vec::new();
+    if a.address != b.address {
+        fields.push("address".to_string());
+    }
+    if a.bytes != b.bytes {
+        fields.push("bytes".to_string());
+    }
+    for index in Register_a..Register_count {
+        let change = |record: &TraceRecord| {
+            record
+                .registers
+                .iter()
+                .find(|(changed, _, _)| *changed == index)
+                .copied()
+        };
+        if change(a) != change(b) {
+            fields.push(register_name_from_operand(&wide_register(index)).into_owned());
+        }
+    }
+    if a.memory_writes != b.memory_writes {
+        fields.push("memory".to_string());
+    }
+    if a.clocks != b.clocks {
+        fields.push("clocks".to_string());
+    }
+    fields
+}
+
+/// The first record at which the traces differ, or where one of them ends
+/// before the other. None if they are identical.
+pub fn first_divergence(a: &[TraceRecord], b: &[TraceRecord]) -> Option<Divergence> {
+    for (index, (a, b)) in a.iter().zip(b).enumerate() {
+        let fields = differing_fields(a, b);
+        if !fields.is_empty() {
+            return Some(Divergence { index, fields });
+        }
+    }
+
+    (a.len() != b.len()).then(|| Divergence {
+        index: a.len().min(b.len()),
+        fields: vec!["length".to_string()],
+    })
+}
+
+/// A report of the divergence between two traces, with up to `context`
+/// matching records before it.
+pub fn divergence_text(
+    a: &[TraceRecord],
+    b: &[TraceRecord],
+    divergence: &Divergence,
+    context: usize,
+) -> String {
+    let mut text = String::new();
+    let index = divergence.index;
+    let _ = writeln!(
+        text,
+        "Traces diverge at instruction {} ({}):",
+        index + 1,
+        divergence.fields.join(", ")
+    );
+    for (number, record) in a
+        .iter()
+        .enumerate()
+        .take(index)
+        .skip(index.saturating_sub(context))
+    {
+        let _ = writeln!(text, "   {:>6} {}", number + 1, record);
+    }
+
+    for (label, trace) in [("a", a), ("b", b)] {
+        match trace.get(index) {
+            Some(record) => {
+                let _ = writeln!(text, "{}: {:>6} {}", label, index + 1, record);
+            }
+            None => {
+                let _ = writeln!(text, "{}: ends after {} instructions", label, trace.len());
+            }
+        }
+    }
+
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::Simulator;
+
+    fn trace_of(code: &[u8]) -> Vec<TraceRecord> {
+        let mut simulator = Simulator::with_hooks(WriteLog::default());
+        let mut writer = TraceWriter::new(Vec::new(), simulator.registers()).unwrap();
+
+        let mut offset = 0usize;
+        while offset < code.len() {
+            let inst = decode_8086_instruction(&code[offset..]).unwrap();
+            simulator.execute_instruction(&inst).unwrap();
+            let bytes = &code[offset..offset + inst.Size as usize];
+            let clocks = ClockInterval { min: 4, max: 4 };
+            writer
+                .record(
+                    offset,
+                    bytes,
+                    simulator.registers(),
+                    simulator.hooks().writes(),
+                    clocks,
+                )
+                .unwrap();
+            offset = simulator.registers().ip() as usize;
+        }
+
+        read_trace(&writer.finish().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn round_trips_records() {
+        // mov bx, 0x3e8 / mov word [bx], 0x102
+        let records = trace_of(&[0xBB, 0xE8, 0x03, 0xC7, 0x07, 0x02, 0x01]);
+        assert_eq!(2, records.len());
+        assert_eq!(
+            vec![(Register_b, 0, 0x3e8), (Register_ip, 0, 3)],
+            records[0].registers
+        );
+        assert_eq!(vec![(0x3e8, 2), (0x3e9, 1)], records[1].memory_writes);
+        assert_eq!(
+            "0x00003: mov word [bx], 258 ; ip:0x3->0x7 [0x003e8]=0x02 [0x003e9]=0x01 +4",
+            records[1].to_string()
+        );
+    }
+
+    #[test]
+    fn finds_first_divergence() {
+        // mov ax, 1 / add ax, 2   against   mov ax, 1 / add ax, 3
+        let a = trace_of(&[0xB8, 0x01, 0x00, 0x05, 0x02, 0x00]);
+        let b = trace_of(&[0xB8, 0x01, 0x00, 0x05, 0x03, 0x00]);
+        assert_eq!(None, first_divergence(&a, &a));
+
+        let divergence = first_divergence(&a, &b).unwrap();
+        assert_eq!(1, divergence.index);
+        assert_eq!(vec!["bytes", "ax", "flags"], divergence.fields);
+
+        let divergence = first_divergence(&a, &a[..1]).unwrap();
+        assert_eq!(1, divergence.index);
+        assert!(divergence_text(&a, &a[..1], &divergence, 3).contains("b: ends after 1"));
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert_eq!(Err(TraceError::NotATrace), read_trace(b"S86S\x01\x00"));
+        assert_eq!(
+            Err(TraceError::UnsupportedVersion(7)),
+            read_trace(b"S86T\x07\x00")
+        );
+        assert_eq!(Err(TraceError::Truncated), read_trace(b"S86T\x01\x00\x01"));
+    }
+
+    #[test]
+    fn rejects_impossible_lengths_and_clocks() {
+        let mut header = b"S86T\x01\x00".to_vec();
+        header.extend([0; (Register_count - Register_a) as usize]);
+
+        // A byte count of 2^64-1
+        let mut huge_length = header.clone();
+        huge_length.push(0);
+        write_varint(&mut huge_length, u64::MAX);
+        assert_eq!(Err(TraceError::Corrupt), read_trace(&huge_length));
+
+        // min clocks of 0xffffffff with a spread of 1, then a min clocks that
+        // doesn't fit in 32 bits
+        let mut record = header;
+        record.extend([0, 1, 0x90, 0, 0]);
+        let mut overflowing_max = record.clone();
+        write_varint(&mut overflowing_max, u32::MAX as u64);
+        write_varint(&mut overflowing_max, 1);
+        assert_eq!(Err(TraceError::Corrupt), read_trace(&overflowing_max));
+
+        let mut overflowing_min = record;
+        write_varint(&mut overflowing_min, 1 << 32);
+        write_varint(&mut overflowing_min, 0);
+        assert_eq!(Err(TraceError::Corrupt), read_trace(&overflowing_min));
+    }
+}
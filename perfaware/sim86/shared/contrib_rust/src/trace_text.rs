@@ -0,0 +1,401 @@
+use std::fmt;
+
+use crate::cycles::ClockInterval;
+use crate::simulator::Registers;
+use crate::text::{clock_interval_text, clocks_text, flags_text, wide_register, FLAG_LETTERS};
+use crate::*;
+
+// Reads back the execution traces that sim86 prints (and that the course ships
+// as listing_XXXX.txt), so runs can be compared as data rather than as text.
+
+/// The " (base + Nea + Np)" breakdown printed by -explainclocks.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ClockExplanation {
+    pub base: ClockInterval,
+    pub ea_clocks: u32,
+    pub penalty: u32,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct StepClocks {
+    pub clocks: ClockInterval,
+    pub total: ClockInterval,
+    pub explanation: Option<ClockExplanation>,
+}
+
+/// One `reg:old->new` entry. Flags transitions use `Register_flags` and hold
+/// the flag bits rather than the letters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegisterChange {
+    pub index: register_index,
+    pub old: u16,
+    pub new: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceStep {
+    pub text: String,
+    pub clocks: Option<StepClocks>,
+    pub changes: Vec<RegisterChange>,
+}
+
+impl TraceStep {
+    pub fn change(&self, index: register_index) -> Option<&RegisterChange> {
+        self.changes.iter().find(|change| change.index == index)
+    }
+
+    pub fn flags_change(&self) -> Option<&RegisterChange> {
+        self.change(Register_flags)
+    }
+}
+
+/// One `--- name execution ---` section of a trace.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct TraceRun {
+    pub name: String,
+    /// The `**** 8088 ****` banner preceding the run, if there was one.
+    pub cpu: Option<String>,
+    pub steps: Vec<TraceStep>,
+    /// STOPONRET and ERROR lines, in the order they appeared.
+    pub messages: Vec<String>,
+    pub final_registers: Registers,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+fn parse_flags(letters: &str) -> Result<u16, String> {
+    letters.chars().try_fold(0, |flags, letter| {
+        FLAG_LETTERS
+            .iter()
+            .find(|(_, known)| *known == letter)
+            .map(|(flag, _)| flags | flag)
+            .ok_or_else(|| format!("unknown flag `{}`", letter))
+    })
+}
+
+fn parse_hex(value: &str) -> Result<u16, String> {
+    value
+        .strip_prefix("0x")
+        .and_then(|digits| u16::from_str_radix(digits, 16).ok())
+        .ok_or_else(|| format!("expected a hex value, found `{}`", value))
+}
+
+/// Clock counts are always plain decimal in the reference's output, so they
+/// don't go through `debugger::parse_number`, which would also read `10h`.
+fn parse_decimal(value: &str) -> Result<u32, String> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| format!("expected a number, found `{}`", value))
+}
+
+fn parse_interval(text: &str) -> Result<ClockInterval, String> {
+    let text = text.trim();
+    if let Some(inner) = text.strip_prefix('[').and_then(|t| t.strip_suffix(']')) {
+        let (min, max) = inner
+            .split_once(',')
+            .ok_or_else(|| format!("bad clock interval `{}`", text))?;
+        Ok(ClockInterval {
+            min: parse_decimal(min)?,
+            max: parse_decimal(max)?,
+        })
+    } else {
+        let clocks = parse_decimal(text)?;
+        Ok(ClockInterval {
+            min: clocks,
+            max: clocks,
+        })
+    }
+}
+
+fn parse_explanation(text: &str) -> Result<ClockExplanation, String> {
+    let mut terms = text.split(" + ");
+    let mut explanation = ClockExplanation {
+        base: parse_interval(terms.next().unwrap_or_default())?,
+        ..Default::default()
+    };
+
+    for term in terms {
+        if let Some(ea) = term.strip_suffix("ea") {
+            explanation.ea_clocks = parse_decimal(ea)?;
+        } else if let Some(penalty) = term.strip_suffix('p') {
+            explanation.penalty = parse_decimal(penalty)?;
+        } else {
+            return Err(format!("unknown clock term `{}`", term));
+        }
+    }
+
+    Ok(explanation)
+}
+
+/// Parses "Clocks: +N = T (base + Nea + Np)".
+fn parse_clocks(text: &str) -> Result<StepClocks, String> {
+    let rest = text
+        .trim()
+        .strip_prefix("Clocks: +")
+        .ok_or_else(|| format!("expected clocks, found `{}`", text))?;
+    let (clocks, rest) = rest
+        .split_once(" = ")
+        .ok_or_else(|| format!("missing clock total in `{}`", text))?;
+    let (total, explanation) = match rest.split_once(" (") {
+        Some((total, explanation)) => {
+            let explanation = explanation
+                .strip_suffix(')')
+                .ok_or_else(|| format!("unterminated clock explanation in `{}`", text))?;
+            (total, Some(parse_explanation(explanation)?))
+        }
+        None => (rest, None),
+    };
+
+    Ok(StepClocks {
+        clocks: parse_interval(clocks)?,
+        total: parse_interval(total)?,
+        explanation,
+    })
+}
+
+fn parse_change(text: &str) -> Result<RegisterChange, String> {
+    let (name, delta) = text
+        .split_once(':')
+        .and_then(|(name, delta)| Some((name, delta.split_once("->")?)))
+        .ok_or_else(|| format!("expected reg:old->new, found `{}`", text))?;
+    let index =
+        register_index_from_name(name).ok_or_else(|| format!("unknown register `{}`", name))?;
+
+    let (old, new) = if index == Register_flags {
+        (parse_flags(delta.0)?, parse_flags(delta.1)?)
+    } else {
+        (parse_hex(delta.0)?, parse_hex(delta.1)?)
+    };
+
+    Ok(RegisterChange { index, old, new })
+}
+
+/// Parses one "text ; Clocks: ... | reg:old->new ..." line.
+pub fn parse_step(line: &str) -> Result<TraceStep, String> {
+    let (text, rest) = line
+        .split_once(';')
+        .ok_or_else(|| format!("expected an instruction trace, found `{}`", line))?;
+
+    let (clocks, changes) = if rest.trim_start().starts_with("Clocks:") {
+        let (clocks, changes) = rest.split_once('|').unwrap_or((rest, ""));
+        (Some(parse_clocks(clocks)?), changes)
+    } else {
+        (None, rest)
+    };
+
+    Ok(TraceStep {
+        text: text.trim().to_string(),
+        clocks,
+        changes: changes
+            .split_whitespace()
+            .map(parse_change)
+            .collect::<Result<_, _>>()?,
+    })
+}
+
+fn parse_final_register(line: &str, registers: &mut Registers) -> Result<(), String> {
+    let (name, value) = line
+        .trim()
+        .split_once(':')
+        .ok_or_else(|| format!("expected `reg: value`, found `{}`", line))?;
+    let index =
+        register_index_from_name(name).ok_or_else(|| format!("unknown register `{}`", name))?;
+
+    let value = value.trim();
+    let value = if index == Register_flags {
+        parse_flags(value)?
+    } else {
+        parse_hex(value.split_whitespace().next().unwrap_or_default())?
+    };
+    registers.set(index, value);
+
+    Ok(())
+}
+
+/// Parses every execution run in a trace. Disassembly, warnings and anything
+/// else outside a `--- name execution ---` section is skipped.
+pub fn parse_trace(trace: &str) -> Result<Vec<TraceRun>, ParseError> {
+    let mut runs: Vec<TraceRun> = Vec::new();
+    let mut cpu = None;
+    let mut in_final = false;
+
+    for (number, line) in trace.lines().enumerate() {
+        let line = line.trim_end();
+        let error = |message| ParseError {
+            line: number + 1,
+            message,
+        };
+
+        if let Some(name) = line
+            .strip_prefix("--- ")
+            .and_then(|rest| rest.strip_suffix(" execution ---"))
+        {
+            runs.push(TraceRun {
+                name: name.to_string(),
+                cpu: cpu.take(),
+                ..Default::default()
+            });
+            in_final = false;
+            continue;
+        }
+
+        if let Some(banner) = line
+            .strip_prefix("**** ")
+            .and_then(|rest| rest.strip_suffix(" ****"))
+        {
+            cpu = Some(banner.to_string());
+            continue;
+        }
+
+        let Some(run) = runs.last_mut() else {
+            continue;
+        };
+
+        if line.is_empty() {
+            in_final = false;
+        } else if line == "Final registers:" {
+            in_final = true;
+        } else if in_final {
+            parse_final_register(line, &mut run.final_registers).map_err(error)?;
+        } else if line.starts_with("STOPONRET:") || line.starts_with("ERROR:") {
+            run.messages.push(line.to_string());
+        } else if line.contains(';') {
+            run.steps.push(parse_step(line).map_err(error)?);
+        }
+    }
+
+    Ok(runs)
+}
+
+impl fmt::Display for StepClocks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", clocks_text(&self.clocks, &self.total))?;
+        if let Some(explanation) = &self.explanation {
+            write!(f, " ({}", clock_interval_text(&explanation.base))?;
+            if explanation.ea_clocks != 0 {
+                write!(f, " + {}ea", explanation.ea_clocks)?;
+            }
+            if explanation.penalty != 0 {
+                write!(f, " + {}p", explanation.penalty)?;
+            }
+            write!(f, ")")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for RegisterChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:",
+            register_name_from_operand(&wide_register(self.index))
+        )?;
+        if self.index == Register_flags {
+            write!(f, "{}->{}", flags_text(self.old), flags_text(self.new))
+        } else {
+            write!(f, "0x{:x}->0x{:x}", self.old, self.new)
+        }
+    }
+}
+
+/// Renders the step the way sim86 prints it, minus the trailing space.
+impl fmt::Display for TraceStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ;", self.text)?;
+        if let Some(clocks) = &self.clocks {
+            write!(f, " {} |", clocks)?;
+        }
+        for change in &self.changes {
+            write!(f, " {}", change)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::{PARITY_FLAG, ZERO_FLAG};
+
+    const TRACE: &str = "\
+**************\r
+**** 8088 ****\r
+**************\r
+\r
+--- test\\listing_0056_estimating_cycles execution ---\r
+mov dx, [+1000] ; Clocks: +18 = 40 (8 + 6ea + 4p) | dx:0xc->0x0 ip:0x11->0x15 \r
+add cx, dx ; Clocks: +3 = 163 | ip:0x2e->0x30 flags:->PZ \r
+STOPONRET: Return encountered at address 52.\r
+\r
+Final registers:\r
+      bx: 0x03e8 (1000)\r
+      ip: 0x0034 (52)\r
+   flags: PZ\r
+\r
+";
+
+    #[test]
+    fn parses_runs() {
+        let runs = parse_trace(TRACE).unwrap();
+        assert_eq!(1, runs.len());
+
+        let run = &runs[0];
+        assert_eq!("test\\listing_0056_estimating_cycles", run.name);
+        assert_eq!(Some("8088"), run.cpu.as_deref());
+        assert_eq!(2, run.steps.len());
+        assert_eq!(1, run.messages.len());
+        assert_eq!(1000, run.final_registers.get(Register_b));
+        assert_eq!(PARITY_FLAG | ZERO_FLAG, run.final_registers.flags());
+
+        let clocks = run.steps[0].clocks.unwrap();
+        assert_eq!(18, clocks.clocks.min);
+        assert_eq!(40, clocks.total.max);
+        assert_eq!(
+            Some(ClockExplanation {
+                base: ClockInterval { min: 8, max: 8 },
+                ea_clocks: 6,
+                penalty: 4,
+            }),
+            clocks.explanation
+        );
+        assert_eq!(
+            Some(&RegisterChange {
+                index: Register_flags,
+                old: 0,
+                new: PARITY_FLAG | ZERO_FLAG,
+            }),
+            run.steps[1].flags_change()
+        );
+    }
+
+    #[test]
+    fn steps_round_trip() {
+        for line in [
+            "mov dx, [+1000] ; Clocks: +18 = 40 (8 + 6ea + 4p) | dx:0xc->0x0 ip:0x11->0x15",
+            "jne $-6 ; Clocks: +[4,16] = [20,32] | ip:0x9->0x3",
+            "sub bx, 5000 ; bx:0x88b8->0x7530 flags:PAS->PO",
+        ] {
+            assert_eq!(line, parse_step(line).unwrap().to_string());
+        }
+    }
+
+    #[test]
+    fn reports_bad_lines() {
+        let error = parse_trace("--- a execution ---\nmov ax, 1 ; zx:0x0->0x1\n").unwrap_err();
+        assert_eq!(2, error.line);
+    }
+}
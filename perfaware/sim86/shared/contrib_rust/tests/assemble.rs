@@ -0,0 +1,49 @@
+//! Assembles every part1 listing source with the built-in assembler and
+//! checks the result against the binary NASM produced for it.
+
+mod common;
+
+use std::fmt::Write;
+
+use common::{part1_files, Report};
+use sim86_shared::asm::assemble;
+
+#[test]
+fn part1_listings_assemble_byte_for_byte() {
+    let sources = part1_files(|path| path.extension().is_some_and(|extension| extension == "asm"));
+
+    let mut report = Report::new();
+    for source in &sources {
+        let name = source.file_stem().unwrap().to_string_lossy();
+        let Ok(expected) = std::fs::read(source.with_extension("")) else {
+            continue;
+        };
+        let text = std::fs::read_to_string(source).unwrap();
+        report.compared();
+        match assemble(&text) {
+            Ok(actual) if actual == expected => {}
+            Ok(actual) => {
+                let at = actual
+                    .iter()
+                    .zip(&expected)
+                    .position(|(a, b)| a != b)
+                    .unwrap_or(actual.len().min(expected.len()));
+                let _ = writeln!(
+                    report,
+                    "{}: differs at byte {} ({} bytes, expected {}): {:02x?} vs {:02x?}",
+                    name,
+                    at,
+                    actual.len(),
+                    expected.len(),
+                    &actual[at..(at + 6).min(actual.len())],
+                    &expected[at..(at + 6).min(expected.len())]
+                );
+            }
+            Err(err) => {
+                let _ = writeln!(report, "{}: {}", name, err);
+            }
+        }
+    }
+
+    report.assert_clean();
+}
@@ -0,0 +1,56 @@
+//! Helpers shared by the integration tests that run over the part1 listings.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+fn part1_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("../../../part1")
+}
+
+/// The files in the part1 directory that `keep` accepts, sorted by name.
+pub fn part1_files(keep: impl Fn(&Path) -> bool) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = std::fs::read_dir(part1_dir())
+        .expect("part1 listings should be next to the crate")
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| keep(path))
+        .collect();
+    files.sort();
+    files
+}
+
+/// Failures collected over every listing, so that one run shows all of them.
+/// Written to with `writeln!`.
+#[derive(Debug, Default)]
+pub struct Report {
+    failures: String,
+    compared: usize,
+}
+
+impl Report {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counts one more thing checked.
+    pub fn compared(&mut self) {
+        self.compared += 1;
+    }
+
+    /// Fails the test if anything was written to the report, or if there
+    /// turned out to be nothing to check.
+    pub fn assert_clean(&self) {
+        assert!(
+            self.compared > 0,
+            "nothing to check in {}",
+            part1_dir().display()
+        );
+        assert!(self.failures.is_empty(), "\n{}", self.failures);
+    }
+}
+
+impl fmt::Write for Report {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        self.failures.push_str(text);
+        Ok(())
+    }
+}
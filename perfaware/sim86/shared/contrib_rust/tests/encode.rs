@@ -0,0 +1,59 @@
+//! Decodes every instruction of the part1 listing binaries, encodes it again
+//! and checks the round trip: both choices of encoding must decode back to
+//! the same instruction, and the shortest must be what NASM produced.
+
+mod common;
+
+use std::fmt::Write;
+
+use common::{part1_files, Report};
+use sim86_shared::encode::{encode_with, EncodingChoice};
+use sim86_shared::text::instruction_text;
+use sim86_shared::*;
+
+#[test]
+fn part1_instructions_round_trip() {
+    let binaries = part1_files(|path| path.extension().is_none() && path.is_file());
+
+    let mut report = Report::new();
+    for binary in &binaries {
+        let name = binary.file_name().unwrap().to_string_lossy();
+        let program = std::fs::read(binary).unwrap();
+        // Some listings end partway through an instruction
+        for decoded in decode_stream(&program, 0).map_while(Result::ok) {
+            let (at, original, inst) = (decoded.offset, decoded.bytes, decoded.instruction);
+            let text = instruction_text(&inst);
+            report.compared();
+
+            for choice in [EncodingChoice::TableOrder, EncodingChoice::Shortest] {
+                let Some(bytes) = encode_with(&inst, choice) else {
+                    let _ = writeln!(
+                        report,
+                        "{} +{}: {:?} can't encode {}",
+                        name, at, choice, text
+                    );
+                    continue;
+                };
+                let again = decode_8086_instruction(&bytes);
+                let same = again.is_ok_and(|again| {
+                    again.Size as usize == bytes.len() && instruction_text(&again) == text
+                });
+                if !same {
+                    let _ = writeln!(
+                        report,
+                        "{} +{}: {:?} encodes {} as {:02x?}",
+                        name, at, choice, text, bytes
+                    );
+                } else if choice == EncodingChoice::Shortest && bytes != original {
+                    let _ = writeln!(
+                        report,
+                        "{} +{}: {} is {:02x?}, expected {:02x?}",
+                        name, at, text, bytes, original
+                    );
+                }
+            }
+        }
+    }
+
+    report.assert_clean();
+}
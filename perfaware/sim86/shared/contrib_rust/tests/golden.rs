@@ -0,0 +1,134 @@
+//! Runs every part1 listing that has a reference `.txt` through the simulator
+//! and compares the trace, instruction by instruction, against the reference.
+
+mod common;
+
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use common::{part1_files, Report};
+use sim86_shared::text::registers_text;
+use sim86_shared::trace_text::{parse_trace, TraceRun};
+use sim86_shared::*;
+
+const MAX_REPORTED_DIFFS: usize = 5;
+
+/// The oldest reference traces predate ip being printed, so only compare it
+/// when the reference actually mentions it.
+fn strip_ip(runs: &mut [TraceRun]) {
+    for run in runs {
+        run.final_registers.set(Register_ip, 0);
+        for step in &mut run.steps {
+            step.changes.retain(|change| change.index != Register_ip);
+        }
+    }
+}
+
+fn compare_run(label: &str, expected: &TraceRun, actual: &TraceRun, report: &mut Report) {
+    let mut diffs = 0;
+    for (index, pair) in expected.steps.iter().zip(&actual.steps).enumerate() {
+        if pair.0 != pair.1 {
+            if diffs < MAX_REPORTED_DIFFS {
+                let _ = writeln!(report, "{}: instruction {} differs", label, index + 1);
+                let _ = writeln!(report, "    expected: {}", pair.0);
+                let _ = writeln!(report, "      actual: {}", pair.1);
+            }
+            diffs += 1;
+        }
+    }
+    if diffs > MAX_REPORTED_DIFFS {
+        let _ = writeln!(
+            report,
+            "{}: ... and {} more",
+            label,
+            diffs - MAX_REPORTED_DIFFS
+        );
+    }
+
+    if expected.steps.len() != actual.steps.len() {
+        let _ = writeln!(
+            report,
+            "{}: expected {} instructions, simulated {}",
+            label,
+            expected.steps.len(),
+            actual.steps.len()
+        );
+    }
+
+    if expected.final_registers != actual.final_registers {
+        let _ = writeln!(
+            report,
+            "{}: final registers differ\nexpected:\n{}actual:\n{}",
+            label,
+            registers_text(&expected.final_registers),
+            registers_text(&actual.final_registers)
+        );
+    }
+}
+
+fn listings() -> Vec<PathBuf> {
+    part1_files(|path| {
+        let name = path.file_name().unwrap().to_string_lossy();
+        name.starts_with("listing_")
+            && path.extension().is_none()
+            && path.with_extension("txt").exists()
+    })
+}
+
+fn simulate(listing: &Path, reference: &str) -> String {
+    let mut args = vec!["-exec", "-stoponret"];
+    if reference.contains("Clocks:") {
+        args.push("-explainclocks");
+    }
+
+    let listing = listing.to_str().unwrap();
+    let mut command = Command::new(env!("CARGO_BIN_EXE_sim86_shared_example"));
+    command.args(&args).arg(listing);
+    // Cycle listings carry a second run with 8088 timings after the 8086 one
+    if reference.contains("**** 8088 ****") {
+        command.arg("-8088").arg(listing);
+    }
+
+    let output = command.output().expect("simulator should run");
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+#[test]
+fn part1_listings_match_reference() {
+    let mut report = Report::new();
+    for listing in &listings() {
+        report.compared();
+        let name = listing.file_name().unwrap().to_string_lossy().into_owned();
+        let reference = std::fs::read_to_string(listing.with_extension("txt")).unwrap();
+
+        let mut expected =
+            parse_trace(&reference).unwrap_or_else(|err| panic!("{}.txt: {}", name, err));
+        let mut actual = parse_trace(&simulate(listing, &reference))
+            .unwrap_or_else(|err| panic!("{} simulation output: {}", name, err));
+        if !reference.contains(" ip:") {
+            strip_ip(&mut expected);
+            strip_ip(&mut actual);
+        }
+
+        if expected.len() != actual.len() {
+            let _ = writeln!(
+                report,
+                "{}: expected {} runs, got {}",
+                name,
+                expected.len(),
+                actual.len()
+            );
+            continue;
+        }
+        for (expected, actual) in expected.iter().zip(&actual) {
+            let label = match &expected.cpu {
+                Some(cpu) => format!("{} ({})", name, cpu),
+                None => name.clone(),
+            };
+            compare_run(&label, expected, actual, &mut report);
+        }
+    }
+
+    report.assert_clean();
+}